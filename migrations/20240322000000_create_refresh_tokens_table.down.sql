@@ -0,0 +1,6 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_refresh_tokens_user_id;
+DROP INDEX IF EXISTS idx_refresh_tokens_family_id;
+
+-- Drop the refresh tokens table
+DROP TABLE IF EXISTS refresh_tokens;
\ No newline at end of file
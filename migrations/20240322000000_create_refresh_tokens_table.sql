@@ -0,0 +1,18 @@
+-- Create Refresh Tokens Table
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- All tokens produced by rotating one login share a family
+    family_id UUID NOT NULL,
+    -- SHA-256 of the opaque token; the token itself is never stored
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- Set once the token has been exchanged for its successor
+    replaced_by INTEGER REFERENCES refresh_tokens(id) ON DELETE SET NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create Indexes for family revocation and per-user cleanup
+CREATE INDEX idx_refresh_tokens_family_id ON refresh_tokens(family_id);
+CREATE INDEX idx_refresh_tokens_user_id ON refresh_tokens(user_id);
\ No newline at end of file
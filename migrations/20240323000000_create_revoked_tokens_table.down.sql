@@ -0,0 +1,8 @@
+-- Drop the logout-everywhere cutoff
+ALTER TABLE users DROP COLUMN IF EXISTS tokens_revoked_before;
+
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_revoked_tokens_expires_at;
+
+-- Drop the revoked tokens table
+DROP TABLE IF EXISTS revoked_tokens;
\ No newline at end of file
@@ -0,0 +1,17 @@
+-- Create Revoked Tokens Table
+-- Access tokens revoked before their expiry, keyed by the JWT ID (jti)
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti VARCHAR(64) PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Rows can be purged once the token would have expired anyway
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create Index for purging expired entries
+CREATE INDEX idx_revoked_tokens_expires_at ON revoked_tokens(expires_at);
+
+-- Tokens issued before this instant are rejected (logout everywhere). Token
+-- `iat` only has whole seconds, so tokens issued in the second of the cutoff
+-- stay valid.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS tokens_revoked_before TIMESTAMP WITH TIME ZONE;
\ No newline at end of file
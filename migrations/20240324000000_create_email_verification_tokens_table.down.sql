@@ -0,0 +1,8 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_email_verification_tokens_user_id;
+
+-- Drop the email verification tokens table
+DROP TABLE IF EXISTS email_verification_tokens;
+
+-- Drop the verification timestamp
+ALTER TABLE users DROP COLUMN IF EXISTS email_verified_at;
\ No newline at end of file
@@ -0,0 +1,15 @@
+-- Track when the user proved ownership of their email address
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMP WITH TIME ZONE;
+
+-- Create Email Verification Tokens Table
+-- The token itself is a signed JWT; this table makes it single-use
+CREATE TABLE IF NOT EXISTS email_verification_tokens (
+    jti UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create Index for the resend cooldown lookup
+CREATE INDEX idx_email_verification_tokens_user_id ON email_verification_tokens(user_id);
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_mfa_recovery_codes_user_id;
+
+-- Drop the MFA recovery codes table
+DROP TABLE IF EXISTS mfa_recovery_codes;
+
+-- Drop the TOTP state
+ALTER TABLE users DROP COLUMN IF EXISTS totp_last_step;
+ALTER TABLE users DROP COLUMN IF EXISTS totp_enabled_at;
+ALTER TABLE users DROP COLUMN IF EXISTS totp_secret;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Drop the signing keys table
+DROP TABLE IF EXISTS signing_keys;
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- Create Signing Keys Table
+-- Asymmetric keys used to sign access tokens, published through the JWKS endpoint
+CREATE TABLE IF NOT EXISTS signing_keys (
+    kid VARCHAR(64) PRIMARY KEY,
+    algorithm VARCHAR(16) NOT NULL,
+    -- DER encoded private key (PKCS#1 for RSA, PKCS#8 otherwise)
+    private_key BYTEA NOT NULL,
+    public_jwk JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Set when a newer key takes over signing
+    retired_at TIMESTAMP WITH TIME ZONE,
+    -- Retired keys stay published until every token they signed has expired
+    expires_at TIMESTAMP WITH TIME ZONE
+);
\ No newline at end of file
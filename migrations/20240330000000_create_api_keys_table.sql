@@ -0,0 +1,19 @@
+-- Create API Keys Table
+-- Long-lived keys for scripts and CI, sent as `Authorization: Bearer ak_...`
+CREATE TABLE IF NOT EXISTS api_keys (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    -- First characters of the key, shown so users can tell their keys apart
+    prefix VARCHAR(16) NOT NULL,
+    -- SHA-256 of the whole key; the key itself is never stored
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- Permissions the key may use, a subset of its owner's
+    scopes TEXT[] NOT NULL DEFAULT '{}',
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create Index for listing a user's keys
+CREATE INDEX idx_api_keys_user_id ON api_keys(user_id);
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_login_attempts_last_failure;
+
+-- Drop the login attempts table
+DROP TABLE IF EXISTS login_attempts;
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Create Login Attempts Table
+-- Failed login counters, keyed by `account:<username>` or `ip:<address>`
+CREATE TABLE IF NOT EXISTS login_attempts (
+    key VARCHAR(320) PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create Index for purging stale counters
+CREATE INDEX idx_login_attempts_last_failure ON login_attempts(last_failure);
\ No newline at end of file
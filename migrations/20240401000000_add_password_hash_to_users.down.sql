@@ -0,0 +1,2 @@
+-- Drop the password hash column
+ALTER TABLE users DROP COLUMN IF EXISTS password_hash;
\ No newline at end of file
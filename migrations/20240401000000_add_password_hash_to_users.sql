@@ -0,0 +1,4 @@
+-- Add Password Hash Column
+-- The users table was created without it. Hashes are PHC strings
+-- ($argon2id$...) or bcrypt hashes ($2b$...) awaiting an upgrade on login.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT NOT NULL;
\ No newline at end of file
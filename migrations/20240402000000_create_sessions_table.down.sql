@@ -0,0 +1,9 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_refresh_tokens_session_id;
+DROP INDEX IF EXISTS idx_sessions_user_id;
+
+-- Drop the session binding of refresh tokens
+ALTER TABLE refresh_tokens DROP COLUMN IF EXISTS session_id;
+
+-- Drop the sessions table
+DROP TABLE IF EXISTS sessions;
\ No newline at end of file
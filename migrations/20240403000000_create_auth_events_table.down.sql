@@ -0,0 +1,12 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_auth_events_subject_id;
+DROP INDEX IF EXISTS idx_auth_events_actor_id;
+DROP INDEX IF EXISTS idx_auth_events_event_type;
+DROP INDEX IF EXISTS idx_auth_events_occurred_at;
+
+-- Drop the append-only guard
+DROP TRIGGER IF EXISTS auth_events_append_only ON auth_events;
+DROP FUNCTION IF EXISTS auth_events_append_only();
+
+-- Drop the auth events table
+DROP TABLE IF EXISTS auth_events;
\ No newline at end of file
@@ -0,0 +1,35 @@
+-- Create Auth Events Table
+-- Append-only security audit log. Each row carries the SHA-256 of its own
+-- content and of the previous row, so editing or removing a row breaks the chain.
+CREATE TABLE IF NOT EXISTS auth_events (
+    id BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    event_type VARCHAR(64) NOT NULL,
+    -- success or failure
+    outcome VARCHAR(16) NOT NULL,
+    -- Who acted and who was affected; no foreign keys, so events outlive users
+    actor_id INTEGER,
+    subject_id INTEGER,
+    ip_address VARCHAR(45),
+    user_agent TEXT,
+    details JSONB NOT NULL DEFAULT '{}',
+    prev_hash VARCHAR(64) NOT NULL,
+    hash VARCHAR(64) NOT NULL UNIQUE
+);
+
+-- Reject any change to recorded events
+CREATE OR REPLACE FUNCTION auth_events_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'auth_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER auth_events_append_only
+    BEFORE UPDATE OR DELETE OR TRUNCATE ON auth_events
+    FOR EACH STATEMENT EXECUTE FUNCTION auth_events_append_only();
+
+-- Create Indexes for the admin filters
+CREATE INDEX idx_auth_events_occurred_at ON auth_events(occurred_at);
+CREATE INDEX idx_auth_events_event_type ON auth_events(event_type);
+CREATE INDEX idx_auth_events_actor_id ON auth_events(actor_id);
+CREATE INDEX idx_auth_events_subject_id ON auth_events(subject_id);
\ No newline at end of file
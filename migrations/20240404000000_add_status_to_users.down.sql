@@ -0,0 +1,3 @@
+-- Drop the account status columns
+ALTER TABLE users DROP COLUMN IF EXISTS password_reset_required;
+ALTER TABLE users DROP COLUMN IF EXISTS disabled_at;
\ No newline at end of file
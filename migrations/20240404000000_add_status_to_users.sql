@@ -0,0 +1,5 @@
+-- Add Account Status Columns
+-- Disabled accounts can't log in and lose their tokens. An account flagged for a
+-- password reset can't log in until the password is reset by email.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_reset_required BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- Add Account Deletion Column
+-- Set when a user deletes their account; the account and its data are purged
+-- once this date passes, unless the user logs in again before then.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deletion_scheduled_at TIMESTAMP WITH TIME ZONE;
+
+-- Create Index for the purge sweep
+CREATE INDEX idx_users_deletion_scheduled_at ON users(deletion_scheduled_at) WHERE deletion_scheduled_at IS NOT NULL;
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_magic_link_tokens_user_id;
+
+-- Drop the magic link tokens table
+DROP TABLE IF EXISTS magic_link_tokens;
+
+-- Drop the opt-in column
+ALTER TABLE users DROP COLUMN IF EXISTS magic_link_enabled;
\ No newline at end of file
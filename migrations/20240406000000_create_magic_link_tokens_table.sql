@@ -0,0 +1,19 @@
+-- Add Magic Link Opt-In Column
+-- Accounts log in with a password unless their owner turns magic links on
+ALTER TABLE users ADD COLUMN IF NOT EXISTS magic_link_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Create Magic Link Tokens Table
+CREATE TABLE IF NOT EXISTS magic_link_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- SHA-256 of the emailed token; the token itself is never stored
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- The address the link was sent to; the link is void once it changes
+    email VARCHAR(255) NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create Index for the per-account resend limit
+CREATE INDEX idx_magic_link_tokens_user_id ON magic_link_tokens(user_id);
\ No newline at end of file
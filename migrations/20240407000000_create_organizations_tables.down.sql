@@ -0,0 +1,15 @@
+-- Drop indexes first
+DROP INDEX IF EXISTS idx_team_members_org_id_user_id;
+DROP INDEX IF EXISTS idx_teams_org_id;
+DROP INDEX IF EXISTS idx_org_invitations_org_id;
+DROP INDEX IF EXISTS idx_org_memberships_user_id;
+
+-- Drop the session column
+ALTER TABLE sessions DROP COLUMN IF EXISTS org_id;
+
+-- Drop the organization tables
+DROP TABLE IF EXISTS team_members;
+DROP TABLE IF EXISTS teams;
+DROP TABLE IF EXISTS org_invitations;
+DROP TABLE IF EXISTS org_memberships;
+DROP TABLE IF EXISTS organizations;
\ No newline at end of file
@@ -0,0 +1,508 @@
+use axum::{
+    async_trait,
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::audit::{self, AuditEvent};
+use crate::mailer::Email;
+use crate::sessions::{end_user_sessions, ClientInfo};
+use crate::tokens::revoke_user_refresh_tokens;
+use crate::views::PublicUser;
+use crate::{auth, verification, AppError, AppState, AuthUser, Claims, User};
+
+// How often accounts past their deletion date are looked for
+pub const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+// Everything the export holds besides the profile, one query per section.
+// Secrets and their hashes are left out; `$1` is the user id.
+const EXPORT_SECTIONS: &[(&str, &str)] = &[
+    (
+        "roles",
+        "SELECT r.name, r.description, ur.granted_at FROM user_roles ur JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = $1 ORDER BY r.name",
+    ),
+    (
+        "sessions",
+        "SELECT id, user_agent, ip_address, mfa, created_at, last_seen_at, revoked_at FROM sessions WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "refresh_tokens",
+        "SELECT id, family_id, session_id, expires_at, revoked_at, created_at FROM refresh_tokens WHERE user_id = $1 ORDER BY id",
+    ),
+    (
+        "revoked_access_tokens",
+        "SELECT jti, expires_at, revoked_at FROM revoked_tokens WHERE user_id = $1 ORDER BY revoked_at",
+    ),
+    (
+        "api_keys",
+        "SELECT id, name, prefix, scopes, expires_at, last_used_at, created_at FROM api_keys WHERE user_id = $1 ORDER BY id",
+    ),
+    (
+        "mfa_recovery_codes",
+        "SELECT used_at, created_at FROM mfa_recovery_codes WHERE user_id = $1 ORDER BY id",
+    ),
+    (
+        "email_verifications",
+        "SELECT expires_at, used_at, created_at FROM email_verification_tokens WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "password_resets",
+        "SELECT expires_at, used_at, created_at FROM password_reset_tokens WHERE user_id = $1 ORDER BY id",
+    ),
+    (
+        "organizations",
+        "SELECT o.id, o.name, o.slug, m.role, m.joined_at FROM org_memberships m JOIN organizations o ON o.id = m.org_id WHERE m.user_id = $1 ORDER BY o.id",
+    ),
+    (
+        "teams",
+        "SELECT t.id, t.org_id, t.name, tm.added_at FROM team_members tm JOIN teams t ON t.id = tm.team_id WHERE tm.user_id = $1 ORDER BY t.id",
+    ),
+    (
+        "magic_links",
+        "SELECT expires_at, used_at, created_at FROM magic_link_tokens WHERE user_id = $1 ORDER BY id",
+    ),
+    (
+        "oauth_authorizations",
+        "SELECT client_id, redirect_uri, scope, auth_time, used_at, created_at FROM oauth_authorization_codes WHERE user_id = $1 ORDER BY id",
+    ),
+    (
+        "login_attempts",
+        "SELECT failures, last_failure FROM login_attempts WHERE key = 'account:' || (SELECT username FROM users WHERE id = $1)",
+    ),
+    (
+        "audit_events",
+        "SELECT id, occurred_at, event_type, outcome, actor_id, subject_id, ip_address, user_agent, details FROM auth_events WHERE actor_id = $1 OR subject_id = $1 ORDER BY id",
+    ),
+];
+
+const EXPORT_PROFILE: &str = r#"
+    SELECT id, username, email, email_verified_at, created_at, disabled_at,
+        password_reset_required, deletion_scheduled_at, totp_enabled_at,
+        magic_link_enabled
+    FROM users WHERE id = $1
+"#;
+
+// Data about users kept outside this service's tables, removed before the
+// account itself when it is purged
+#[async_trait]
+pub trait PurgeHook: Send + Sync {
+    async fn purge(&self, user: &User) -> Result<(), String>;
+}
+
+// Asks another service to delete what it holds about the user. Any answer
+// other than 2xx keeps the account around until the next sweep.
+pub struct WebhookPurgeHook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookPurgeHook {
+    pub fn new(url: impl Into<String>) -> Self {
+        WebhookPurgeHook {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl PurgeHook for WebhookPurgeHook {
+    async fn purge(&self, user: &User) -> Result<(), String> {
+        self.client
+            .post(&self.url)
+            .json(&serde_json::json!({ "event": "user.deleted", "user_id": user.id }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| format!("{}: {}", self.url, e))
+    }
+}
+
+// Read `ACCOUNT_PURGE_WEBHOOKS`, a comma-separated list of URLs
+pub fn purge_hooks_from_env() -> Vec<Box<dyn PurgeHook>> {
+    std::env::var("ACCOUNT_PURGE_WEBHOOKS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| Box::new(WebhookPurgeHook::new(url)) as Box<dyn PurgeHook>)
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateProfileRequest {
+    username: Option<String>,
+    email: Option<String>,
+    // Required to change the email address
+    current_password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletionScheduled {
+    deletion_scheduled_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub fn require_own_login(claims: &Claims) -> Result<(), AppError> {
+    if !auth::is_own_login(claims) {
+        return Err(AppError::ForbiddenError(
+            "This requires logging in as the account owner".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn fetch_user(state: &AppState, user_id: i32) -> Result<User, AppError> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)
+}
+
+fn verify_password(state: &AppState, user: &User, password: &str) -> Result<(), AppError> {
+    if !state
+        .password_hasher
+        .verify(password, &user.password_hash)?
+    {
+        return Err(AppError::AuthError("Invalid credentials".to_string()));
+    }
+
+    Ok(())
+}
+
+async fn notify(state: &AppState, to: &str, subject: &str, body: String) {
+    let email = Email {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body,
+    };
+    if let Err(e) = state.mailer.send(email).await {
+        warn!("Failed to send \"{}\" email: {}", subject, e);
+    }
+}
+
+// Logging in again during the grace period keeps the account
+pub async fn cancel_deletion(
+    state: &AppState,
+    user: &User,
+    client: &ClientInfo,
+) -> Result<(), AppError> {
+    sqlx::query("UPDATE users SET deletion_scheduled_at = NULL WHERE id = $1")
+        .bind(user.id)
+        .execute(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    info!("User {} logged in, account deletion cancelled", user.id);
+    audit::record(
+        state,
+        AuditEvent::success("account_deletion_cancelled")
+            .user(user.id)
+            .client(client),
+    )
+    .await;
+
+    Ok(())
+}
+
+// Run the purge hooks, then delete the account; its rows in the other auth
+// tables go with it. Audit events are kept.
+pub async fn purge_user(state: &AppState, user: &User) -> Result<(), AppError> {
+    purge(state, user, false).await?;
+    Ok(())
+}
+
+// Purge the account, or with `only_if_due` only while its deletion is still
+// scheduled and due. Returns false when the account was kept.
+async fn purge(state: &AppState, user: &User, only_if_due: bool) -> Result<bool, AppError> {
+    for hook in &state.purge_hooks {
+        hook.purge(user)
+            .await
+            .map_err(|e| AppError::InternalError(format!("Purge hook failed: {}", e)))?;
+    }
+
+    // Logging in cancels the deletion, possibly while the hooks ran
+    let deleted = sqlx::query(
+        r#"
+        DELETE FROM users
+        WHERE id = $1
+          AND (NOT $2 OR (deletion_scheduled_at IS NOT NULL AND deletion_scheduled_at <= NOW()))
+        "#,
+    )
+    .bind(user.id)
+    .bind(only_if_due)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+    if deleted == 0 {
+        return Ok(false);
+    }
+
+    // Failed login counters are keyed by username rather than tied to the row
+    state.login_throttle.unlock(&user.username).await?;
+    state.revocations.forget_user(user.id);
+
+    Ok(true)
+}
+
+// Purge every account whose grace period has run out
+pub async fn purge_due_accounts(state: &AppState) -> Result<(), AppError> {
+    let due = sqlx::query_as::<_, User>("SELECT * FROM users WHERE deletion_scheduled_at <= NOW()")
+        .fetch_all(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    for user in due {
+        match purge(state, &user, true).await {
+            Ok(false) => info!(
+                "Kept account of user {}, its deletion was cancelled",
+                user.id
+            ),
+            Ok(true) => {
+                info!("Purged account of user {}", user.id);
+                audit::record(
+                    state,
+                    AuditEvent::success("account_purged").subject(user.id),
+                )
+                .await;
+            }
+            Err(e) => warn!("Failed to purge account of user {}: {:?}", user.id, e),
+        }
+    }
+
+    Ok(())
+}
+
+// Handler functions
+
+// Change the username or email address. A new address has to be verified
+// again, and the old one is told about the change.
+pub async fn update_profile(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Result<Json<PublicUser>, AppError> {
+    require_own_login(&claims)?;
+    let user = fetch_user(&state, claims.sub).await?;
+
+    let username = payload
+        .username
+        .as_deref()
+        .map(str::trim)
+        .filter(|username| *username != user.username);
+    let email = payload
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| *email != user.email);
+
+    if username.is_some_and(str::is_empty) {
+        return Err(AppError::ValidationError(
+            "Username must not be empty".to_string(),
+        ));
+    }
+    if let Some(email) = email {
+        if !email.contains('@') {
+            return Err(AppError::ValidationError(
+                "Invalid email address".to_string(),
+            ));
+        }
+        // Whoever controls the address can reset the password
+        let password = payload
+            .current_password
+            .as_deref()
+            .ok_or(AppError::ValidationError(
+                "current_password is required to change the email address".to_string(),
+            ))?;
+        verify_password(&state, &user, password)?;
+    }
+    if username.is_none() && email.is_none() {
+        return Ok(Json(user.into()));
+    }
+
+    let updated = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET
+            username = COALESCE($2, username),
+            email = COALESCE($3, email),
+            email_verified_at = CASE WHEN $3::TEXT IS NULL THEN email_verified_at END
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(user.id)
+    .bind(username)
+    .bind(email)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| {
+        if e.as_database_error()
+            .is_some_and(|e| e.is_unique_violation())
+        {
+            AppError::ValidationError("Username or email is already taken".to_string())
+        } else {
+            AppError::DatabaseError(e)
+        }
+    })?;
+
+    if email.is_some() {
+        verification::send_verification_email(&state, &updated).await?;
+        notify(
+            &state,
+            &user.email,
+            "Your email address was changed",
+            format!(
+                "Hi {},\n\nThe email address of your account was changed to {}. If this wasn't you, contact support right away.\n",
+                updated.username, updated.email
+            ),
+        )
+        .await;
+    }
+
+    let changed: Vec<&str> = [("username", username), ("email", email)]
+        .into_iter()
+        .filter_map(|(field, value)| value.map(|_| field))
+        .collect();
+    let event = AuditEvent::success("profile_updated")
+        .by(&claims)
+        .subject(user.id)
+        .client(&client)
+        .details(serde_json::json!({ "changed": changed }));
+    audit::record(&state, event).await;
+
+    Ok(Json(updated.into()))
+}
+
+// Sign the user out everywhere and purge the account after the grace period,
+// unless they log in again before then
+pub async fn delete_account(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<DeleteAccountRequest>,
+) -> Result<(StatusCode, Json<DeletionScheduled>), AppError> {
+    require_own_login(&claims)?;
+    let user = fetch_user(&state, claims.sub).await?;
+    verify_password(&state, &user, &payload.password)?;
+
+    let deletion_scheduled_at = chrono::Utc::now() + state.account_deletion_grace;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+    sqlx::query("UPDATE users SET deletion_scheduled_at = $2 WHERE id = $1")
+        .bind(user.id)
+        .bind(deletion_scheduled_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+    revoke_user_refresh_tokens(&mut tx, user.id).await?;
+    end_user_sessions(&mut tx, user.id).await?;
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    state.revocations.revoke_all(&state.db, user.id).await?;
+
+    notify(
+        &state,
+        &user.email,
+        "Your account will be deleted",
+        format!(
+            "Hi {},\n\nYour account and its data will be deleted on {}. Log in before then to keep it.\n",
+            user.username,
+            deletion_scheduled_at.format("%Y-%m-%d %H:%M UTC")
+        ),
+    )
+    .await;
+
+    let event = AuditEvent::success("account_deletion_scheduled")
+        .by(&claims)
+        .subject(user.id)
+        .client(&client)
+        .details(serde_json::json!({ "deletion_scheduled_at": deletion_scheduled_at }));
+    audit::record(&state, event).await;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(DeletionScheduled {
+            deletion_scheduled_at,
+        }),
+    ))
+}
+
+// Everything held about the user across the auth tables, as one JSON document
+pub async fn export_account(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+) -> Result<impl IntoResponse, AppError> {
+    require_own_login(&claims)?;
+
+    let profile = sqlx::query_scalar::<_, serde_json::Value>(&format!(
+        "SELECT row_to_json(t) FROM ({}) t",
+        EXPORT_PROFILE
+    ))
+    .bind(claims.sub)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    let mut export = serde_json::Map::new();
+    export.insert(
+        "exported_at".to_string(),
+        serde_json::json!(chrono::Utc::now()),
+    );
+    export.insert("profile".to_string(), profile);
+    for (section, query) in EXPORT_SECTIONS {
+        let rows = sqlx::query_scalar::<_, serde_json::Value>(&format!(
+            "SELECT COALESCE(json_agg(t), '[]'::json) FROM ({}) t",
+            query
+        ))
+        .bind(claims.sub)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+        export.insert(section.to_string(), rows);
+    }
+
+    let event = AuditEvent::success("account_exported")
+        .by(&claims)
+        .subject(claims.sub)
+        .client(&client);
+    audit::record(&state, event).await;
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"account-export.json\"",
+        )],
+        Json(serde_json::Value::Object(export)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_leaves_out_secrets() {
+        let queries = EXPORT_SECTIONS.iter().map(|(_, query)| *query);
+        for query in queries.chain([EXPORT_PROFILE]) {
+            // Columns are listed one by one, so new secret columns stay out
+            assert!(!query.contains('*'), "{}", query);
+            assert!(!query.contains("_hash"), "{}", query);
+            assert!(!query.contains("secret"), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use auth_api::authz::RequirePermission;
+use auth_api::permission;
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::sessions::ClientInfo;
+use crate::{AppError, AppState, Claims};
+
+permission!(pub AuditRead = "audit:read");
+
+// Advisory lock serializing appends, so the chain never forks
+const CHAIN_LOCK_ID: i64 = 0x6175_6474;
+
+// `prev_hash` of the very first event
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+// Events per export; larger logs are exported page by page with `after_id`
+const MAX_EXPORT_LIMIT: i64 = 10_000;
+
+// Events checked per query when verifying the chain
+const VERIFY_BATCH: i64 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+}
+
+// An event about to be recorded, e.g.
+// `AuditEvent::failure("login").client(&client).details(json!({ "reason": "locked" }))`
+pub struct AuditEvent {
+    event_type: &'static str,
+    outcome: Outcome,
+    actor_id: Option<i32>,
+    subject_id: Option<i32>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    details: Value,
+}
+
+impl AuditEvent {
+    pub fn new(event_type: &'static str, outcome: Outcome) -> Self {
+        AuditEvent {
+            event_type,
+            outcome,
+            actor_id: None,
+            subject_id: None,
+            ip_address: None,
+            user_agent: None,
+            details: Value::Object(Default::default()),
+        }
+    }
+
+    pub fn success(event_type: &'static str) -> Self {
+        AuditEvent::new(event_type, Outcome::Success)
+    }
+
+    pub fn failure(event_type: &'static str) -> Self {
+        AuditEvent::new(event_type, Outcome::Failure)
+    }
+
+    // The user acting on their own account
+    pub fn user(self, user_id: i32) -> Self {
+        self.actor(user_id).subject(user_id)
+    }
+
+    // The caller behind an access token; while impersonating, that's the
+    // administrator rather than the impersonated user
+    pub fn by(self, claims: &Claims) -> Self {
+        self.actor(claims.act.as_ref().map_or(claims.sub, |act| act.sub))
+    }
+
+    pub fn actor(mut self, user_id: i32) -> Self {
+        self.actor_id = Some(user_id);
+        self
+    }
+
+    pub fn subject(mut self, user_id: i32) -> Self {
+        self.subject_id = Some(user_id);
+        self
+    }
+
+    pub fn client(mut self, client: &ClientInfo) -> Self {
+        self.ip_address = client.ip_address.clone();
+        self.user_agent = client.user_agent.clone();
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct StoredEvent {
+    id: i64,
+    occurred_at: chrono::DateTime<chrono::Utc>,
+    event_type: String,
+    outcome: String,
+    actor_id: Option<i32>,
+    subject_id: Option<i32>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    details: Value,
+    prev_hash: String,
+    hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventFilter {
+    event_type: Option<String>,
+    outcome: Option<String>,
+    actor_id: Option<i32>,
+    subject_id: Option<i32>,
+    ip_address: Option<String>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    // Page backwards from this event id, when searching newest first
+    before_id: Option<i64>,
+    // Page forwards from this event id, when exporting oldest first
+    after_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChainStatus {
+    valid: bool,
+    checked: u64,
+    // First event whose hashes don't match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broken_at: Option<i64>,
+}
+
+// Objects with their keys sorted, so a value hashes the same before and after
+// a round trip through JSONB
+fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), canonical(v)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(canonical).collect()),
+        other => other.clone(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn chain_hash(
+    prev_hash: &str,
+    occurred_at: chrono::DateTime<chrono::Utc>,
+    event_type: &str,
+    outcome: &str,
+    actor_id: Option<i32>,
+    subject_id: Option<i32>,
+    ip_address: Option<&str>,
+    user_agent: Option<&str>,
+    details: &Value,
+) -> String {
+    // A JSON array keeps the fields apart without any escaping rules of our own
+    let record = serde_json::json!([
+        prev_hash,
+        occurred_at.timestamp_micros(),
+        event_type,
+        outcome,
+        actor_id,
+        subject_id,
+        ip_address,
+        user_agent,
+        canonical(details),
+    ]);
+    hex::encode(Sha256::digest(record.to_string().as_bytes()))
+}
+
+fn stored_hash(event: &StoredEvent) -> String {
+    chain_hash(
+        &event.prev_hash,
+        event.occurred_at,
+        &event.event_type,
+        &event.outcome,
+        event.actor_id,
+        event.subject_id,
+        event.ip_address.as_deref(),
+        event.user_agent.as_deref(),
+        &event.details,
+    )
+}
+
+async fn append(db: &PgPool, event: AuditEvent) -> Result<(), AppError> {
+    let mut tx = db.begin().await.map_err(AppError::DatabaseError)?;
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(CHAIN_LOCK_ID)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    let prev_hash =
+        sqlx::query_scalar::<_, String>("SELECT hash FROM auth_events ORDER BY id DESC LIMIT 1")
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(AppError::DatabaseError)?
+            .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+    // Postgres keeps microseconds, so hash exactly what will be stored
+    let now = chrono::Utc::now();
+    let occurred_at =
+        chrono::DateTime::from_timestamp_micros(now.timestamp_micros()).unwrap_or(now);
+    let hash = chain_hash(
+        &prev_hash,
+        occurred_at,
+        event.event_type,
+        event.outcome.as_str(),
+        event.actor_id,
+        event.subject_id,
+        event.ip_address.as_deref(),
+        event.user_agent.as_deref(),
+        &event.details,
+    );
+
+    sqlx::query(
+        r#"
+        INSERT INTO auth_events
+            (occurred_at, event_type, outcome, actor_id, subject_id, ip_address, user_agent, details, prev_hash, hash)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        "#,
+    )
+    .bind(occurred_at)
+    .bind(event.event_type)
+    .bind(event.outcome.as_str())
+    .bind(event.actor_id)
+    .bind(event.subject_id)
+    .bind(&event.ip_address)
+    .bind(&event.user_agent)
+    .bind(&event.details)
+    .bind(&prev_hash)
+    .bind(&hash)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)
+}
+
+// Append an event to the audit log. A failure is logged but doesn't fail the
+// request being audited.
+pub async fn record(state: &AppState, event: AuditEvent) {
+    let event_type = event.event_type;
+    if let Err(e) = append(&state.db, event).await {
+        warn!("Failed to record audit event {}: {:?}", event_type, e);
+    }
+}
+
+// The cursor continuing a listing in the given order. The other one would
+// jump to the far end of the log instead of the next page, so it's refused.
+fn page_cursor(filter: &EventFilter, newest_first: bool) -> Result<Option<i64>, AppError> {
+    let (cursor, wrong) = if newest_first {
+        (filter.before_id, filter.after_id.map(|_| "after_id"))
+    } else {
+        (filter.after_id, filter.before_id.map(|_| "before_id"))
+    };
+    match wrong {
+        Some(name) => Err(AppError::ValidationError(format!(
+            "`{}` doesn't apply to this order",
+            name
+        ))),
+        None => Ok(cursor),
+    }
+}
+
+async fn fetch_events(
+    db: &PgPool,
+    filter: &EventFilter,
+    newest_first: bool,
+    limit: i64,
+) -> Result<Vec<StoredEvent>, AppError> {
+    let cursor = page_cursor(filter, newest_first)?;
+    let (order, past_cursor) = if newest_first {
+        ("DESC", "<")
+    } else {
+        ("ASC", ">")
+    };
+    let query = format!(
+        r#"
+        SELECT * FROM auth_events
+        WHERE ($1::TEXT IS NULL OR event_type = $1)
+            AND ($2::TEXT IS NULL OR outcome = $2)
+            AND ($3::INTEGER IS NULL OR actor_id = $3)
+            AND ($4::INTEGER IS NULL OR subject_id = $4)
+            AND ($5::TEXT IS NULL OR ip_address = $5)
+            AND ($6::TIMESTAMPTZ IS NULL OR occurred_at >= $6)
+            AND ($7::TIMESTAMPTZ IS NULL OR occurred_at < $7)
+            AND ($8::BIGINT IS NULL OR id {} $8)
+        ORDER BY id {}
+        LIMIT $9
+        "#,
+        past_cursor, order
+    );
+
+    sqlx::query_as::<_, StoredEvent>(&query)
+        .bind(&filter.event_type)
+        .bind(&filter.outcome)
+        .bind(filter.actor_id)
+        .bind(filter.subject_id)
+        .bind(&filter.ip_address)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .map_err(AppError::DatabaseError)
+}
+
+// Handler functions
+pub async fn list_events(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<AuditRead>,
+    Query(filter): Query<EventFilter>,
+) -> Result<Json<Vec<StoredEvent>>, AppError> {
+    let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let events = fetch_events(&state.db, &filter, true, limit).await?;
+
+    Ok(Json(events))
+}
+
+// Matching events as JSON lines, oldest first, for SIEM ingestion. A page
+// holds at most `MAX_EXPORT_LIMIT` events; the next one starts after the id
+// of the last line.
+pub async fn export_events(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<AuditRead>,
+    Query(filter): Query<EventFilter>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = filter
+        .limit
+        .unwrap_or(MAX_EXPORT_LIMIT)
+        .clamp(1, MAX_EXPORT_LIMIT);
+    let events = fetch_events(&state.db, &filter, false, limit).await?;
+
+    let mut body = String::new();
+    for event in &events {
+        body.push_str(&serde_json::to_string(event).unwrap_or_default());
+        body.push('\n');
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"auth_events.jsonl\"",
+            ),
+        ],
+        body,
+    ))
+}
+
+// Walk the whole chain and report the first event that doesn't fit
+pub async fn verify_chain(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<AuditRead>,
+) -> Result<Json<ChainStatus>, AppError> {
+    let mut expected_prev = GENESIS_HASH.to_string();
+    let mut last_id = 0i64;
+    let mut checked = 0u64;
+
+    loop {
+        let batch = sqlx::query_as::<_, StoredEvent>(
+            "SELECT * FROM auth_events WHERE id > $1 ORDER BY id LIMIT $2",
+        )
+        .bind(last_id)
+        .bind(VERIFY_BATCH)
+        .fetch_all(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        if batch.is_empty() {
+            break;
+        }
+
+        for event in batch {
+            if event.prev_hash != expected_prev || stored_hash(&event) != event.hash {
+                return Ok(Json(ChainStatus {
+                    valid: false,
+                    checked,
+                    broken_at: Some(event.id),
+                }));
+            }
+            checked += 1;
+            last_id = event.id;
+            expected_prev = event.hash;
+        }
+    }
+
+    Ok(Json(ChainStatus {
+        valid: true,
+        checked,
+        broken_at: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(details: Value) -> StoredEvent {
+        let occurred_at = chrono::DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap();
+        let mut event = StoredEvent {
+            id: 1,
+            occurred_at,
+            event_type: "login".to_string(),
+            outcome: "success".to_string(),
+            actor_id: Some(42),
+            subject_id: Some(42),
+            ip_address: Some("127.0.0.1".to_string()),
+            user_agent: None,
+            details,
+            prev_hash: GENESIS_HASH.to_string(),
+            hash: String::new(),
+        };
+        event.hash = stored_hash(&event);
+        event
+    }
+
+    #[test]
+    fn hash_ignores_key_order() {
+        let a = event(serde_json::json!({ "a": 1, "b": { "y": 2, "x": 3 } }));
+        let b = event(serde_json::json!({ "b": { "x": 3, "y": 2 }, "a": 1 }));
+        assert_eq!(a.hash, b.hash);
+    }
+
+    #[test]
+    fn hash_covers_every_field() {
+        let original = event(serde_json::json!({}));
+
+        let mut tampered = event(serde_json::json!({}));
+        tampered.outcome = "failure".to_string();
+        assert_ne!(stored_hash(&tampered), original.hash);
+
+        let mut tampered = event(serde_json::json!({}));
+        tampered.prev_hash = "f".repeat(64);
+        assert_ne!(stored_hash(&tampered), original.hash);
+    }
+
+    fn filter(before_id: Option<i64>, after_id: Option<i64>) -> EventFilter {
+        EventFilter {
+            event_type: None,
+            outcome: None,
+            actor_id: None,
+            subject_id: None,
+            ip_address: None,
+            since: None,
+            until: None,
+            before_id,
+            after_id,
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn cursors_follow_the_order() {
+        assert_eq!(page_cursor(&filter(Some(7), None), true).unwrap(), Some(7));
+        assert_eq!(page_cursor(&filter(None, Some(7)), false).unwrap(), Some(7));
+        assert_eq!(page_cursor(&filter(None, None), false).unwrap(), None);
+
+        // Paging backwards through an oldest-first export would restart it
+        assert!(page_cursor(&filter(Some(7), None), false).is_err());
+        assert!(page_cursor(&filter(None, Some(7)), true).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use auth_api::authz::{Authenticator, AuthzError};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts},
+};
+use std::sync::Arc;
+
+use crate::{api_keys, oidc, AppError, AppState, Claims};
+
+// Authenticated caller, extracted from the `Authorization: Bearer` header.
+// Cookie clients get one too, filled in by `cookies::authenticate_cookies`.
+// Tokens issued to OAuth clients are rejected, see `ClientAuthUser`, and so
+// are API keys, see `KeyOrAuthUser`.
+pub struct AuthUser {
+    pub claims: Claims,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = decode_token(state, bearer_token(parts)?).await?;
+        reject_api_keys(&claims)?;
+
+        Ok(AuthUser { claims })
+    }
+}
+
+// Caller that may also be using an API key. Only for endpoints that neither
+// change the account nor reveal its credentials and sessions.
+pub struct KeyOrAuthUser {
+    pub claims: Claims,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for KeyOrAuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = decode_token(state, bearer_token(parts)?).await?;
+
+        Ok(KeyOrAuthUser { claims })
+    }
+}
+
+// Caller holding an access token issued to an OAuth client, which is only
+// good for `/oauth/userinfo`
+pub struct ClientAuthUser {
+    pub claims: Claims,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientAuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = decode_client_token(state, bearer_token(parts)?).await?;
+
+        Ok(ClientAuthUser { claims })
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Result<&str, AppError> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or(AppError::AuthError("Missing token".to_string()))
+}
+
+// Verify a token's signature and expiry, then make sure it wasn't revoked.
+// API keys are looked up instead; deleting a key is what revokes it.
+pub async fn decode_token(state: &AppState, token: &str) -> Result<Claims, AppError> {
+    if api_keys::is_api_key(token) {
+        return api_keys::authenticate(state, token).await;
+    }
+
+    check_revoked(state, state.keys.verify(&state.db, token).await?).await
+}
+
+// Same for access tokens issued to OAuth clients, which carry their own
+// audience so that `decode_token` refuses them
+pub async fn decode_client_token(state: &AppState, token: &str) -> Result<Claims, AppError> {
+    let claims = state
+        .keys
+        .verify_audience(&state.db, token, Some(oidc::CLIENT_TOKEN_AUDIENCE))
+        .await?;
+
+    check_revoked(state, claims).await
+}
+
+async fn check_revoked(state: &AppState, claims: Claims) -> Result<Claims, AppError> {
+    if state.revocations.is_revoked(&state.db, &claims).await? {
+        return Err(AppError::AuthError("Token revoked".to_string()));
+    }
+
+    Ok(claims)
+}
+
+// A leaked key must not be able to take over the account, e.g. by enrolling
+// its own second factor or listing and deleting the owner's other keys
+fn reject_api_keys(claims: &Claims) -> Result<(), AppError> {
+    if api_keys::is_api_key_claims(claims) {
+        return Err(AppError::ForbiddenError(
+            "API keys can't be used for this endpoint".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Whether the token comes from the user logging in themselves, rather than an
+// API key, an OAuth client or an administrator impersonating them
+pub fn is_own_login(claims: &Claims) -> bool {
+    !api_keys::is_api_key_claims(claims) && claims.client_id.is_none() && claims.act.is_none()
+}
+
+// Lets the shared `authz` extractors see revoked tokens too
+pub struct RevocationAwareAuthenticator {
+    pub state: Arc<AppState>,
+}
+
+#[async_trait]
+impl Authenticator for RevocationAwareAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Claims, AuthzError> {
+        decode_token(&self.state, token).await.map_err(|e| match e {
+            AppError::AuthError(msg) => AuthzError::Unauthorized(msg),
+            AppError::ForbiddenError(msg) => AuthzError::Forbidden(msg),
+            AppError::DatabaseError(e) => AuthzError::Internal(e.to_string()),
+            AppError::ValidationError(msg)
+            | AppError::RateLimitError(msg)
+            | AppError::InternalError(msg) => AuthzError::Internal(msg),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(jti: &str) -> Claims {
+        Claims {
+            sub: 1,
+            exp: 0,
+            iat: 0,
+            jti: jti.to_string(),
+            sid: None,
+            act: None,
+            org_id: None,
+            roles: Vec::new(),
+            permissions: Vec::new(),
+            client_id: None,
+            scope: None,
+            aud: None,
+        }
+    }
+
+    #[test]
+    fn rejects_api_keys() {
+        let key = claims("api_key:1");
+        assert!(matches!(
+            reject_api_keys(&key),
+            Err(AppError::ForbiddenError(_))
+        ));
+        assert!(!is_own_login(&key));
+
+        let login = claims("0b7e1c9a-8f1d-4a53-9d8e-2f6f3c1d5a42");
+        assert!(reject_api_keys(&login).is_ok());
+        assert!(is_own_login(&login));
+    }
+}
@@ -0,0 +1,373 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long fetched JWKS keys are trusted before asking again
+const JWKS_MAX_AGE: Duration = Duration::from_secs(600);
+
+// Unknown `kid`s trigger a refetch, but not more often than this
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(30);
+
+// JWT Claims
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: i32,    // user id
+    pub exp: usize,  // expiration time
+    pub iat: usize,  // issued at
+    pub jti: String, // unique token id, used for revocation
+    // Session the token belongs to, so ending the session revokes it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // Set when an admin impersonates `sub`: who is really acting (RFC 8693)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    // Organization the token acts in, switched with `POST /auth/switch-org`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<i32>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    // Union of the permissions granted by `roles` when the token was issued
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    // Set on tokens issued to OAuth clients, which only carry the granted scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // Limits the token to the endpoints of one audience. The authenticators
+    // here reject every token that has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: i32,
+}
+
+impl Claims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    // `*` grants everything and `todos:*` grants every `todos:` permission
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|granted| {
+            granted == "*"
+                || granted == permission
+                || granted
+                    .strip_suffix('*')
+                    .is_some_and(|prefix| prefix.ends_with(':') && permission.starts_with(prefix))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthzError {
+    Unauthorized(String),
+    Forbidden(String),
+    Internal(String),
+}
+
+impl IntoResponse for AuthzError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AuthzError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            AuthzError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            AuthzError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        };
+
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+// Turns a bearer token into claims. The extractors find it in the request
+// extensions, so add it to the router with `.layer(Extension(authenticator))`.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, token: &str) -> Result<Claims, AuthzError>;
+}
+
+// Verifies tokens signed with the shared `JWT_SECRET`. Revocation isn't
+// checked, so a revoked token stays usable here until it expires.
+pub struct JwtAuthenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    pub fn from_secret(secret: &[u8]) -> Self {
+        JwtAuthenticator {
+            key: DecodingKey::from_secret(secret),
+            validation: Validation::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Claims, AuthzError> {
+        decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|_| AuthzError::Unauthorized("Invalid token".to_string()))
+    }
+}
+
+// Verification key and algorithm of a published JWK
+pub fn decoding_key(jwk: &Jwk) -> Option<(Algorithm, DecodingKey)> {
+    let algorithm = Algorithm::from_str(&jwk.common.key_algorithm?.to_string()).ok()?;
+    let key = DecodingKey::from_jwk(jwk).ok()?;
+    Some((algorithm, key))
+}
+
+#[derive(Default)]
+struct JwksCache {
+    keys: HashMap<String, (Algorithm, DecodingKey)>,
+    fetched: Option<Instant>,
+}
+
+// Verifies tokens against the public keys served at `/.well-known/jwks.json`,
+// so services don't need any secret. Like `JwtAuthenticator`, it doesn't see
+// revocations.
+pub struct JwksAuthenticator {
+    url: String,
+    client: reqwest::Client,
+    cache: Mutex<JwksCache>,
+}
+
+impl JwksAuthenticator {
+    // e.g. `JwksAuthenticator::new("http://localhost:3000/.well-known/jwks.json")`
+    pub fn new(url: impl Into<String>) -> Self {
+        JwksAuthenticator {
+            url: url.into(),
+            client: reqwest::Client::new(),
+            cache: Mutex::new(JwksCache::default()),
+        }
+    }
+
+    fn cached(&self, kid: &str) -> (Option<(Algorithm, DecodingKey)>, bool) {
+        let cache = self.cache.lock().unwrap();
+        let age = cache.fetched.map(|fetched| fetched.elapsed());
+        let key = cache.keys.get(kid).cloned();
+        let stale = age.is_none_or(|age| age > JWKS_MAX_AGE);
+        let may_refetch = age.is_none_or(|age| age > JWKS_MIN_REFETCH);
+
+        ((!stale).then_some(key).flatten(), may_refetch)
+    }
+
+    async fn fetch(&self) -> Result<(), AuthzError> {
+        let jwks = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| AuthzError::Internal(format!("Failed to fetch JWKS: {}", e)))?
+            .json::<JwkSet>()
+            .await
+            .map_err(|e| AuthzError::Internal(format!("Invalid JWKS: {}", e)))?;
+
+        let keys = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| Some((jwk.common.key_id.clone()?, decoding_key(jwk)?)))
+            .collect();
+
+        *self.cache.lock().unwrap() = JwksCache {
+            keys,
+            fetched: Some(Instant::now()),
+        };
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Authenticator for JwksAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Claims, AuthzError> {
+        let kid = decode_header(token)
+            .ok()
+            .and_then(|header| header.kid)
+            .ok_or(AuthzError::Unauthorized("Invalid token".to_string()))?;
+
+        let (mut key, may_refetch) = self.cached(&kid);
+        if key.is_none() && may_refetch {
+            self.fetch().await?;
+            key = self.cached(&kid).0;
+        }
+        let (algorithm, key) = key.ok_or(AuthzError::Unauthorized("Invalid token".to_string()))?;
+
+        decode::<Claims>(token, &key, &Validation::new(algorithm))
+            .map(|data| data.claims)
+            .map_err(|_| AuthzError::Unauthorized("Invalid token".to_string()))
+    }
+}
+
+async fn authenticate(parts: &Parts) -> Result<Claims, AuthzError> {
+    let authenticator = parts
+        .extensions
+        .get::<Arc<dyn Authenticator>>()
+        .cloned()
+        .ok_or(AuthzError::Internal(
+            "No authenticator configured".to_string(),
+        ))?;
+
+    let token = parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or(AuthzError::Unauthorized("Missing token".to_string()))?;
+
+    authenticator.authenticate(token).await
+}
+
+// Any authenticated caller
+pub struct Authenticated(pub Claims);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Authenticated {
+    type Rejection = AuthzError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Authenticated(authenticate(parts).await?))
+    }
+}
+
+// A permission checked at compile time, declared with `permission!`
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+// Declare a permission type for `RequirePermission`:
+// `permission!(pub TodosWrite = "todos:write");`
+#[macro_export]
+macro_rules! permission {
+    ($vis:vis $name:ident = $permission:literal) => {
+        $vis struct $name;
+
+        impl $crate::authz::Permission for $name {
+            const NAME: &'static str = $permission;
+        }
+    };
+}
+
+// Caller holding the permission `P`, e.g. `RequirePermission<TodosWrite>`
+pub struct RequirePermission<P: Permission> {
+    pub claims: Claims,
+    permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<S: Send + Sync, P: Permission> FromRequestParts<S> for RequirePermission<P> {
+    type Rejection = AuthzError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let claims = authenticate(parts).await?;
+        if !claims.has_permission(P::NAME) {
+            return Err(AuthzError::Forbidden(format!(
+                "Missing permission `{}`",
+                P::NAME
+            )));
+        }
+
+        Ok(RequirePermission {
+            claims,
+            permission: PhantomData,
+        })
+    }
+}
+
+// Middleware guarding every route of a router with one permission:
+// `.route_layer(middleware::from_fn_with_state("todos:write", require_permission))`
+pub async fn require_permission(
+    State(permission): State<&'static str>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthzError> {
+    let (mut parts, body) = request.into_parts();
+    let claims = authenticate(&parts).await?;
+    if !claims.has_permission(permission) {
+        return Err(AuthzError::Forbidden(format!(
+            "Missing permission `{}`",
+            permission
+        )));
+    }
+
+    // Handlers behind the layer can still read the claims
+    parts.extensions.insert(claims);
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(permissions: &[&str]) -> Claims {
+        Claims {
+            sub: 1,
+            exp: 0,
+            iat: 0,
+            jti: String::new(),
+            sid: None,
+            act: None,
+            org_id: None,
+            roles: Vec::new(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            client_id: None,
+            scope: None,
+            aud: None,
+        }
+    }
+
+    #[test]
+    fn matches_exact_permissions() {
+        let claims = claims(&["todos:read"]);
+        assert!(claims.has_permission("todos:read"));
+        assert!(!claims.has_permission("todos:write"));
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_with_an_audience() {
+        let authenticator = JwtAuthenticator::from_secret(b"secret");
+        let sign = |claims: &Claims| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                claims,
+                &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+            )
+            .unwrap()
+        };
+        let mut claims = claims(&[]);
+        claims.exp = (chrono::Utc::now().timestamp() + 60) as usize;
+
+        assert!(authenticator.authenticate(&sign(&claims)).await.is_ok());
+
+        claims.aud = Some("oauth-client".to_string());
+        assert!(authenticator.authenticate(&sign(&claims)).await.is_err());
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(claims(&["*"]).has_permission("roles:write"));
+        assert!(claims(&["todos:*"]).has_permission("todos:write"));
+        assert!(!claims(&["todos:*"]).has_permission("todosx:write"));
+        assert!(!claims(&["todos*"]).has_permission("todosx:write"));
+    }
+}
@@ -0,0 +1,350 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{
+        header::{AUTHORIZATION, COOKIE, SET_COOKIE},
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::tokens::generate_opaque_token;
+use crate::views::PublicUser;
+use crate::{AppError, AppState, AuthResponse};
+
+const ACCESS_COOKIE: &str = "access_token";
+const REFRESH_COOKIE: &str = "refresh_token";
+// Readable by scripts, which echo it in `CSRF_HEADER`
+const CSRF_COOKIE: &str = "csrf_token";
+
+const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+// Sent by clients that want their tokens in cookies
+const SESSION_MODE_HEADER: HeaderName = HeaderName::from_static("x-session-mode");
+
+// The refresh cookie is only sent where it's needed
+const REFRESH_COOKIE_PATH: &str = "/auth/refresh";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+// Settings of the cookie session mode, off unless `SESSION_COOKIES=on`
+#[derive(Debug, Clone)]
+pub struct SessionCookies {
+    // Unset for host-only cookies
+    domain: Option<String>,
+    same_site: SameSite,
+}
+
+impl SessionCookies {
+    pub fn new(domain: Option<String>, same_site: SameSite) -> Self {
+        SessionCookies { domain, same_site }
+    }
+
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var("SESSION_COOKIES").as_deref() {
+            Ok("on") => {}
+            Ok("off") | Err(_) => return Ok(None),
+            Ok(other) => return Err(format!("Unknown SESSION_COOKIES `{}`", other)),
+        }
+
+        let same_site = match std::env::var("SESSION_COOKIE_SAMESITE").as_deref() {
+            Ok("strict") => SameSite::Strict,
+            Ok("lax") | Err(_) => SameSite::Lax,
+            Ok("none") => SameSite::None,
+            Ok(other) => return Err(format!("Unknown SESSION_COOKIE_SAMESITE `{}`", other)),
+        };
+        let domain = std::env::var("SESSION_COOKIE_DOMAIN")
+            .ok()
+            .filter(|domain| !domain.is_empty());
+
+        Ok(Some(SessionCookies::new(domain, same_site)))
+    }
+
+    fn cookie(&self, name: &str, value: &str, path: &str, max_age: i64, http_only: bool) -> String {
+        let mut cookie = format!(
+            "{}={}; Path={}; Max-Age={}; Secure; SameSite={}",
+            name,
+            value,
+            path,
+            max_age,
+            self.same_site.as_str()
+        );
+        if http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if let Some(domain) = &self.domain {
+            cookie.push_str("; Domain=");
+            cookie.push_str(domain);
+        }
+        cookie
+    }
+
+    fn session_cookies(
+        &self,
+        state: &AppState,
+        response: &AuthResponse,
+        csrf: &str,
+    ) -> Vec<String> {
+        let refresh_max_age = state.refresh_token_ttl.num_seconds();
+        vec![
+            self.cookie(
+                ACCESS_COOKIE,
+                &response.token,
+                "/",
+                response.expires_in,
+                true,
+            ),
+            self.cookie(
+                REFRESH_COOKIE,
+                &response.refresh_token,
+                REFRESH_COOKIE_PATH,
+                refresh_max_age,
+                true,
+            ),
+            self.cookie(CSRF_COOKIE, csrf, "/", refresh_max_age, false),
+        ]
+    }
+
+    fn expired_cookies(&self) -> Vec<String> {
+        vec![
+            self.cookie(ACCESS_COOKIE, "", "/", 0, true),
+            self.cookie(REFRESH_COOKIE, "", REFRESH_COOKIE_PATH, 0, true),
+            self.cookie(CSRF_COOKIE, "", "/", 0, false),
+        ]
+    }
+}
+
+// Where a login's tokens go: the response body, or HttpOnly cookies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionMode {
+    Bearer,
+    Cookie,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for SessionMode {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let wants_cookies = parts
+            .headers
+            .get(SESSION_MODE_HEADER)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"cookie"));
+
+        match (wants_cookies, &state.session_cookies) {
+            (false, _) => Ok(SessionMode::Bearer),
+            (true, Some(_)) => Ok(SessionMode::Cookie),
+            (true, None) => Err(AppError::ValidationError(
+                "Cookie sessions are not enabled".to_string(),
+            )),
+        }
+    }
+}
+
+// What cookie clients get instead of `AuthResponse`: no tokens, only the
+// CSRF token they have to send back
+#[derive(Debug, Serialize)]
+struct CookieSessionResponse {
+    expires_in: i64,
+    csrf_token: String,
+    user: PublicUser,
+}
+
+// Answer a successful login or refresh in the mode the client asked for
+pub fn respond(state: &AppState, mode: SessionMode, response: AuthResponse) -> Response {
+    let Some(config) = state
+        .session_cookies
+        .as_ref()
+        .filter(|_| mode == SessionMode::Cookie)
+    else {
+        return Json(response).into_response();
+    };
+
+    let csrf_token = generate_opaque_token();
+    let mut headers = HeaderMap::new();
+    for cookie in config.session_cookies(state, &response, &csrf_token) {
+        headers.append(SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+    }
+
+    let body = CookieSessionResponse {
+        expires_in: response.expires_in,
+        csrf_token,
+        user: response.user,
+    };
+    (headers, Json(body)).into_response()
+}
+
+// Headers removing the session cookies, if the mode is on
+pub fn clear(state: &AppState) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(config) = &state.session_cookies {
+        for cookie in config.expired_cookies() {
+            headers.append(SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        }
+    }
+    headers
+}
+
+// The refresh token cookie, if the mode is on
+pub fn refresh_token(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    state.session_cookies.as_ref()?;
+    cookie(headers, REFRESH_COOKIE).map(str::to_string)
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Double-submit check: a cross-site page can make the browser send the
+// cookies, but can't read the CSRF cookie to copy it into the header
+fn csrf_matches(headers: &HeaderMap) -> bool {
+    let header = headers.get(CSRF_HEADER).map(HeaderValue::as_bytes);
+    match (cookie(headers, CSRF_COOKIE), header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() => {
+            cookie.len() == header.len()
+                && cookie
+                    .bytes()
+                    .zip(header)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+        _ => false,
+    }
+}
+
+// Middleware letting cookie clients through every extractor: state-changing
+// requests carrying session cookies must pass the CSRF check, then the access
+// cookie stands in for a missing `Authorization` header
+pub async fn authenticate_cookies(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if state.session_cookies.is_none() || request.headers().contains_key(AUTHORIZATION) {
+        return Ok(next.run(request).await);
+    }
+
+    let headers = request.headers();
+    let has_session =
+        cookie(headers, ACCESS_COOKIE).is_some() || cookie(headers, REFRESH_COOKIE).is_some();
+    if has_session && !request.method().is_safe() && !csrf_matches(headers) {
+        return Err(AppError::ForbiddenError(
+            "Missing or invalid CSRF token".to_string(),
+        ));
+    }
+
+    if let Some(token) = cookie(headers, ACCESS_COOKIE).filter(|token| !token.is_empty()) {
+        let bearer = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| AppError::AuthError("Invalid token".to_string()))?;
+        request.headers_mut().insert(AUTHORIZATION, bearer);
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::views::tests::{assert_no_hash_material, user, PASSWORD_HASHES};
+
+    // What cookie-mode logins and refreshes answer with instead of tokens
+    #[test]
+    fn cookie_session_body_never_contains_password_hashes() {
+        for password_hash in PASSWORD_HASHES {
+            let body = CookieSessionResponse {
+                expires_in: 900,
+                csrf_token: "csrf".to_string(),
+                user: user(password_hash).into(),
+            };
+            assert_no_hash_material(&body, password_hash);
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn finds_cookies_across_headers() {
+        let headers = headers(&[
+            ("cookie", "theme=dark; access_token=abc.def"),
+            ("cookie", "csrf_token=xyz"),
+        ]);
+        assert_eq!(cookie(&headers, ACCESS_COOKIE), Some("abc.def"));
+        assert_eq!(cookie(&headers, CSRF_COOKIE), Some("xyz"));
+        assert_eq!(cookie(&headers, REFRESH_COOKIE), None);
+    }
+
+    #[test]
+    fn csrf_header_must_match_cookie() {
+        assert!(csrf_matches(&headers(&[
+            ("cookie", "csrf_token=xyz"),
+            ("x-csrf-token", "xyz"),
+        ])));
+        assert!(!csrf_matches(&headers(&[
+            ("cookie", "csrf_token=xyz"),
+            ("x-csrf-token", "xyw"),
+        ])));
+        assert!(!csrf_matches(&headers(&[("cookie", "csrf_token=xyz")])));
+        assert!(!csrf_matches(&headers(&[
+            ("cookie", "csrf_token="),
+            ("x-csrf-token", ""),
+        ])));
+    }
+
+    #[test]
+    fn formats_cookie_attributes() {
+        let config = SessionCookies {
+            domain: Some("example.com".to_string()),
+            same_site: SameSite::Strict,
+        };
+        assert_eq!(
+            config.cookie(ACCESS_COOKIE, "abc", "/", 900, true),
+            "access_token=abc; Path=/; Max-Age=900; Secure; SameSite=Strict; HttpOnly; Domain=example.com"
+        );
+        let host_only = SessionCookies {
+            domain: None,
+            same_site: SameSite::Lax,
+        };
+        assert_eq!(
+            host_only.cookie(CSRF_COOKIE, "xyz", "/", 60, false),
+            "csrf_token=xyz; Path=/; Max-Age=60; Secure; SameSite=Lax"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params, Version};
+use tracing::{info, warn};
+
+use crate::{AppError, AppState, User};
+
+// Lowest bcrypt cost still accepted for new hashes
+const MIN_BCRYPT_COST: u32 = 12;
+
+// A password hashing scheme. Hashes are self-describing strings (PHC or
+// modular crypt format), so the parameters travel with every hash.
+pub trait PasswordHasher: Send + Sync {
+    // Whether `hash` was produced by this scheme
+    fn recognizes(&self, hash: &str) -> bool;
+
+    fn hash(&self, password: &str) -> Result<String, AppError>;
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError>;
+
+    // Whether `hash` was made with other parameters than this hasher uses
+    fn is_outdated(&self, hash: &str) -> bool;
+}
+
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, String> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|e| format!("Invalid Argon2 parameters: {}", e))?;
+        Ok(Argon2idHasher { params })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(
+            argon2::Algorithm::Argon2id,
+            Version::V0x13,
+            self.params.clone(),
+        )
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, AppError> {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| AppError::ValidationError(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError> {
+        let parsed =
+            PasswordHash::new(hash).map_err(|e| AppError::ValidationError(e.to_string()))?;
+
+        // The parameters stored in the hash are used, not the configured ones
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(AppError::ValidationError(e.to_string())),
+        }
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let Ok(params) = Params::try_from(&parsed) else {
+            return true;
+        };
+
+        parsed.algorithm != argon2::Algorithm::Argon2id.ident()
+            || parsed.version != Some(Version::V0x13.into())
+            || params.m_cost() != self.params.m_cost()
+            || params.t_cost() != self.params.t_cost()
+            || params.p_cost() != self.params.p_cost()
+    }
+}
+
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+impl BcryptHasher {
+    pub fn new(cost: u32) -> Self {
+        BcryptHasher { cost }
+    }
+}
+
+// Cost of a `$2b$<cost>$...` hash
+fn bcrypt_cost(hash: &str) -> Option<u32> {
+    hash.split('$').nth(2)?.parse().ok()
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, AppError> {
+        bcrypt::hash(password, self.cost).map_err(|e| AppError::ValidationError(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError> {
+        bcrypt::verify(password, hash).map_err(|e| AppError::ValidationError(e.to_string()))
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        bcrypt_cost(hash) != Some(self.cost)
+    }
+}
+
+// Hashes new passwords with the preferred scheme and still verifies hashes
+// made with the others
+pub struct PasswordHashing {
+    preferred: Box<dyn PasswordHasher>,
+    others: Vec<Box<dyn PasswordHasher>>,
+}
+
+impl PasswordHashing {
+    pub fn new(preferred: Box<dyn PasswordHasher>, others: Vec<Box<dyn PasswordHasher>>) -> Self {
+        PasswordHashing { preferred, others }
+    }
+
+    // Read `PASSWORD_HASH_ALGORITHM` (argon2id or bcrypt), `ARGON2_MEMORY_KIB`,
+    // `ARGON2_ITERATIONS`, `ARGON2_PARALLELISM` and `BCRYPT_COST`
+    pub fn from_env() -> Result<Self, String> {
+        let argon2 = Argon2idHasher::new(
+            crate::env_or("ARGON2_MEMORY_KIB", 19456) as u32,
+            crate::env_or("ARGON2_ITERATIONS", 2) as u32,
+            crate::env_or("ARGON2_PARALLELISM", 1) as u32,
+        )?;
+        let cost = crate::env_or("BCRYPT_COST", MIN_BCRYPT_COST as i64) as u32;
+        if cost < MIN_BCRYPT_COST {
+            return Err(format!("BCRYPT_COST must be at least {}", MIN_BCRYPT_COST));
+        }
+        let bcrypt = BcryptHasher::new(cost);
+
+        match std::env::var("PASSWORD_HASH_ALGORITHM")
+            .as_deref()
+            .unwrap_or("argon2id")
+        {
+            "argon2id" => Ok(PasswordHashing::new(
+                Box::new(argon2),
+                vec![Box::new(bcrypt)],
+            )),
+            "bcrypt" => Ok(PasswordHashing::new(
+                Box::new(bcrypt),
+                vec![Box::new(argon2)],
+            )),
+            other => Err(format!("Unknown PASSWORD_HASH_ALGORITHM `{}`", other)),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, AppError> {
+        self.preferred.hash(password)
+    }
+
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError> {
+        let hasher = std::iter::once(&self.preferred)
+            .chain(&self.others)
+            .find(|hasher| hasher.recognizes(hash))
+            .ok_or(AppError::ValidationError(
+                "Unknown password hash format".to_string(),
+            ))?;
+
+        hasher.verify(password, hash)
+    }
+
+    // Whether `hash` should be replaced by one from the preferred scheme
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        !self.preferred.recognizes(hash) || self.preferred.is_outdated(hash)
+    }
+}
+
+// After a successful login, replace a hash made with an old scheme or old
+// parameters. Failing to do so doesn't fail the login.
+pub async fn rehash_if_needed(state: &AppState, user: &User, password: &str) {
+    if !state.password_hasher.needs_rehash(&user.password_hash) {
+        return;
+    }
+
+    let password_hash = match state.password_hasher.hash(password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            warn!("Failed to rehash password of user {}: {:?}", user.id, e);
+            return;
+        }
+    };
+
+    // Only replace the hash that was just verified, in case it changed meanwhile
+    let updated =
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3")
+            .bind(&password_hash)
+            .bind(user.id)
+            .bind(&user.password_hash)
+            .execute(&state.db)
+            .await;
+
+    match updated {
+        Ok(_) => info!("Upgraded password hash of user {}", user.id),
+        Err(e) => warn!(
+            "Failed to store rehashed password of user {}: {}",
+            user.id, e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters, the tests only check the bookkeeping
+    fn hashing() -> PasswordHashing {
+        PasswordHashing::new(
+            Box::new(Argon2idHasher::new(1024, 1, 1).unwrap()),
+            vec![Box::new(BcryptHasher::new(5))],
+        )
+    }
+
+    #[test]
+    fn hashes_with_argon2id() {
+        let hashing = hashing();
+        let hash = hashing.hash("correct horse").unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(hashing.verify("correct horse", &hash).unwrap());
+        assert!(!hashing.verify("wrong horse", &hash).unwrap());
+        assert!(!hashing.needs_rehash(&hash));
+    }
+
+    #[test]
+    fn upgrades_legacy_hashes() {
+        let hashing = hashing();
+
+        let legacy = bcrypt::hash("correct horse", 4).unwrap();
+        assert!(hashing.verify("correct horse", &legacy).unwrap());
+        assert!(hashing.needs_rehash(&legacy));
+
+        let weaker = Argon2idHasher::new(512, 1, 1)
+            .unwrap()
+            .hash("correct horse")
+            .unwrap();
+        assert!(hashing.verify("correct horse", &weaker).unwrap());
+        assert!(hashing.needs_rehash(&weaker));
+    }
+
+    #[test]
+    fn flags_outdated_bcrypt_cost() {
+        let hasher = BcryptHasher::new(5);
+        assert!(hasher.is_outdated(&bcrypt::hash("pw", 4).unwrap()));
+        assert!(!hasher.is_outdated(&bcrypt::hash("pw", 5).unwrap()));
+    }
+}
@@ -0,0 +1,415 @@
+use auth_api::authz::{decoding_key, Claims};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rsa::{pkcs1::EncodeRsaPrivateKey, traits::PublicKeyParts, RsaPrivateKey};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Instant;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::AppError;
+
+// Arbitrary constant identifying the rotation lock across instances
+const ROTATION_LOCK_ID: i64 = 0x6a77_6b73;
+
+// Unknown `kid`s reload the keys from the database, at most this often
+const MIN_RELOAD_SECS: u64 = 5;
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredKey {
+    kid: String,
+    algorithm: String,
+    private_key: Vec<u8>,
+    public_jwk: sqlx::types::Json<Jwk>,
+    retired_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Default)]
+struct Keys {
+    signing: Option<(String, EncodingKey)>,
+    verifying: HashMap<String, (Algorithm, DecodingKey)>,
+    jwks: Vec<Jwk>,
+    loaded: Option<Instant>,
+}
+
+// Keys used to sign and verify access tokens.
+//
+// With `HS256` every token is signed with `JWT_SECRET`. With `RS256`, `ES256`
+// or `EdDSA` key pairs are generated and stored in Postgres, so every instance
+// signs with the same key. A new key takes over every `rotation`; retired keys
+// stay published in the JWKS until the tokens they signed have expired.
+pub struct KeyRing {
+    algorithm: Algorithm,
+    secret: String,
+    rotation: chrono::Duration,
+    retention: chrono::Duration,
+    keys: RwLock<Keys>,
+}
+
+impl KeyRing {
+    // Read `JWT_ALGORITHM` and `JWT_KEY_ROTATION_DAYS`. Retired keys are kept
+    // for the access token lifetime plus a margin for clock skew.
+    pub fn from_env(secret: String, access_token_ttl: chrono::Duration) -> Result<Self, String> {
+        let algorithm = match std::env::var("JWT_ALGORITHM").as_deref().unwrap_or("HS256") {
+            "HS256" => Algorithm::HS256,
+            "RS256" => Algorithm::RS256,
+            "ES256" => Algorithm::ES256,
+            "EdDSA" => Algorithm::EdDSA,
+            other => return Err(format!("Unsupported JWT_ALGORITHM `{}`", other)),
+        };
+
+        Ok(KeyRing {
+            algorithm,
+            secret,
+            rotation: chrono::Duration::days(crate::env_or("JWT_KEY_ROTATION_DAYS", 30)),
+            retention: access_token_ttl + chrono::Duration::minutes(5),
+            keys: RwLock::new(Keys::default()),
+        })
+    }
+
+    pub fn is_symmetric(&self) -> bool {
+        self.algorithm == Algorithm::HS256
+    }
+
+    // The `alg` every new token is signed with
+    pub fn algorithm_name(&self) -> &'static str {
+        algorithm_name(self.algorithm)
+    }
+
+    // Rotate if the signing key is due, then load every published key. Runs at
+    // startup and periodically afterwards.
+    pub async fn rotate(&self, db: &PgPool) -> Result<(), AppError> {
+        if self.is_symmetric() {
+            return Ok(());
+        }
+
+        let mut tx = db.begin().await.map_err(AppError::DatabaseError)?;
+
+        // Only one instance may decide on a rotation at a time
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(ROTATION_LOCK_ID)
+            .execute(&mut *tx)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+        let due = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT NOT EXISTS (
+                SELECT 1 FROM signing_keys
+                WHERE retired_at IS NULL AND algorithm = $1 AND created_at > NOW() - make_interval(secs => $2)
+            )
+            "#,
+        )
+        .bind(algorithm_name(self.algorithm))
+        .bind(self.rotation.num_seconds() as f64)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        if due {
+            let kid = Uuid::new_v4().to_string();
+            let (private_key, public_jwk) = generate_key(self.algorithm, &kid)?;
+
+            sqlx::query(
+                r#"
+                UPDATE signing_keys SET retired_at = NOW(), expires_at = NOW() + make_interval(secs => $1)
+                WHERE retired_at IS NULL
+                "#,
+            )
+            .bind(self.retention.num_seconds() as f64)
+            .execute(&mut *tx)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+            sqlx::query(
+                "INSERT INTO signing_keys (kid, algorithm, private_key, public_jwk) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(&kid)
+            .bind(algorithm_name(self.algorithm))
+            .bind(private_key)
+            .bind(sqlx::types::Json(public_jwk))
+            .execute(&mut *tx)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+            info!("Rotated access token signing key, new kid {}", kid);
+        }
+
+        sqlx::query("DELETE FROM signing_keys WHERE expires_at < NOW()")
+            .execute(&mut *tx)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+        tx.commit().await.map_err(AppError::DatabaseError)?;
+
+        self.load(db).await
+    }
+
+    async fn load(&self, db: &PgPool) -> Result<(), AppError> {
+        let stored = sqlx::query_as::<_, StoredKey>(
+            r#"
+            SELECT kid, algorithm, private_key, public_jwk, retired_at FROM signing_keys
+            WHERE expires_at IS NULL OR expires_at > NOW()
+            ORDER BY created_at DESC
+            "#,
+        )
+        .fetch_all(db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        let mut keys = Keys {
+            loaded: Some(Instant::now()),
+            ..Keys::default()
+        };
+        for key in stored {
+            if key.retired_at.is_none() && keys.signing.is_none() {
+                let encoding = encoding_key(&key.algorithm, &key.private_key);
+                keys.signing = Some((key.kid.clone(), encoding));
+            }
+            if let Some(decoding) = decoding_key(&key.public_jwk) {
+                keys.verifying.insert(key.kid, decoding);
+            }
+            keys.jwks.push(key.public_jwk.0);
+        }
+
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    // Sign access tokens, or any other token verifiable through the JWKS
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, AppError> {
+        if self.is_symmetric() {
+            return encode(
+                &Header::default(),
+                claims,
+                &EncodingKey::from_secret(self.secret.as_bytes()),
+            )
+            .map_err(|e| AppError::ValidationError(e.to_string()));
+        }
+
+        let keys = self.keys.read().unwrap();
+        let (kid, key) = keys.signing.as_ref().ok_or(AppError::ValidationError(
+            "No signing key loaded".to_string(),
+        ))?;
+
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(kid.clone());
+        encode(&header, claims, key).map_err(|e| AppError::ValidationError(e.to_string()))
+    }
+
+    fn lookup(&self, kid: &str) -> (Option<(Algorithm, DecodingKey)>, bool) {
+        let keys = self.keys.read().unwrap();
+        let may_reload = keys
+            .loaded
+            .is_none_or(|loaded| loaded.elapsed().as_secs() >= MIN_RELOAD_SECS);
+        (keys.verifying.get(kid).cloned(), may_reload)
+    }
+
+    // Check the signature and expiry of an access token
+    pub async fn verify(&self, db: &PgPool, token: &str) -> Result<Claims, AppError> {
+        self.verify_audience(db, token, None).await
+    }
+
+    // Like `verify`, for tokens limited to `audience`. Without one, tokens
+    // carrying an audience are rejected.
+    pub async fn verify_audience(
+        &self,
+        db: &PgPool,
+        token: &str,
+        audience: Option<&str>,
+    ) -> Result<Claims, AppError> {
+        let invalid = || AppError::AuthError("Invalid token".to_string());
+        let validation = |algorithm| {
+            let mut validation = Validation::new(algorithm);
+            if let Some(audience) = audience {
+                validation.set_audience(&[audience]);
+                validation.set_required_spec_claims(&["exp", "aud"]);
+            }
+            validation
+        };
+
+        if self.is_symmetric() {
+            return decode::<Claims>(
+                token,
+                &DecodingKey::from_secret(self.secret.as_bytes()),
+                &validation(Algorithm::HS256),
+            )
+            .map(|data| data.claims)
+            .map_err(|_| invalid());
+        }
+
+        let kid = decode_header(token)
+            .ok()
+            .and_then(|header| header.kid)
+            .ok_or_else(invalid)?;
+
+        // Another instance may have rotated since we last loaded
+        let (mut key, may_reload) = self.lookup(&kid);
+        if key.is_none() && may_reload {
+            self.load(db).await?;
+            key = self.lookup(&kid).0;
+        }
+        let (algorithm, key) = key.ok_or_else(invalid)?;
+
+        decode::<Claims>(token, &key, &validation(algorithm))
+            .map(|data| data.claims)
+            .map_err(|_| invalid())
+    }
+
+    // Public keys of the signing key and of retired keys still in use
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.read().unwrap().jwks.clone(),
+        }
+    }
+}
+
+fn algorithm_name(algorithm: Algorithm) -> &'static str {
+    match algorithm {
+        Algorithm::RS256 => "RS256",
+        Algorithm::ES256 => "ES256",
+        Algorithm::EdDSA => "EdDSA",
+        _ => "HS256",
+    }
+}
+
+fn encoding_key(algorithm: &str, der: &[u8]) -> EncodingKey {
+    match algorithm {
+        "RS256" => EncodingKey::from_rsa_der(der),
+        "ES256" => EncodingKey::from_ec_der(der),
+        _ => EncodingKey::from_ed_der(der),
+    }
+}
+
+// New key pair: the DER private key and the public half as a JWK
+fn generate_key(algorithm: Algorithm, kid: &str) -> Result<(Vec<u8>, Jwk), AppError> {
+    let failed = |e: String| AppError::ValidationError(format!("Key generation failed: {}", e));
+    let rng = SystemRandom::new();
+
+    let (private_key, params) = match algorithm {
+        Algorithm::RS256 => {
+            let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 2048)
+                .map_err(|e| failed(e.to_string()))?;
+            let der = key.to_pkcs1_der().map_err(|e| failed(e.to_string()))?;
+            let params = serde_json::json!({
+                "kty": "RSA",
+                "n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            });
+            (der.as_bytes().to_vec(), params)
+        }
+        Algorithm::ES256 => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|e| failed(e.to_string()))?;
+            let pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                    .map_err(|e| failed(e.to_string()))?;
+            // Uncompressed point: 0x04 || x || y
+            let point = pair.public_key().as_ref();
+            let params = serde_json::json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+            });
+            (pkcs8.as_ref().to_vec(), params)
+        }
+        Algorithm::EdDSA => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|e| failed(e.to_string()))?;
+            let pair =
+                Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|e| failed(e.to_string()))?;
+            let params = serde_json::json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            });
+            (pkcs8.as_ref().to_vec(), params)
+        }
+        _ => return Err(failed("symmetric algorithms have no key pair".to_string())),
+    };
+
+    let mut jwk = params;
+    jwk["kid"] = kid.into();
+    jwk["alg"] = algorithm_name(algorithm).into();
+    jwk["use"] = "sig".into();
+    let jwk = serde_json::from_value(jwk).map_err(|e| failed(e.to_string()))?;
+
+    Ok((private_key, jwk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> Claims {
+        Claims {
+            sub: 7,
+            exp: (chrono::Utc::now().timestamp() + 60) as usize,
+            iat: chrono::Utc::now().timestamp() as usize,
+            jti: "test".to_string(),
+            sid: None,
+            act: None,
+            org_id: None,
+            roles: Vec::new(),
+            permissions: Vec::new(),
+            client_id: None,
+            scope: None,
+            aud: None,
+        }
+    }
+
+    // Client tokens only verify for their audience, and nothing else does
+    #[tokio::test]
+    async fn audience_tokens_need_their_audience() {
+        let keys = KeyRing {
+            algorithm: Algorithm::HS256,
+            secret: "secret".to_string(),
+            rotation: chrono::Duration::days(30),
+            retention: chrono::Duration::minutes(20),
+            keys: RwLock::new(Keys::default()),
+        };
+        let db = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+
+        let user_token = keys.sign(&claims()).unwrap();
+        let mut client_claims = claims();
+        client_claims.aud = Some("oauth-client".to_string());
+        let client_token = keys.sign(&client_claims).unwrap();
+
+        assert!(keys.verify(&db, &user_token).await.is_ok());
+        assert!(keys.verify(&db, &client_token).await.is_err());
+        assert!(keys
+            .verify_audience(&db, &client_token, Some("oauth-client"))
+            .await
+            .is_ok());
+        assert!(keys
+            .verify_audience(&db, &user_token, Some("oauth-client"))
+            .await
+            .is_err());
+    }
+
+    // Sign with the generated private key and verify through the published JWK
+    #[test]
+    fn generated_keys_round_trip() {
+        for algorithm in [Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA] {
+            let (private_key, jwk) = generate_key(algorithm, "kid-1").unwrap();
+            let encoding = encoding_key(algorithm_name(algorithm), &private_key);
+            let token = encode(&Header::new(algorithm), &claims(), &encoding).unwrap();
+
+            let (published, key) = decoding_key(&jwk).unwrap();
+            assert_eq!(published, algorithm);
+            let decoded = decode::<Claims>(&token, &key, &Validation::new(algorithm)).unwrap();
+            assert_eq!(decoded.claims.sub, 7);
+        }
+    }
+}
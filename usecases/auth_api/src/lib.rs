@@ -0,0 +1,6 @@
+// Shared pieces of the authentication API that other services can depend on.
+//
+// Add `auth_api = { path = "../auth_api" }` to a service's Cargo.toml and use
+// the extractors and middleware in `authz` to protect its routes with the
+// access tokens issued by this API.
+pub mod authz;
@@ -0,0 +1,611 @@
+use auth_api::authz::RequirePermission;
+use axum::{
+    async_trait,
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::audit::{self, AuditEvent};
+use crate::mailer::Email;
+use crate::sessions::ClientInfo;
+use crate::users::UsersWrite;
+use crate::{AppError, AppState, User};
+
+// Audience of unlock tokens, so they can't be used as access tokens
+const AUDIENCE: &str = "account-unlock";
+
+// Longest wait between two attempts on the same account
+const MAX_DELAY_SECS: i64 = 30;
+
+// The in-memory store drops expired counters once it holds this many
+const MEMORY_STORE_PRUNE_AT: usize = 10_000;
+
+// Failed logins counted for an account or an address
+#[derive(Debug, Clone, Copy)]
+pub struct Attempts {
+    pub failures: i32,
+    pub last_failure: chrono::DateTime<chrono::Utc>,
+}
+
+#[async_trait]
+pub trait AttemptStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>, AppError>;
+
+    // Count a failure. Counters whose last failure is older than `window`
+    // start over.
+    async fn record_failure(
+        &self,
+        key: &str,
+        window: chrono::Duration,
+    ) -> Result<Attempts, AppError>;
+
+    // Take back one counted failure, e.g. of an attempt that succeeded
+    async fn release(&self, key: &str) -> Result<(), AppError>;
+
+    async fn clear(&self, key: &str) -> Result<(), AppError>;
+}
+
+// Counters shared by every instance
+pub struct PgAttemptStore {
+    db: PgPool,
+}
+
+impl PgAttemptStore {
+    pub fn new(db: PgPool) -> Self {
+        PgAttemptStore { db }
+    }
+}
+
+#[async_trait]
+impl AttemptStore for PgAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>, AppError> {
+        let row = sqlx::query_as::<_, (i32, chrono::DateTime<chrono::Utc>)>(
+            "SELECT failures, last_failure FROM login_attempts WHERE key = $1",
+        )
+        .bind(key)
+        .fetch_optional(&self.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        Ok(row.map(|(failures, last_failure)| Attempts {
+            failures,
+            last_failure,
+        }))
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        window: chrono::Duration,
+    ) -> Result<Attempts, AppError> {
+        let window_secs = window.num_seconds() as f64;
+
+        let (failures, last_failure) = sqlx::query_as::<_, (i32, chrono::DateTime<chrono::Utc>)>(
+            r#"
+            INSERT INTO login_attempts (key, failures, last_failure)
+            VALUES ($1, 1, NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                failures = CASE
+                    WHEN login_attempts.last_failure < NOW() - make_interval(secs => $2) THEN 1
+                    ELSE login_attempts.failures + 1
+                END,
+                last_failure = NOW()
+            RETURNING failures, last_failure
+            "#,
+        )
+        .bind(key)
+        .bind(window_secs)
+        .fetch_one(&self.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        // Counters nobody touched for a whole window are meaningless
+        sqlx::query(
+            "DELETE FROM login_attempts WHERE last_failure < NOW() - make_interval(secs => $1)",
+        )
+        .bind(window_secs)
+        .execute(&self.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        Ok(Attempts {
+            failures,
+            last_failure,
+        })
+    }
+
+    async fn release(&self, key: &str) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE login_attempts SET failures = failures - 1 WHERE key = $1 AND failures > 0",
+        )
+        .bind(key)
+        .execute(&self.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM login_attempts WHERE key = $1")
+            .bind(key)
+            .execute(&self.db)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+        Ok(())
+    }
+}
+
+// Counters local to this instance, for single-instance setups and tests
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+#[async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>, AppError> {
+        Ok(self.attempts.lock().unwrap().get(key).copied())
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        window: chrono::Duration,
+    ) -> Result<Attempts, AppError> {
+        let now = chrono::Utc::now();
+        let mut attempts = self.attempts.lock().unwrap();
+
+        if attempts.len() >= MEMORY_STORE_PRUNE_AT {
+            attempts.retain(|_, a| now - a.last_failure < window);
+        }
+
+        let entry = attempts.entry(key.to_string()).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+        });
+        if now - entry.last_failure >= window {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+
+        Ok(*entry)
+    }
+
+    async fn release(&self, key: &str) -> Result<(), AppError> {
+        if let Some(attempts) = self.attempts.lock().unwrap().get_mut(key) {
+            attempts.failures = (attempts.failures - 1).max(0);
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AppError> {
+        self.attempts.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+// Wait imposed after `failures` failed attempts: 1s, 2s, 4s... up to 30s
+fn delay(failures: i32) -> chrono::Duration {
+    if failures <= 0 {
+        return chrono::Duration::zero();
+    }
+    let secs = 1i64 << (failures - 1).min(5);
+    chrono::Duration::seconds(secs.min(MAX_DELAY_SECS))
+}
+
+fn account_key(username: &str) -> String {
+    format!("account:{}", username)
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+fn challenge_key(jti: &str) -> String {
+    format!("mfa:{}", jti)
+}
+
+// A password or code attempt, counted as a failure until it succeeds
+pub struct Attempt {
+    username: String,
+    ip: IpAddr,
+    account_failures: i32,
+}
+
+// Slows down and eventually stops password guessing, per account and per
+// client address
+pub struct LoginThrottle {
+    store: Box<dyn AttemptStore>,
+    max_failures: i32,
+    max_ip_failures: i32,
+    lockout: chrono::Duration,
+}
+
+impl LoginThrottle {
+    pub fn new(
+        store: Box<dyn AttemptStore>,
+        max_failures: i32,
+        max_ip_failures: i32,
+        lockout: chrono::Duration,
+    ) -> Self {
+        LoginThrottle {
+            store,
+            max_failures,
+            max_ip_failures,
+            lockout,
+        }
+    }
+
+    // Read `LOGIN_ATTEMPT_STORE` (postgres or memory), `LOGIN_MAX_FAILURES`,
+    // `LOGIN_MAX_FAILURES_PER_IP` and `LOCKOUT_MINUTES`
+    pub fn from_env(db: PgPool) -> Result<Self, String> {
+        let store: Box<dyn AttemptStore> = match std::env::var("LOGIN_ATTEMPT_STORE")
+            .as_deref()
+            .unwrap_or("postgres")
+        {
+            "postgres" => Box::new(PgAttemptStore::new(db)),
+            "memory" => Box::new(MemoryAttemptStore::default()),
+            other => return Err(format!("Unknown LOGIN_ATTEMPT_STORE `{}`", other)),
+        };
+
+        Ok(LoginThrottle::new(
+            store,
+            crate::env_or("LOGIN_MAX_FAILURES", 5) as i32,
+            crate::env_or("LOGIN_MAX_FAILURES_PER_IP", 50) as i32,
+            chrono::Duration::minutes(crate::env_or("LOCKOUT_MINUTES", 15)),
+        ))
+    }
+
+    // Start an attempt on the account: refuse it while the account or address
+    // is locked or still has to wait, otherwise count it as a failure before
+    // the password or code is even checked. Parallel requests can then never
+    // get more guesses than the limit, since each sees the others' counts.
+    // The attempt is settled with `record_failure`, `record_success` or
+    // `release`.
+    pub async fn begin(&self, username: &str, ip: IpAddr) -> Result<Attempt, AppError> {
+        self.check(username, ip).await?;
+        self.count(username, ip).await
+    }
+
+    async fn count(&self, username: &str, ip: IpAddr) -> Result<Attempt, AppError> {
+        let address = self.store.record_failure(&ip_key(ip), self.lockout).await?;
+        let account = self
+            .store
+            .record_failure(&account_key(username), self.lockout)
+            .await?;
+        let attempt = Attempt {
+            username: username.to_string(),
+            ip,
+            account_failures: account.failures,
+        };
+
+        // Lost the race against attempts made in parallel
+        if account.failures > self.max_failures || address.failures > self.max_ip_failures {
+            self.release(attempt).await?;
+            return Err(AppError::RateLimitError(
+                "Too many failed logins, try again later".to_string(),
+            ));
+        }
+
+        Ok(attempt)
+    }
+
+    // Refuse the attempt while the account or address is locked, or while the
+    // account still has to wait after its last failure
+    async fn check(&self, username: &str, ip: IpAddr) -> Result<(), AppError> {
+        let now = chrono::Utc::now();
+
+        if let Some(attempts) = self.store.get(&ip_key(ip)).await? {
+            if attempts.failures >= self.max_ip_failures
+                && now - attempts.last_failure < self.lockout
+            {
+                return Err(AppError::RateLimitError(
+                    "Too many failed logins from this address, try again later".to_string(),
+                ));
+            }
+        }
+
+        if let Some(attempts) = self.store.get(&account_key(username)).await? {
+            let elapsed = now - attempts.last_failure;
+            if elapsed >= self.lockout {
+                return Ok(());
+            }
+            if attempts.failures >= self.max_failures {
+                return Err(AppError::RateLimitError(
+                    "Account temporarily locked, try again later or use the unlock link sent by email"
+                        .to_string(),
+                ));
+            }
+            let wait = delay(attempts.failures) - elapsed;
+            if wait > chrono::Duration::zero() {
+                return Err(AppError::RateLimitError(format!(
+                    "Too many failed logins, retry in {} seconds",
+                    wait.num_seconds() + 1
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The attempt failed and stays counted. Returns true when it locked the
+    // account.
+    pub fn record_failure(&self, attempt: Attempt) -> bool {
+        attempt.account_failures == self.max_failures
+    }
+
+    // Count a wrong code entered for one MFA challenge. Returns how many codes
+    // the challenge has seen fail.
+    pub async fn record_challenge_failure(&self, jti: &str) -> Result<i32, AppError> {
+        let attempts = self
+            .store
+            .record_failure(&challenge_key(jti), self.lockout)
+            .await?;
+
+        Ok(attempts.failures)
+    }
+
+    // A successful login clears the account's counter. The address only takes
+    // the attempt back, so one valid account doesn't reset guessing on others.
+    pub async fn record_success(&self, attempt: Attempt) -> Result<(), AppError> {
+        self.store.release(&ip_key(attempt.ip)).await?;
+        self.store.clear(&account_key(&attempt.username)).await
+    }
+
+    // Forget an attempt that turned out not to be a guess, e.g. a correct
+    // password still waiting for its MFA code
+    pub async fn release(&self, attempt: Attempt) -> Result<(), AppError> {
+        self.store.release(&ip_key(attempt.ip)).await?;
+        self.store.release(&account_key(&attempt.username)).await
+    }
+
+    pub async fn unlock(&self, username: &str) -> Result<(), AppError> {
+        self.store.clear(&account_key(username)).await
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UnlockClaims {
+    sub: i32,
+    username: String,
+    aud: String,
+    exp: usize,
+    // Revoked once used, so each link unlocks once
+    jti: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockRequest {
+    token: String,
+}
+
+// Mail an unlock link to the owner of a locked account. The link is only
+// valid as long as the lockout itself.
+pub async fn send_unlock_email(state: &AppState, user: &User) {
+    let claims = UnlockClaims {
+        sub: user.id,
+        username: user.username.clone(),
+        aud: AUDIENCE.to_string(),
+        exp: (chrono::Utc::now() + state.login_throttle.lockout).timestamp() as usize,
+        jti: uuid::Uuid::new_v4().to_string(),
+    };
+    let token = match encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    ) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("Failed to create unlock token for user {}: {}", user.id, e);
+            return;
+        }
+    };
+
+    let link = format!("{}/unlock?token={}", state.app_url, token);
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Your account has been locked".to_string(),
+        body: format!(
+            "Hi {},\n\nThere were too many failed attempts to log in to your account, so it is locked for {} minutes.\n\nIf this was you, unlock it now by opening this link:\n\n{}\n\nIf it wasn't, consider changing your password.\n",
+            user.username,
+            state.login_throttle.lockout.num_minutes(),
+            link
+        ),
+    };
+
+    if let Err(e) = state.mailer.send(email).await {
+        warn!("Failed to send unlock email to user {}: {}", user.id, e);
+    }
+}
+
+// Handler functions
+pub async fn unlock_account(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<UnlockRequest>,
+) -> Result<StatusCode, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[AUDIENCE]);
+
+    let claims = decode::<UnlockClaims>(
+        &payload.token,
+        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map_err(|_| AppError::AuthError("Invalid unlock token".to_string()))?
+    .claims;
+
+    let first_use = state
+        .revocations
+        .revoke_jti(&state.db, &claims.jti, claims.sub, claims.exp as i64)
+        .await?;
+    if !first_use {
+        return Err(AppError::AuthError("Invalid unlock token".to_string()));
+    }
+
+    state.login_throttle.unlock(&claims.username).await?;
+    info!("User {} unlocked their account", claims.sub);
+    audit::record(
+        &state,
+        AuditEvent::success("account_unlocked")
+            .user(claims.sub)
+            .client(&client),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn admin_unlock(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<UsersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .ok_or(AppError::ValidationError("Unknown user".to_string()))?;
+
+    state.login_throttle.unlock(&username).await?;
+    info!("User {} unlocked by admin {}", user_id, claims.sub);
+    audit::record(
+        &state,
+        AuditEvent::success("account_unlocked")
+            .by(&claims)
+            .subject(user_id)
+            .client(&client),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(
+            Box::new(MemoryAttemptStore::default()),
+            3,
+            10,
+            chrono::Duration::minutes(15),
+        )
+    }
+
+    #[test]
+    fn delays_grow_and_cap() {
+        assert_eq!(delay(0).num_seconds(), 0);
+        assert_eq!(delay(1).num_seconds(), 1);
+        assert_eq!(delay(3).num_seconds(), 4);
+        assert_eq!(delay(40).num_seconds(), MAX_DELAY_SECS);
+    }
+
+    #[tokio::test]
+    async fn locks_after_max_failures() {
+        let throttle = throttle();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let attempt = throttle.begin("alice", ip).await.unwrap();
+        assert!(!throttle.record_failure(attempt));
+        // The next attempt has to wait
+        assert!(throttle.begin("alice", ip).await.is_err());
+
+        // Past the waits, the third failure locks the account
+        let attempt = throttle.count("alice", ip).await.unwrap();
+        assert!(!throttle.record_failure(attempt));
+        let attempt = throttle.count("alice", ip).await.unwrap();
+        assert!(throttle.record_failure(attempt));
+        assert!(throttle.check("alice", ip).await.is_err());
+        // Other accounts are unaffected
+        assert!(throttle.begin("bob", ip).await.is_ok());
+
+        throttle.unlock("alice").await.unwrap();
+        assert!(throttle.begin("alice", ip).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn parallel_attempts_get_no_more_than_the_limit() {
+        let throttle = throttle();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        // Every request passes the check before any of them is counted
+        for _ in 0..10 {
+            assert!(throttle.check("alice", ip).await.is_ok());
+        }
+        let mut started = Vec::new();
+        for _ in 0..10 {
+            if let Ok(attempt) = throttle.count("alice", ip).await {
+                started.push(attempt);
+            }
+        }
+        assert_eq!(started.len(), 3);
+
+        let locked: Vec<_> = started
+            .into_iter()
+            .map(|attempt| throttle.record_failure(attempt))
+            .collect();
+        assert_eq!(locked, [false, false, true]);
+        let alice = throttle.store.get(&account_key("alice")).await.unwrap();
+        assert_eq!(alice.unwrap().failures, 3);
+    }
+
+    #[tokio::test]
+    async fn successes_are_taken_back() {
+        let throttle = throttle();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        for _ in 0..20 {
+            let attempt = throttle.begin("alice", ip).await.unwrap();
+            throttle.record_success(attempt).await.unwrap();
+        }
+        let attempt = throttle.begin("bob", ip).await.unwrap();
+        throttle.release(attempt).await.unwrap();
+
+        let address = throttle.store.get(&ip_key(ip)).await.unwrap().unwrap();
+        assert_eq!(address.failures, 0);
+        assert!(throttle
+            .store
+            .get(&account_key("alice"))
+            .await
+            .unwrap()
+            .is_none());
+        let bob = throttle
+            .store
+            .get(&account_key("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn locks_addresses_separately() {
+        let throttle = throttle();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        for i in 0..10 {
+            let attempt = throttle.begin(&format!("user{}", i), ip).await.unwrap();
+            throttle.record_failure(attempt);
+        }
+        assert!(throttle.begin("someone", ip).await.is_err());
+        assert!(throttle
+            .check("someone", "10.0.0.2".parse().unwrap())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,147 @@
+use std::net::IpAddr;
+
+use crate::audit::{self, AuditEvent};
+use crate::sessions::ClientInfo;
+use crate::{account, hashing, lockout, mfa, users, AppError, AppState, User};
+
+// Outcome of a correct password
+pub enum PasswordLogin {
+    // Every check passed and tokens can be issued
+    Complete(User),
+    // MFA is on and no code came with the password
+    MfaRequired(User),
+}
+
+// Why a password login failed: a reason for the audit log, the user when
+// known, and the error for the client
+pub struct LoginFailure {
+    pub reason: &'static str,
+    pub user_id: Option<i32>,
+    pub error: AppError,
+}
+
+impl LoginFailure {
+    fn new(reason: &'static str, user: Option<&User>, error: AppError) -> Self {
+        LoginFailure {
+            reason,
+            user_id: user.map(|user| user.id),
+            error,
+        }
+    }
+}
+
+// The steps every password login goes through, whether it comes from
+// `/auth/login` or the OAuth consent page. `otp` is checked right away when
+// given; without it an account with MFA only earns `MfaRequired`, and its
+// failure counter stays until the second step passes.
+pub async fn check_password(
+    state: &AppState,
+    username: &str,
+    password: &str,
+    otp: Option<&str>,
+    ip: IpAddr,
+    client: &ClientInfo,
+) -> Result<PasswordLogin, LoginFailure> {
+    let throttle = &state.login_throttle;
+    let internal = |e: AppError| LoginFailure::new("internal_error", None, e);
+
+    let attempt = throttle
+        .begin(username, ip)
+        .await
+        .map_err(|e| LoginFailure::new("throttled", None, e))?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1")
+        .bind(username)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| internal(AppError::DatabaseError(e)))?;
+
+    let user = match user {
+        Some(user)
+            if state
+                .password_hasher
+                .verify(password, &user.password_hash)
+                .map_err(internal)? =>
+        {
+            user
+        }
+        user => {
+            // Unknown usernames count too, so lockouts don't reveal which exist
+            record_failure(state, attempt, user.as_ref(), client).await;
+            return Err(LoginFailure::new(
+                "invalid_credentials",
+                user.as_ref(),
+                AppError::AuthError("Invalid credentials".to_string()),
+            ));
+        }
+    };
+    hashing::rehash_if_needed(state, &user, password).await;
+
+    // The password was right; refusing the login below isn't a failed guess
+    if let Err(e) = users::check_active(&user) {
+        throttle.release(attempt).await.map_err(internal)?;
+        let reason = if user.disabled_at.is_some() {
+            "account_disabled"
+        } else {
+            "password_reset_required"
+        };
+        return Err(LoginFailure::new(reason, Some(&user), e));
+    }
+
+    if let Err(e) = state.unverified_policy.check(&user) {
+        throttle.release(attempt).await.map_err(internal)?;
+        return Err(LoginFailure::new("email_unverified", Some(&user), e));
+    }
+
+    if mfa::is_enabled(state, user.id).await.map_err(internal)? {
+        let Some(otp) = otp.filter(|otp| !otp.trim().is_empty()) else {
+            throttle.release(attempt).await.map_err(internal)?;
+            return Ok(PasswordLogin::MfaRequired(user));
+        };
+        if mfa::consume_code(state, user.id, otp).await.is_err() {
+            record_failure(state, attempt, Some(&user), client).await;
+            return Err(LoginFailure::new(
+                "invalid_otp",
+                Some(&user),
+                AppError::AuthError("Invalid authenticator code".to_string()),
+            ));
+        }
+    }
+
+    throttle.record_success(attempt).await.map_err(internal)?;
+
+    // Logging in again during the grace period keeps the account
+    let mut user = user;
+    if user.deletion_scheduled_at.is_some() {
+        account::cancel_deletion(state, &user, client)
+            .await
+            .map_err(internal)?;
+        user.deletion_scheduled_at = None;
+    }
+
+    Ok(PasswordLogin::Complete(user))
+}
+
+// Settle an attempt with a wrong password or code, and tell the owner when it
+// locked the account. Returns whether it did.
+pub async fn record_failure(
+    state: &AppState,
+    attempt: lockout::Attempt,
+    user: Option<&User>,
+    client: &ClientInfo,
+) -> bool {
+    let locked = state.login_throttle.record_failure(attempt);
+
+    if let (true, Some(user)) = (locked, user) {
+        audit::record(
+            state,
+            AuditEvent::success("account_locked")
+                .subject(user.id)
+                .client(client),
+        )
+        .await;
+        lockout::send_unlock_email(state, user).await;
+    }
+
+    locked
+}
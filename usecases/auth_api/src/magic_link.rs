@@ -0,0 +1,273 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::account::require_own_login;
+use crate::audit::{self, AuditEvent};
+use crate::cookies::{self, SessionMode};
+use crate::mailer::Email;
+use crate::sessions::ClientInfo;
+use crate::tokens::{self, generate_opaque_token, hash_token};
+use crate::{mfa, users, AppError, AppState, AuthUser, User};
+
+// Minimum time between two login links for the same account
+const RESEND_COOLDOWN_SECS: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRequest {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkCallback {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkSettings {
+    enabled: bool,
+}
+
+// Create a login token for the account behind `email`, if it accepts magic
+// links, and mail it
+async fn send_login_link(
+    state: &AppState,
+    email: &str,
+    client: &ClientInfo,
+) -> Result<(), AppError> {
+    let user =
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1 AND magic_link_enabled")
+            .bind(email)
+            .fetch_optional(&state.db)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+    let Some(user) = user else {
+        return Ok(());
+    };
+    if users::check_active(&user).is_err() {
+        return Ok(());
+    }
+
+    let recent = sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM magic_link_tokens
+            WHERE user_id = $1 AND created_at > NOW() - make_interval(secs => $2)
+        )
+        "#,
+    )
+    .bind(user.id)
+    .bind(RESEND_COOLDOWN_SECS as f64)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    if recent {
+        return Ok(());
+    }
+
+    let token = generate_opaque_token();
+    sqlx::query(
+        "INSERT INTO magic_link_tokens (user_id, token_hash, email, expires_at) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(user.id)
+    .bind(hash_token(&token))
+    .bind(&user.email)
+    .bind(chrono::Utc::now() + state.magic_link_ttl)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    audit::record(
+        state,
+        AuditEvent::success("magic_link_requested")
+            .subject(user.id)
+            .client(client),
+    )
+    .await;
+
+    let link = format!("{}/auth/magic-link/callback?token={}", state.app_url, token);
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Your login link".to_string(),
+        body: format!(
+            "Hi {},\n\nLog in by opening this link:\n\n{}\n\nThe link works once and expires in {} minutes. If you didn't ask for it, ignore this email.\n",
+            user.username,
+            link,
+            state.magic_link_ttl.num_minutes()
+        ),
+    };
+
+    state
+        .mailer
+        .send(email)
+        .await
+        .map_err(|e| AppError::ValidationError(e.to_string()))
+}
+
+fn magic_link_failure(client: &ClientInfo, reason: &str) -> AuditEvent {
+    AuditEvent::failure("login")
+        .client(client)
+        .details(serde_json::json!({ "method": "magic_link", "reason": reason }))
+}
+
+// Handler functions
+
+// Like forgot_password, the answer is always 202 so it doesn't tell which
+// addresses have an account or which accounts accept magic links
+pub async fn request_magic_link(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<MagicLinkRequest>,
+) -> StatusCode {
+    tokio::spawn(async move {
+        if let Err(e) = send_login_link(&state, &payload.email, &client).await {
+            warn!("Failed to send magic link email: {:?}", e);
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+pub async fn magic_link_callback(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    mode: SessionMode,
+    Query(query): Query<MagicLinkCallback>,
+) -> Result<Response, AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    // The link is void once the account changes its address or turns the
+    // method off
+    let user_id = sqlx::query_scalar::<_, i32>(
+        r#"
+        UPDATE magic_link_tokens t SET used_at = NOW()
+        FROM users u
+        WHERE t.token_hash = $1 AND t.used_at IS NULL AND t.expires_at > NOW()
+            AND u.id = t.user_id AND u.email = t.email AND u.magic_link_enabled
+        RETURNING t.user_id
+        "#,
+    )
+    .bind(hash_token(&query.token))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    let Some(user_id) = user_id else {
+        audit::record(&state, magic_link_failure(&client, "invalid_token")).await;
+        return Err(AppError::AuthError(
+            "Invalid or expired login link".to_string(),
+        ));
+    };
+
+    // Opening the link proves the address belongs to the user
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW())
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    if let Err(e) = users::check_active(&user) {
+        let reason = if user.disabled_at.is_some() {
+            "account_disabled"
+        } else {
+            "password_reset_required"
+        };
+        audit::record(&state, magic_link_failure(&client, reason).subject(user.id)).await;
+        return Err(e);
+    }
+
+    // The link replaces the password only, a second factor is still asked for
+    if mfa::is_enabled(&state, user.id).await? {
+        let event = AuditEvent::success("login")
+            .user(user.id)
+            .client(&client)
+            .details(serde_json::json!({ "method": "magic_link", "mfa_required": true }));
+        audit::record(&state, event).await;
+        return Ok(Json(mfa::challenge(&state, user.id)?).into_response());
+    }
+
+    let event = AuditEvent::success("login")
+        .user(user.id)
+        .client(&client)
+        .details(serde_json::json!({ "method": "magic_link" }));
+    audit::record(&state, event).await;
+
+    let response = tokens::issue_token_pair(&state, user, &client, false).await?;
+
+    Ok(cookies::respond(&state, mode, response))
+}
+
+pub async fn update_magic_link_settings(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<MagicLinkSettings>,
+) -> Result<StatusCode, AppError> {
+    require_own_login(&claims)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    // Links are only sent to an address known to reach the user
+    let verified = sqlx::query_scalar::<_, bool>(
+        "SELECT email_verified_at IS NOT NULL FROM users WHERE id = $1",
+    )
+    .bind(claims.sub)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    if payload.enabled && !verified {
+        return Err(AppError::ForbiddenError(
+            "Verify your email address before enabling magic links".to_string(),
+        ));
+    }
+
+    sqlx::query("UPDATE users SET magic_link_enabled = $1 WHERE id = $2")
+        .bind(payload.enabled)
+        .bind(claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    // Links already sent stop working when the method is turned off
+    if !payload.enabled {
+        sqlx::query(
+            "UPDATE magic_link_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+    }
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    let event_type = if payload.enabled {
+        "magic_link_enabled"
+    } else {
+        "magic_link_disabled"
+    };
+    audit::record(
+        &state,
+        AuditEvent::success(event_type)
+            .by(&claims)
+            .subject(claims.sub)
+            .client(&client),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,31 +1,82 @@
+use auth_api::authz::{Authenticator, Claims};
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    extract::{ConnectInfo, Path, State},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
 };
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
-// JWT Claims
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: i32, // user id
-    exp: usize, // expiration time
-}
+mod account;
+mod api_keys;
+mod audit;
+mod auth;
+mod cookies;
+mod hashing;
+mod keys;
+mod lockout;
+mod login;
+mod magic_link;
+mod mailer;
+mod mfa;
+mod oauth_clients;
+mod oidc;
+mod orgs;
+mod password_policy;
+mod passwords;
+mod revocation;
+mod roles;
+mod sessions;
+mod teams;
+mod tokens;
+mod users;
+mod verification;
+mod views;
+
+use audit::AuditEvent;
+use auth::{AuthUser, KeyOrAuthUser};
+use cookies::SessionMode;
+use hashing::PasswordHashing;
+use keys::KeyRing;
+use lockout::LoginThrottle;
+use login::PasswordLogin;
+use password_policy::PasswordPolicy;
+use revocation::RevocationList;
+use sessions::ClientInfo;
+use verification::UnverifiedPolicy;
+use views::PublicUser;
 
-// User model
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+// User model, as stored. Responses use the types in `views`.
+#[derive(sqlx::FromRow)]
 struct User {
     id: i32,
     username: String,
     email: String,
     password_hash: String,
+    email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    disabled_at: Option<chrono::DateTime<chrono::Utc>>,
+    password_reset_required: bool,
+    deletion_scheduled_at: Option<chrono::DateTime<chrono::Utc>>,
+    magic_link_enabled: bool,
+}
+
+// Written by hand so the password hash stays out of logs
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("email", &self.email)
+            .finish_non_exhaustive()
+    }
 }
 
 // Request/Response types
@@ -40,18 +91,48 @@ struct RegisterRequest {
     username: String,
     email: String,
     password: String,
+    // Joins the inviting organization; the invitation must be for `email`
+    invitation_token: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 struct AuthResponse {
     token: String,
-    user: User,
+    refresh_token: String,
+    expires_in: i64, // access token lifetime in seconds
+    user: PublicUser,
 }
 
 // Application state
 struct AppState {
     db: PgPool,
+    // Signs the internal verification and MFA challenge tokens
     jwt_secret: String,
+    // Signs and verifies access tokens
+    keys: KeyRing,
+    access_token_ttl: chrono::Duration,
+    refresh_token_ttl: chrono::Duration,
+    revocations: RevocationList,
+    mailer: Box<dyn mailer::Mailer>,
+    // Base URL used in links sent by email
+    app_url: String,
+    email_verification_ttl: chrono::Duration,
+    password_reset_ttl: chrono::Duration,
+    magic_link_ttl: chrono::Duration,
+    invitation_ttl: chrono::Duration,
+    unverified_policy: UnverifiedPolicy,
+    // Shown next to the account in authenticator apps
+    mfa_issuer: String,
+    // Issuer of OpenID Connect ID tokens
+    oidc_issuer: String,
+    login_throttle: LoginThrottle,
+    password_hasher: PasswordHashing,
+    password_policy: PasswordPolicy,
+    // How long a deleted account can still be restored by logging in
+    account_deletion_grace: chrono::Duration,
+    purge_hooks: Vec<Box<dyn account::PurgeHook>>,
+    // Set when browsers may keep their tokens in cookies
+    session_cookies: Option<cookies::SessionCookies>,
 }
 
 // Custom error type
@@ -60,6 +141,9 @@ enum AppError {
     DatabaseError(sqlx::Error),
     AuthError(String),
     ValidationError(String),
+    ForbiddenError(String),
+    RateLimitError(String),
+    InternalError(String),
 }
 
 impl IntoResponse for AppError {
@@ -68,6 +152,9 @@ impl IntoResponse for AppError {
             AppError::DatabaseError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
             AppError::AuthError(msg) => (StatusCode::UNAUTHORIZED, msg),
             AppError::ValidationError(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::ForbiddenError(msg) => (StatusCode::FORBIDDEN, msg),
+            AppError::RateLimitError(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            AppError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
         };
 
         (status, Json(serde_json::json!({ "error": message }))).into_response()
@@ -86,44 +173,257 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let access_token_ttl = chrono::Duration::minutes(env_or("ACCESS_TOKEN_TTL_MINUTES", 15));
+    let refresh_token_ttl = chrono::Duration::days(env_or("REFRESH_TOKEN_TTL_DAYS", 30));
+    let revocation_cache_ttl =
+        std::time::Duration::from_secs(env_or("REVOCATION_CACHE_TTL_SECS", 30) as u64);
+    let app_url = std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let email_verification_ttl =
+        chrono::Duration::hours(env_or("EMAIL_VERIFICATION_TTL_HOURS", 24));
+    let password_reset_ttl = chrono::Duration::minutes(env_or("PASSWORD_RESET_TTL_MINUTES", 60));
+    let magic_link_ttl = chrono::Duration::minutes(env_or("MAGIC_LINK_TTL_MINUTES", 15));
+    let invitation_ttl = chrono::Duration::days(env_or("INVITATION_TTL_DAYS", 7));
+    let mfa_issuer = std::env::var("MFA_ISSUER").unwrap_or_else(|_| "auth_api".to_string());
+    let oidc_issuer =
+        std::env::var("OIDC_ISSUER").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let account_deletion_grace = chrono::Duration::days(env_or("ACCOUNT_DELETION_GRACE_DAYS", 30));
+    let keys = KeyRing::from_env(jwt_secret.clone(), access_token_ttl)?;
+    let password_hasher = PasswordHashing::from_env()?;
+    let password_policy = PasswordPolicy::from_env()?;
+    let session_cookies = cookies::SessionCookies::from_env()?;
+    let mailer = mailer::from_env().map_err(|e| e.to_string())?;
 
     // Create database connection pool
     let pool = PgPool::connect(&database_url).await?;
+    roles::bootstrap_admin(&pool).await?;
+    keys.rotate(&pool).await.map_err(|e| format!("{:?}", e))?;
+    let login_throttle = LoginThrottle::from_env(pool.clone())?;
 
     // Create application state
     let state = Arc::new(AppState {
         db: pool,
         jwt_secret,
+        keys,
+        access_token_ttl,
+        refresh_token_ttl,
+        revocations: RevocationList::new(revocation_cache_ttl),
+        mailer,
+        app_url,
+        email_verification_ttl,
+        password_reset_ttl,
+        magic_link_ttl,
+        invitation_ttl,
+        unverified_policy: UnverifiedPolicy::from_env(),
+        mfa_issuer,
+        oidc_issuer,
+        login_throttle,
+        password_hasher,
+        password_policy,
+        account_deletion_grace,
+        purge_hooks: account::purge_hooks_from_env(),
+        session_cookies,
+    });
+    // Rotate signing keys when due and pick up rotations by other instances
+    let rotation_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = rotation_state.keys.rotate(&rotation_state.db).await {
+                tracing::warn!("Signing key rotation failed: {:?}", e);
+            }
+        }
     });
 
-    // Build our application with routes
-    let app = Router::new()
-        .route("/auth/register", post(register))
-        .route("/auth/login", post(login))
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+    // Purge accounts whose deletion grace period is over
+    let purge_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(account::PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = account::purge_due_accounts(&purge_state).await {
+                tracing::warn!("Account purge failed: {:?}", e);
+            }
+        }
+    });
+
+    let authenticator: Arc<dyn Authenticator> = Arc::new(auth::RevocationAwareAuthenticator {
+        state: state.clone(),
+    });
+
+    let app = router(state, authenticator);
 
     // Run it
     let addr = "127.0.0.1:3000";
     info!("Starting server on {}", addr);
     axum::Server::bind(&addr.parse()?)
-        .serve(app.into_make_service())
+        // Login throttling needs the client address
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
 }
 
+// Build our application with routes
+fn router(state: Arc<AppState>, authenticator: Arc<dyn Authenticator>) -> Router {
+    // OAuth endpoints take the consent form, client credentials or bearer
+    // tokens, never session cookies. The consent page is a plain browser
+    // form that can't send the CSRF header, so they stay outside the cookie
+    // middleware.
+    let oauth = Router::new()
+        .route("/.well-known/jwks.json", get(jwks))
+        .route(
+            "/.well-known/openid-configuration",
+            get(oidc::openid_configuration),
+        )
+        .route(
+            "/oauth/authorize",
+            get(oidc::authorize).post(oidc::authorize_submit),
+        )
+        .route("/oauth/token", post(oidc::token))
+        .route("/oauth/userinfo", get(oidc::userinfo))
+        .route("/oauth/introspect", post(oidc::introspect))
+        .route("/oauth/revoke", post(oidc::revoke));
+
+    Router::new()
+        .route("/auth/register", post(register))
+        .route("/auth/login", post(login))
+        .route("/auth/refresh", post(tokens::refresh))
+        .route("/auth/logout", post(tokens::logout))
+        .route("/auth/logout-all", post(tokens::logout_all))
+        .route("/auth/verify-email", post(verification::verify_email))
+        .route(
+            "/auth/verify-email/resend",
+            post(verification::resend_verification),
+        )
+        .route("/auth/password/forgot", post(passwords::forgot_password))
+        .route("/auth/password/reset", post(passwords::reset_password))
+        .route("/auth/password/change", post(passwords::change_password))
+        .route("/auth/magic-link", post(magic_link::request_magic_link))
+        .route(
+            "/auth/magic-link/callback",
+            get(magic_link::magic_link_callback),
+        )
+        .route("/auth/mfa/totp/enroll", post(mfa::enroll))
+        .route("/auth/mfa/totp/confirm", post(mfa::confirm))
+        .route("/auth/mfa/verify", post(mfa::verify))
+        .route("/auth/unlock", post(lockout::unlock_account))
+        .route(
+            "/auth/me",
+            get(get_current_user)
+                .patch(account::update_profile)
+                .delete(account::delete_account),
+        )
+        .route("/auth/me/export", get(account::export_account))
+        .route(
+            "/auth/me/magic-link",
+            put(magic_link::update_magic_link_settings),
+        )
+        .route(
+            "/auth/api-keys",
+            get(api_keys::list_api_keys).post(api_keys::create_api_key),
+        )
+        .route("/auth/api-keys/:id", delete(api_keys::delete_api_key))
+        .route("/auth/sessions", get(sessions::list_sessions))
+        .route("/auth/sessions/:id", delete(sessions::delete_session))
+        .route(
+            "/admin/roles",
+            get(roles::list_roles).post(roles::create_role),
+        )
+        .route(
+            "/admin/roles/:name",
+            put(roles::update_role_permissions).delete(roles::delete_role),
+        )
+        .route(
+            "/admin/users/:user_id/roles",
+            get(roles::list_user_roles).post(roles::assign_role),
+        )
+        .route(
+            "/admin/users/:user_id/roles/:role",
+            delete(roles::remove_role),
+        )
+        .route("/auth/switch-org", post(orgs::switch_org))
+        .route("/orgs", get(orgs::list_orgs).post(orgs::create_org))
+        .route(
+            "/orgs/invitations/accept",
+            post(orgs::accept_invitation_handler),
+        )
+        .route("/org", get(orgs::get_org).delete(orgs::delete_org))
+        .route("/org/leave", post(orgs::leave_org))
+        .route("/org/members", get(orgs::list_members))
+        .route(
+            "/org/members/:user_id",
+            put(orgs::set_member_role).delete(orgs::remove_member),
+        )
+        .route(
+            "/org/invitations",
+            get(orgs::list_invitations).post(orgs::invite),
+        )
+        .route("/org/invitations/:id", delete(orgs::revoke_invitation))
+        .route(
+            "/org/teams",
+            get(teams::list_teams).post(teams::create_team),
+        )
+        .route("/org/teams/:team_id", delete(teams::delete_team))
+        .route(
+            "/org/teams/:team_id/members/:user_id",
+            put(teams::add_team_member).delete(teams::remove_team_member),
+        )
+        .route("/admin/users", get(users::list_users))
+        .route(
+            "/admin/users/:user_id",
+            get(users::get_user).delete(users::delete_user),
+        )
+        .route("/admin/users/:user_id/disable", post(users::disable_user))
+        .route("/admin/users/:user_id/enable", post(users::enable_user))
+        .route(
+            "/admin/users/:user_id/password-reset",
+            post(users::force_password_reset),
+        )
+        .route(
+            "/admin/users/:user_id/impersonate",
+            post(users::impersonate),
+        )
+        .route("/admin/users/:user_id/unlock", post(lockout::admin_unlock))
+        .route("/admin/audit/events", get(audit::list_events))
+        .route("/admin/audit/export", get(audit::export_events))
+        .route("/admin/audit/verify", get(audit::verify_chain))
+        .route(
+            "/admin/oauth/clients",
+            get(oauth_clients::list_clients).post(oauth_clients::register_client),
+        )
+        .route(
+            "/admin/oauth/clients/:client_id",
+            delete(oauth_clients::delete_client),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cookies::authenticate_cookies,
+        ))
+        .merge(oauth)
+        .layer(Extension(authenticator))
+        .with_state(state)
+}
+
 // Handler functions
 async fn register(
     State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    mode: SessionMode,
     Json(payload): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, AppError> {
+) -> Result<Response, AppError> {
+    state
+        .password_policy
+        .check(&payload.password, &payload.username, &payload.email)
+        .await?;
+
     // Hash the password
-    let password_hash = bcrypt::hash(payload.password, 10)
-        .map_err(|e| AppError::ValidationError(e.to_string()))?;
+    let password_hash = state.password_hasher.hash(&payload.password)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
 
     // Insert the user
-    let user = sqlx::query_as::<_, User>(
+    let mut user = sqlx::query_as::<_, User>(
         r#"
         INSERT INTO users (username, email, password_hash)
         VALUES ($1, $2, $3)
@@ -133,65 +433,109 @@ async fn register(
     .bind(&payload.username)
     .bind(&payload.email)
     .bind(&password_hash)
-    .fetch_one(&state.db)
+    .fetch_one(&mut *tx)
     .await
     .map_err(AppError::DatabaseError)?;
 
-    // Generate JWT token
-    let token = generate_token(&state.jwt_secret, user.id)?;
+    // An invitation can only be accepted with the address it was sent to, so
+    // it verifies the address too. A bad one rolls the registration back.
+    let mut org_id = None;
+    if let Some(token) = &payload.invitation_token {
+        org_id = Some(orgs::accept_invitation(&mut tx, token, &user).await?);
+        user = sqlx::query_as::<_, User>(
+            "UPDATE users SET email_verified_at = NOW() WHERE id = $1 RETURNING *",
+        )
+        .bind(user.id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+    }
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
 
-    Ok(Json(AuthResponse { token, user }))
+    audit::record(
+        &state,
+        AuditEvent::success("register")
+            .user(user.id)
+            .client(&client),
+    )
+    .await;
+
+    if let Some(org_id) = org_id {
+        let event = AuditEvent::success("org_invitation_accepted")
+            .user(user.id)
+            .client(&client)
+            .details(serde_json::json!({ "org_id": org_id }));
+        audit::record(&state, event).await;
+    } else {
+        verification::send_verification_email(&state, &user).await?;
+    }
+
+    // Accounts that must verify first only get their profile back
+    if state.unverified_policy.check(&user).is_err() {
+        return Ok((StatusCode::ACCEPTED, Json(PublicUser::from(user))).into_response());
+    }
+
+    // Generate access and refresh tokens
+    let response = tokens::issue_token_pair(&state, user, &client, false).await?;
+
+    Ok(cookies::respond(&state, mode, response))
 }
 
 async fn login(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    mode: SessionMode,
     Json(payload): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, AppError> {
-    // Find the user
-    let user = sqlx::query_as::<_, User>(
-        r#"
-        SELECT * FROM users WHERE username = $1
-        "#,
+) -> Result<Response, AppError> {
+    let login = login::check_password(
+        &state,
+        &payload.username,
+        &payload.password,
+        None,
+        addr.ip(),
+        &client,
     )
-    .bind(&payload.username)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(AppError::DatabaseError)?
-    .ok_or(AppError::AuthError("Invalid credentials".to_string()))?;
-
-    // Verify password
-    if !bcrypt::verify(&payload.password, &user.password_hash)
-        .map_err(|e| AppError::ValidationError(e.to_string()))?
-    {
-        return Err(AppError::AuthError("Invalid credentials".to_string()));
-    }
+    .await;
 
-    // Generate JWT token
-    let token = generate_token(&state.jwt_secret, user.id)?;
+    let user = match login {
+        Ok(PasswordLogin::Complete(user)) => user,
+        // With MFA on, the password only earns a challenge for the second step
+        Ok(PasswordLogin::MfaRequired(user)) => {
+            let event = AuditEvent::success("login")
+                .user(user.id)
+                .client(&client)
+                .details(serde_json::json!({ "mfa_required": true }));
+            audit::record(&state, event).await;
+            return Ok(Json(mfa::challenge(&state, user.id)?).into_response());
+        }
+        Err(failure) => {
+            let mut event = login_failure(&client, &payload.username, failure.reason);
+            if let Some(user_id) = failure.user_id {
+                event = event.subject(user_id);
+            }
+            audit::record(&state, event).await;
+            return Err(failure.error);
+        }
+    };
 
-    Ok(Json(AuthResponse { token, user }))
+    audit::record(
+        &state,
+        AuditEvent::success("login").user(user.id).client(&client),
+    )
+    .await;
+
+    // Generate access and refresh tokens
+    let response = tokens::issue_token_pair(&state, user, &client, false).await?;
+
+    Ok(cookies::respond(&state, mode, response))
 }
 
 async fn get_current_user(
     State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-) -> Result<Json<User>, AppError> {
-    // Extract token from Authorization header
-    let token = headers
-        .get("Authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or(AppError::AuthError("Missing token".to_string()))?;
-
-    // Verify token
-    let claims = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|_| AppError::AuthError("Invalid token".to_string()))?
-    .claims;
-
+    KeyOrAuthUser { claims }: KeyOrAuthUser,
+) -> Result<Json<PublicUser>, AppError> {
     // Get user from database
     let user = sqlx::query_as::<_, User>(
         r#"
@@ -203,25 +547,183 @@ async fn get_current_user(
     .await
     .map_err(AppError::DatabaseError)?;
 
-    Ok(Json(user))
+    Ok(Json(user.into()))
+}
+
+// Public keys for verifying access tokens without a shared secret
+async fn jwks(State(state): State<Arc<AppState>>) -> Json<jsonwebtoken::jwk::JwkSet> {
+    Json(state.keys.jwks())
 }
 
 // Helper functions
-fn generate_token(secret: &str, user_id: i32) -> Result<String, AppError> {
-    let expiration = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::hours(24))
+
+// A failed login, keeping the attempted username since there may be no user
+fn login_failure(client: &ClientInfo, username: &str, reason: &str) -> AuditEvent {
+    AuditEvent::failure("login")
+        .client(client)
+        .details(serde_json::json!({ "username": username, "reason": reason }))
+}
+
+// Roles and permissions are read at issue time, so changes apply on refresh
+async fn generate_token(
+    state: &AppState,
+    user_id: i32,
+    session_id: Option<uuid::Uuid>,
+) -> Result<String, AppError> {
+    let (roles, mut permissions) = roles::load_grants(&state.db, user_id).await?;
+
+    // In an organization, the member's role there adds its permissions
+    let org = match session_id {
+        Some(session_id) => orgs::session_grants(&state.db, user_id, session_id).await?,
+        None => None,
+    };
+    if let Some((_, granted)) = org {
+        permissions.extend(granted.iter().map(|p| p.to_string()));
+        permissions.sort();
+        permissions.dedup();
+    }
+
+    let now = chrono::Utc::now();
+    let expiration = now
+        .checked_add_signed(state.access_token_ttl)
         .expect("valid timestamp")
         .timestamp() as usize;
 
     let claims = Claims {
         sub: user_id,
         exp: expiration,
+        iat: now.timestamp() as usize,
+        jti: uuid::Uuid::new_v4().to_string(),
+        sid: session_id.map(|sid| sid.to_string()),
+        act: None,
+        org_id: org.map(|(org_id, _)| org_id),
+        roles,
+        permissions,
+        client_id: None,
+        scope: None,
+        aud: None,
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|e| AppError::ValidationError(e.to_string()))
+    state.keys.sign(&claims)
+}
+
+// Read a numeric setting from the environment, falling back to a default
+fn env_or(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{header, Method, Request};
+    use lockout::MemoryAttemptStore;
+    use tower::ServiceExt;
+
+    fn test_state() -> Arc<AppState> {
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("postgres://localhost:1/unused")
+            .unwrap();
+        let access_token_ttl = chrono::Duration::minutes(15);
+
+        Arc::new(AppState {
+            db: db.clone(),
+            jwt_secret: "secret".to_string(),
+            keys: KeyRing::from_env("secret".to_string(), access_token_ttl).unwrap(),
+            access_token_ttl,
+            refresh_token_ttl: chrono::Duration::days(7),
+            revocations: RevocationList::new(std::time::Duration::from_secs(30)),
+            mailer: Box::new(mailer::MemoryMailer::default()),
+            app_url: "http://localhost:3000".to_string(),
+            email_verification_ttl: chrono::Duration::hours(24),
+            password_reset_ttl: chrono::Duration::minutes(60),
+            magic_link_ttl: chrono::Duration::minutes(15),
+            invitation_ttl: chrono::Duration::days(7),
+            unverified_policy: UnverifiedPolicy::Allow,
+            mfa_issuer: "auth_api".to_string(),
+            oidc_issuer: "http://localhost:3000".to_string(),
+            login_throttle: LoginThrottle::new(
+                Box::new(MemoryAttemptStore::default()),
+                5,
+                50,
+                chrono::Duration::minutes(15),
+            ),
+            password_hasher: PasswordHashing::from_env().unwrap(),
+            password_policy: PasswordPolicy::new(8, 0, None),
+            account_deletion_grace: chrono::Duration::days(30),
+            purge_hooks: Vec::new(),
+            session_cookies: Some(cookies::SessionCookies::new(None, cookies::SameSite::Lax)),
+        })
+    }
+
+    fn app() -> Router {
+        let state = test_state();
+        let authenticator: Arc<dyn Authenticator> = Arc::new(auth::RevocationAwareAuthenticator {
+            state: state.clone(),
+        });
+        router(state, authenticator)
+    }
+
+    // A browser with a cookie session, posting a form without the CSRF header
+    fn cookie_post(uri: &str, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::COOKIE, "access_token=abc.def; csrf_token=xyz")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn body_text(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn consent_form_works_with_a_cookie_session() {
+        let request = cookie_post(
+            "/oauth/authorize",
+            "response_type=code&client_id=app&redirect_uri=https%3A%2F%2Fapp.example.com%2Fcb\
+             &username=alice&password=secret&decision=allow",
+        );
+        let response = app().oneshot(request).await.unwrap();
+
+        // Past the cookie middleware and into the consent handler
+        assert_ne!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!body_text(response).await.contains("CSRF"));
+    }
+
+    #[tokio::test]
+    async fn cookie_routes_still_need_the_csrf_header() {
+        let response = app()
+            .oneshot(cookie_post("/auth/logout", ""))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(body_text(response).await.contains("CSRF"));
+    }
+
+    #[tokio::test]
+    async fn discovery_lists_only_the_signing_algorithm() {
+        let request = Request::builder()
+            .uri("/.well-known/openid-configuration")
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        let discovery: serde_json::Value =
+            serde_json::from_str(&body_text(response).await).unwrap();
+
+        assert_eq!(
+            discovery["id_token_signing_alg_values_supported"],
+            serde_json::json!(["HS256"])
+        );
+    }
 } 
\ No newline at end of file
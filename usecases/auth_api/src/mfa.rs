@@ -0,0 +1,513 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    response::Response,
+    Json,
+};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::account::require_own_login;
+use crate::audit::{self, AuditEvent};
+use crate::cookies::{self, SessionMode};
+use crate::login;
+use crate::sessions::ClientInfo;
+use crate::tokens::{hash_token, issue_token_pair};
+use crate::users;
+use crate::{AppError, AppState, AuthUser, User};
+
+// RFC 6238 defaults, which every authenticator app supports
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+
+// Codes from the previous and next step are accepted to absorb clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+// Audience of challenge tokens, so they can't be used as access tokens
+const AUDIENCE: &str = "mfa-challenge";
+
+// How long the user has to enter a code after a correct password
+const CHALLENGE_TTL_SECS: i64 = 300;
+
+// Wrong codes after which a challenge stops working and the password has to
+// be entered again
+const MAX_CHALLENGE_FAILURES: i32 = 3;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, sqlx::FromRow)]
+struct TotpState {
+    totp_secret: Option<String>,
+    totp_enabled_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: i32,
+    aud: String,
+    exp: usize,
+    // Revoked once the challenge is used or sees too many wrong codes
+    jti: String,
+}
+
+// Returned by `login` instead of tokens when the account has MFA enabled
+#[derive(Debug, Serialize)]
+pub struct MfaChallenge {
+    mfa_required: bool,
+    mfa_token: String,
+    expires_in: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnrollResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmRequest {
+    code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyRequest {
+    mfa_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+// HOTP value (RFC 4226) for one counter value
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation: the low nibble of the last byte picks 4 bytes
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    value % 10u32.pow(DIGITS)
+}
+
+// Time step a code is checked against
+fn time_step(timestamp: i64) -> i64 {
+    timestamp.div_euclid(STEP_SECS)
+}
+
+// Find the step within the allowed drift whose code matches
+fn matching_step(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = time_step(now);
+    (current - ALLOWED_DRIFT_STEPS..=current + ALLOWED_DRIFT_STEPS)
+        .find(|step| hotp(secret, *step as u64) == code)
+}
+
+fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding::encode(issuer),
+        urlencoding::encode(account),
+        secret,
+        urlencoding::encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+// Recovery codes look like `abcde-12345` so they're easy to type
+fn generate_recovery_code() -> String {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    let mut code: String = (0..10)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect();
+    code.insert(5, '-');
+    code
+}
+
+async fn load_totp(state: &AppState, user_id: i32) -> Result<TotpState, AppError> {
+    sqlx::query_as::<_, TotpState>("SELECT totp_secret, totp_enabled_at FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)
+}
+
+// Whether login has to go through the MFA challenge for this user
+pub async fn is_enabled(state: &AppState, user_id: i32) -> Result<bool, AppError> {
+    Ok(load_totp(state, user_id).await?.totp_enabled_at.is_some())
+}
+
+// Short-lived token proving the password step succeeded
+pub fn challenge(state: &AppState, user_id: i32) -> Result<MfaChallenge, AppError> {
+    let claims = ChallengeClaims {
+        sub: user_id,
+        aud: AUDIENCE.to_string(),
+        exp: (chrono::Utc::now().timestamp() + CHALLENGE_TTL_SECS) as usize,
+        jti: Uuid::new_v4().to_string(),
+    };
+    let mfa_token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    )
+    .map_err(|e| AppError::ValidationError(e.to_string()))?;
+
+    Ok(MfaChallenge {
+        mfa_required: true,
+        mfa_token,
+        expires_in: CHALLENGE_TTL_SECS,
+    })
+}
+
+// Accept the code once per time step; a later step also rules out earlier ones
+pub async fn consume_code(state: &AppState, user_id: i32, code: &str) -> Result<(), AppError> {
+    let secret = load_totp(state, user_id)
+        .await?
+        .totp_secret
+        .and_then(|secret| BASE32_NOPAD.decode(secret.as_bytes()).ok())
+        .ok_or(AppError::ValidationError("MFA is not set up".to_string()))?;
+
+    let step = matching_step(&secret, code, chrono::Utc::now().timestamp())
+        .ok_or(AppError::AuthError("Invalid code".to_string()))?;
+
+    let accepted = sqlx::query(
+        r#"
+        UPDATE users SET totp_last_step = $2
+        WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(step)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+
+    if accepted == 0 {
+        return Err(AppError::AuthError("Code already used".to_string()));
+    }
+
+    Ok(())
+}
+
+async fn consume_recovery_code(
+    state: &AppState,
+    user_id: i32,
+    recovery_code: &str,
+) -> Result<(), AppError> {
+    let used = sqlx::query(
+        r#"
+        UPDATE mfa_recovery_codes SET used_at = NOW()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(hash_token(recovery_code.trim()))
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+
+    if used == 0 {
+        return Err(AppError::AuthError("Invalid recovery code".to_string()));
+    }
+
+    Ok(())
+}
+
+// Handler functions
+pub async fn enroll(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+) -> Result<Json<EnrollResponse>, AppError> {
+    // Neither an impersonating admin nor an OAuth client may swap the factor
+    require_own_login(&claims)?;
+    if is_enabled(&state, claims.sub).await? {
+        return Err(AppError::ValidationError(
+            "MFA is already enabled".to_string(),
+        ));
+    }
+
+    let mut bytes = [0u8; 20];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let secret = BASE32_NOPAD.encode(&bytes);
+
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE id = $2 RETURNING *",
+    )
+    .bind(&secret)
+    .bind(claims.sub)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(EnrollResponse {
+        otpauth_uri: otpauth_uri(&state.mfa_issuer, &user.username, &secret),
+        secret,
+    }))
+}
+
+// The first valid code turns MFA on and hands out the recovery codes
+pub async fn confirm(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<ConfirmRequest>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    require_own_login(&claims)?;
+    if is_enabled(&state, claims.sub).await? {
+        return Err(AppError::ValidationError(
+            "MFA is already enabled".to_string(),
+        ));
+    }
+
+    consume_code(&state, claims.sub, &payload.code).await?;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    sqlx::query("UPDATE users SET totp_enabled_at = NOW() WHERE id = $1")
+        .bind(claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+        .bind(claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    for code in &recovery_codes {
+        sqlx::query("INSERT INTO mfa_recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(claims.sub)
+            .bind(hash_token(code))
+            .execute(&mut *tx)
+            .await
+            .map_err(AppError::DatabaseError)?;
+    }
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    audit::record(
+        &state,
+        AuditEvent::success("mfa_enabled")
+            .by(&claims)
+            .subject(claims.sub)
+            .client(&client),
+    )
+    .await;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+// Second login step: trade the challenge token and a code for real tokens.
+// Each challenge works once, wrong codes count towards the account lockout,
+// and a few of them use the challenge up.
+pub async fn verify(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    mode: SessionMode,
+    Json(payload): Json<VerifyRequest>,
+) -> Result<Response, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[AUDIENCE]);
+
+    let challenge = decode::<ChallengeClaims>(
+        &payload.mfa_token,
+        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map_err(|_| AppError::AuthError("Invalid MFA token".to_string()))?
+    .claims;
+    let user_id = challenge.sub;
+    let exp = challenge.exp as i64;
+
+    if state
+        .revocations
+        .is_jti_revoked(&state.db, &challenge.jti, exp)
+        .await?
+    {
+        return Err(AppError::AuthError("Invalid MFA token".to_string()));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+    let attempt = state
+        .login_throttle
+        .begin(&user.username, addr.ip())
+        .await?;
+
+    let (checked, method) = match (&payload.code, &payload.recovery_code) {
+        (Some(code), None) => (consume_code(&state, user_id, code).await, "totp"),
+        (None, Some(recovery_code)) => (
+            consume_recovery_code(&state, user_id, recovery_code).await,
+            "recovery_code",
+        ),
+        _ => {
+            state.login_throttle.release(attempt).await?;
+            return Err(AppError::ValidationError(
+                "Provide either `code` or `recovery_code`".to_string(),
+            ));
+        }
+    };
+
+    let event = match checked {
+        Ok(()) => AuditEvent::success("mfa_verify"),
+        Err(_) => AuditEvent::failure("mfa_verify"),
+    };
+    audit::record(
+        &state,
+        event
+            .user(user_id)
+            .client(&client)
+            .details(serde_json::json!({ "method": method })),
+    )
+    .await;
+
+    if let Err(e) = checked {
+        let locked = login::record_failure(&state, attempt, Some(&user), &client).await;
+
+        let failures = state
+            .login_throttle
+            .record_challenge_failure(&challenge.jti)
+            .await?;
+        if challenge_used_up(failures, locked) {
+            state
+                .revocations
+                .revoke_jti(&state.db, &challenge.jti, user_id, exp)
+                .await?;
+        }
+        return Err(e);
+    }
+
+    state
+        .revocations
+        .revoke_jti(&state.db, &challenge.jti, user_id, exp)
+        .await?;
+    state.login_throttle.record_success(attempt).await?;
+    users::check_active(&user)?;
+
+    let response = issue_token_pair(&state, user, &client, true).await?;
+
+    Ok(cookies::respond(&state, mode, response))
+}
+
+// A challenge is dropped after too many wrong codes, or once they locked the
+// account
+fn challenge_used_up(failures: i32, locked: bool) -> bool {
+    locked || failures >= MAX_CHALLENGE_FAILURES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from RFC 6238 appendix B (SHA-1), truncated to 6 digits
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        for (timestamp, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+        ] {
+            assert_eq!(hotp(SECRET, time_step(timestamp) as u64), code);
+        }
+    }
+
+    #[test]
+    fn accepts_adjacent_steps_only() {
+        let now = 1111111109;
+        let step = time_step(now);
+        let code = |step: i64| format!("{:06}", hotp(SECRET, step as u64));
+
+        assert_eq!(matching_step(SECRET, &code(step), now), Some(step));
+        assert_eq!(matching_step(SECRET, &code(step - 1), now), Some(step - 1));
+        assert_eq!(matching_step(SECRET, &code(step + 1), now), Some(step + 1));
+        assert_eq!(matching_step(SECRET, &code(step - 2), now), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert_eq!(matching_step(SECRET, "12345", 59), None);
+        assert_eq!(matching_step(SECRET, "28708a", 59), None);
+        assert_eq!(matching_step(SECRET, " 287082 ", 59), Some(1));
+    }
+
+    #[test]
+    fn challenges_allow_a_few_wrong_codes() {
+        assert!(!challenge_used_up(1, false));
+        assert!(!challenge_used_up(MAX_CHALLENGE_FAILURES - 1, false));
+        assert!(challenge_used_up(MAX_CHALLENGE_FAILURES, false));
+        assert!(challenge_used_up(1, true));
+    }
+
+    #[test]
+    fn challenge_tokens_are_unique() {
+        let secret = b"secret";
+        let token = |user_id| {
+            encode(
+                &Header::default(),
+                &ChallengeClaims {
+                    sub: user_id,
+                    aud: AUDIENCE.to_string(),
+                    exp: (chrono::Utc::now().timestamp() + CHALLENGE_TTL_SECS) as usize,
+                    jti: Uuid::new_v4().to_string(),
+                },
+                &EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let mut validation = Validation::default();
+        validation.set_audience(&[AUDIENCE]);
+        let jti = |token: String| {
+            decode::<ChallengeClaims>(&token, &DecodingKey::from_secret(secret), &validation)
+                .unwrap()
+                .claims
+                .jti
+        };
+
+        assert_ne!(jti(token(1)), jti(token(1)));
+    }
+
+    #[test]
+    fn builds_otpauth_uri() {
+        assert_eq!(
+            otpauth_uri("My App", "john doe", "JBSWY3DPEHPK3PXP"),
+            "otpauth://totp/My%20App:john%20doe?secret=JBSWY3DPEHPK3PXP&issuer=My%20App&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use auth_api::authz::RequirePermission;
+use auth_api::permission;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::tokens::{generate_opaque_token, hash_token};
+use crate::{AppError, AppState};
+
+permission!(pub OauthClientsRead = "oauth_clients:read");
+permission!(pub OauthClientsWrite = "oauth_clients:write");
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct OauthClient {
+    pub client_id: String,
+    #[serde(skip)]
+    pub client_secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl OauthClient {
+    // Confidential clients hold a secret; public ones rely on PKCE alone
+    pub fn is_confidential(&self) -> bool {
+        self.client_secret_hash.is_some()
+    }
+
+    pub fn check_secret(&self, secret: Option<&str>) -> bool {
+        match (&self.client_secret_hash, secret) {
+            (Some(hash), Some(secret)) => *hash == hash_token(secret),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterClientRequest {
+    name: String,
+    redirect_uris: Vec<String>,
+    #[serde(default = "default_confidential")]
+    confidential: bool,
+}
+
+fn default_confidential() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisteredClient {
+    #[serde(flatten)]
+    client: OauthClient,
+    // Only returned once, at registration
+    client_secret: Option<String>,
+}
+
+pub async fn find_client(
+    state: &AppState,
+    client_id: &str,
+) -> Result<Option<OauthClient>, AppError> {
+    sqlx::query_as::<_, OauthClient>("SELECT * FROM oauth_clients WHERE client_id = $1")
+        .bind(client_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)
+}
+
+// HTTPS anywhere, plain HTTP only for native apps listening on the loopback
+// interface. The host is compared as parsed, so `localhost.example.com` or
+// `localhost@example.com` don't pass as local.
+fn redirect_uri_allowed(uri: &str) -> bool {
+    let Ok(url) = url::Url::parse(uri) else {
+        return false;
+    };
+    match (url.scheme(), url.host_str()) {
+        ("https", Some(_)) => true,
+        ("http", Some(host)) => host == "localhost" || host == "127.0.0.1",
+        _ => false,
+    }
+}
+
+// Handler functions
+pub async fn register_client(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<OauthClientsWrite>,
+    Json(payload): Json<RegisterClientRequest>,
+) -> Result<(StatusCode, Json<RegisteredClient>), AppError> {
+    if payload.name.trim().is_empty() {
+        return Err(AppError::ValidationError(
+            "Client name must not be empty".to_string(),
+        ));
+    }
+    if payload.redirect_uris.is_empty() {
+        return Err(AppError::ValidationError(
+            "At least one redirect URI is required".to_string(),
+        ));
+    }
+    // Redirect URIs are compared exactly, so fragments and wildcards make no sense
+    for uri in &payload.redirect_uris {
+        if !redirect_uri_allowed(uri) || uri.contains('#') || uri.contains('*') {
+            return Err(AppError::ValidationError(format!(
+                "Invalid redirect URI `{}`",
+                uri
+            )));
+        }
+    }
+
+    let client_secret = payload.confidential.then(generate_opaque_token);
+
+    let client = sqlx::query_as::<_, OauthClient>(
+        r#"
+        INSERT INTO oauth_clients (client_id, client_secret_hash, name, redirect_uris)
+        VALUES ($1, $2, $3, $4)
+        RETURNING *
+        "#,
+    )
+    .bind(uuid::Uuid::new_v4().simple().to_string())
+    .bind(client_secret.as_deref().map(hash_token))
+    .bind(&payload.name)
+    .bind(&payload.redirect_uris)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisteredClient {
+            client,
+            client_secret,
+        }),
+    ))
+}
+
+pub async fn list_clients(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<OauthClientsRead>,
+) -> Result<Json<Vec<OauthClient>>, AppError> {
+    let clients = sqlx::query_as::<_, OauthClient>("SELECT * FROM oauth_clients ORDER BY name")
+        .fetch_all(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(clients))
+}
+
+pub async fn delete_client(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<OauthClientsWrite>,
+    Path(client_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let deleted = sqlx::query("DELETE FROM oauth_clients WHERE client_id = $1")
+        .bind(&client_id)
+        .execute(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .rows_affected();
+
+    if deleted == 0 {
+        return Err(AppError::ValidationError(format!(
+            "Unknown client `{}`",
+            client_id
+        )));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_loopback_hosts_may_use_http() {
+        for uri in [
+            "https://app.example.com/callback",
+            "http://localhost/callback",
+            "http://localhost:8080/callback",
+            "http://127.0.0.1:53682/",
+        ] {
+            assert!(redirect_uri_allowed(uri), "{}", uri);
+        }
+        for uri in [
+            "http://localhost.evil.example/cb",
+            "http://127.0.0.1.evil.example/cb",
+            "http://localhost@evil.example/cb",
+            "http://app.example.com/callback",
+            "javascript:alert(1)",
+            "/callback",
+        ] {
+            assert!(!redirect_uri_allowed(uri), "{}", uri);
+        }
+    }
+}
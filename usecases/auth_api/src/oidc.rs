@@ -0,0 +1,910 @@
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::audit::{self, AuditEvent};
+use crate::auth::{self, ClientAuthUser};
+use crate::login::{self, PasswordLogin};
+use crate::oauth_clients::{find_client, OauthClient};
+use crate::sessions::ClientInfo;
+use crate::tokens::{generate_opaque_token, hash_token};
+use crate::{AppError, AppState, Claims, User};
+
+// Audience of access tokens issued to clients. Only `/oauth/userinfo` takes
+// them; every other endpoint, here and in other services, rejects them.
+pub const CLIENT_TOKEN_AUDIENCE: &str = "oauth-client";
+
+// Scopes this provider understands
+const SUPPORTED_SCOPES: &[&str] = &["openid", "profile", "email"];
+
+// Authorization codes are exchanged right after the redirect
+const CODE_TTL_SECS: i64 = 300;
+
+// OAuth error response (RFC 6749 section 5.2)
+#[derive(Debug)]
+pub struct OAuthError {
+    status: StatusCode,
+    error: &'static str,
+    description: String,
+}
+
+impl OAuthError {
+    fn new(status: StatusCode, error: &'static str, description: impl Into<String>) -> Self {
+        OAuthError {
+            status,
+            error,
+            description: description.into(),
+        }
+    }
+
+    fn invalid_request(description: impl Into<String>) -> Self {
+        OAuthError::new(StatusCode::BAD_REQUEST, "invalid_request", description)
+    }
+
+    fn invalid_grant(description: impl Into<String>) -> Self {
+        OAuthError::new(StatusCode::BAD_REQUEST, "invalid_grant", description)
+    }
+
+    fn invalid_client() -> Self {
+        OAuthError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_client",
+            "Client authentication failed",
+        )
+    }
+}
+
+impl From<AppError> for OAuthError {
+    fn from(e: AppError) -> Self {
+        let description = match e {
+            AppError::DatabaseError(e) => e.to_string(),
+            AppError::AuthError(msg)
+            | AppError::ValidationError(msg)
+            | AppError::ForbiddenError(msg)
+            | AppError::RateLimitError(msg)
+            | AppError::InternalError(msg) => msg,
+        };
+        OAuthError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "server_error",
+            description,
+        )
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            [(header::CACHE_CONTROL, "no-store")],
+            Json(serde_json::json!({
+                "error": self.error,
+                "error_description": self.description,
+            })),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthorizeParams {
+    response_type: Option<String>,
+    client_id: Option<String>,
+    redirect_uri: Option<String>,
+    scope: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeForm {
+    #[serde(flatten)]
+    params: AuthorizeParams,
+    username: String,
+    password: String,
+    otp: Option<String>,
+    decision: String,
+}
+
+// An authorization request that passed validation
+struct AuthorizeRequest {
+    client: OauthClient,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: String,
+}
+
+// Problems with the client or redirect URI are shown to the user; everything
+// else is reported back to the client through the redirect
+enum AuthorizeError {
+    Page(String),
+    Redirect(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AuthorizationCode {
+    id: i32,
+    client_id: String,
+    user_id: i32,
+    redirect_uri: String,
+    scope: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    auth_time: chrono::DateTime<chrono::Utc>,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    used_at: Option<chrono::DateTime<chrono::Utc>>,
+    access_token_jti: Option<String>,
+    access_token_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: usize,
+    iat: usize,
+    auth_time: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    profile: ScopedClaims,
+}
+
+// Claims released for the `profile` and `email` scopes
+#[derive(Debug, Default, Serialize)]
+struct ScopedClaims {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    sub: String,
+    #[serde(flatten)]
+    profile: ScopedClaims,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenParam {
+    token: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+// Introspection response (RFC 7662); only `active` is set for unusable tokens
+#[derive(Debug, Default, Serialize)]
+pub struct Introspection {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+}
+
+fn has_scope(scope: &str, wanted: &str) -> bool {
+    scope.split(' ').any(|s| s == wanted)
+}
+
+fn scoped_claims(user: &User, scope: &str) -> ScopedClaims {
+    let mut claims = ScopedClaims::default();
+    if has_scope(scope, "profile") {
+        claims.preferred_username = Some(user.username.clone());
+    }
+    if has_scope(scope, "email") {
+        claims.email = Some(user.email.clone());
+        claims.email_verified = Some(user.email_verified_at.is_some());
+    }
+    claims
+}
+
+// `redirect_uri` with extra query parameters appended
+fn redirect_with(redirect_uri: &str, params: &[(&str, &str)]) -> String {
+    let query = params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", redirect_uri, separator, query)
+}
+
+// BASE64URL(SHA256(verifier)) as required by the S256 PKCE method
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+async fn validate_authorize(
+    state: &AppState,
+    params: &AuthorizeParams,
+) -> Result<AuthorizeRequest, AuthorizeError> {
+    if state.keys.is_symmetric() {
+        return Err(AuthorizeError::Page(
+            "Single sign-on requires an asymmetric JWT_ALGORITHM".to_string(),
+        ));
+    }
+
+    let client_id = params
+        .client_id
+        .as_deref()
+        .ok_or(AuthorizeError::Page("Missing client_id".to_string()))?;
+    let client = find_client(state, client_id)
+        .await
+        .map_err(|_| AuthorizeError::Page("Unable to load the client".to_string()))?
+        .ok_or(AuthorizeError::Page("Unknown client".to_string()))?;
+
+    let redirect_uri = params
+        .redirect_uri
+        .clone()
+        .ok_or(AuthorizeError::Page("Missing redirect_uri".to_string()))?;
+    if !client.redirect_uris.contains(&redirect_uri) {
+        return Err(AuthorizeError::Page(
+            "The redirect_uri is not registered for this client".to_string(),
+        ));
+    }
+
+    // From here on the redirect URI is trusted, so errors go back to the client
+    let fail = |error: &str, description: &str| {
+        let mut query = vec![("error", error), ("error_description", description)];
+        if let Some(state) = &params.state {
+            query.push(("state", state));
+        }
+        query.push(("iss", &state.oidc_issuer));
+        AuthorizeError::Redirect(redirect_with(&redirect_uri, &query))
+    };
+
+    if params.response_type.as_deref() != Some("code") {
+        return Err(fail(
+            "unsupported_response_type",
+            "Only the code response type is supported",
+        ));
+    }
+
+    let scope = params.scope.clone().unwrap_or_else(|| "openid".to_string());
+    if let Some(unknown) = scope.split(' ').find(|s| !SUPPORTED_SCOPES.contains(s)) {
+        return Err(fail(
+            "invalid_scope",
+            &format!("Unknown scope `{}`", unknown),
+        ));
+    }
+
+    // OAuth 2.1 makes PKCE mandatory; the `plain` method is not accepted
+    let code_challenge = params
+        .code_challenge
+        .clone()
+        .ok_or_else(|| fail("invalid_request", "code_challenge is required"))?;
+    if params.code_challenge_method.as_deref() != Some("S256") {
+        return Err(fail(
+            "invalid_request",
+            "code_challenge_method must be S256",
+        ));
+    }
+
+    Ok(AuthorizeRequest {
+        client,
+        redirect_uri,
+        scope,
+        state: params.state.clone(),
+        nonce: params.nonce.clone(),
+        code_challenge,
+    })
+}
+
+fn error_page(message: &str) -> Response {
+    let body = format!(
+        "<!doctype html><html><head><title>Sign in</title></head><body><h1>Sign in failed</h1><p>{}</p></body></html>",
+        escape_html(message)
+    );
+    (StatusCode::BAD_REQUEST, Html(body)).into_response()
+}
+
+// Login and consent screen
+fn consent_page(
+    request: &AuthorizeRequest,
+    params: &AuthorizeParams,
+    error: Option<&str>,
+) -> Html<String> {
+    let hidden = [
+        ("response_type", &params.response_type),
+        ("client_id", &params.client_id),
+        ("redirect_uri", &params.redirect_uri),
+        ("scope", &params.scope),
+        ("state", &params.state),
+        ("nonce", &params.nonce),
+        ("code_challenge", &params.code_challenge),
+        ("code_challenge_method", &params.code_challenge_method),
+    ]
+    .iter()
+    .filter_map(|(name, value)| {
+        value.as_ref().map(|value| {
+            format!(
+                r#"<input type="hidden" name="{}" value="{}">"#,
+                name,
+                escape_html(value)
+            )
+        })
+    })
+    .collect::<String>();
+
+    let scopes = request
+        .scope
+        .split(' ')
+        .map(|scope| {
+            let description = match scope {
+                "openid" => "Know who you are",
+                "profile" => "See your username",
+                "email" => "See your email address",
+                _ => scope,
+            };
+            format!("<li>{}</li>", escape_html(description))
+        })
+        .collect::<String>();
+
+    let error = error
+        .map(|e| format!(r#"<p role="alert">{}</p>"#, escape_html(e)))
+        .unwrap_or_default();
+
+    Html(format!(
+        r#"<!doctype html>
+<html>
+<head><title>Sign in to {name}</title></head>
+<body>
+<h1>Sign in to {name}</h1>
+{error}
+<p>{name} would like to:</p>
+<ul>{scopes}</ul>
+<form method="post" action="/oauth/authorize">
+{hidden}
+<p><label>Username <input name="username" autocomplete="username" required></label></p>
+<p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p><label>Authenticator code (if enabled) <input name="otp" inputmode="numeric" autocomplete="one-time-code"></label></p>
+<button name="decision" value="allow">Allow</button>
+<button name="decision" value="deny" formnovalidate>Deny</button>
+</form>
+</body>
+</html>"#,
+        name = escape_html(&request.client.name),
+        error = error,
+        scopes = scopes,
+        hidden = hidden,
+    ))
+}
+
+// Check the credentials typed into the consent page. Failures come back with
+// the audit reason and the message shown on the page.
+async fn login(
+    state: &AppState,
+    form: &AuthorizeForm,
+    ip: IpAddr,
+    client: &ClientInfo,
+) -> Result<User, (&'static str, String)> {
+    let login = login::check_password(
+        state,
+        &form.username,
+        &form.password,
+        form.otp.as_deref(),
+        ip,
+        client,
+    )
+    .await;
+
+    match login {
+        Ok(PasswordLogin::Complete(user)) => Ok(user),
+        Ok(PasswordLogin::MfaRequired(_)) => Err((
+            "otp_required",
+            "Enter the code from your authenticator app".to_string(),
+        )),
+        Err(failure) => {
+            let message = match failure.error {
+                AppError::RateLimitError(msg) | AppError::ForbiddenError(msg) => msg,
+                _ if failure.reason == "invalid_otp" => "Invalid authenticator code".to_string(),
+                _ => "Invalid credentials".to_string(),
+            };
+            Err((failure.reason, message))
+        }
+    }
+}
+
+// Client credentials from HTTP Basic auth or the form body
+async fn authenticate_client(
+    state: &AppState,
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<OauthClient, OAuthError> {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+
+    let (client_id, client_secret) = match &basic {
+        Some(credentials) => {
+            let (id, secret) = credentials
+                .split_once(':')
+                .ok_or_else(OAuthError::invalid_client)?;
+            (id.to_string(), Some(secret.to_string()))
+        }
+        None => (
+            client_id
+                .ok_or_else(OAuthError::invalid_client)?
+                .to_string(),
+            client_secret.map(str::to_string),
+        ),
+    };
+
+    let client = find_client(state, &client_id)
+        .await?
+        .ok_or_else(OAuthError::invalid_client)?;
+
+    if !client.check_secret(client_secret.as_deref()) {
+        return Err(OAuthError::invalid_client());
+    }
+
+    Ok(client)
+}
+
+// Handler functions
+pub async fn openid_configuration(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    let issuer = &state.oidc_issuer;
+    Json(serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+        "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [state.keys.algorithm_name()],
+        "scopes_supported": SUPPORTED_SCOPES,
+        "claims_supported": ["sub", "preferred_username", "email", "email_verified"],
+        "code_challenge_methods_supported": ["S256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "authorization_response_iss_parameter_supported": true,
+    }))
+}
+
+pub async fn authorize(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuthorizeParams>,
+) -> Response {
+    match validate_authorize(&state, &params).await {
+        Ok(request) => consent_page(&request, &params, None).into_response(),
+        Err(AuthorizeError::Page(message)) => error_page(&message),
+        Err(AuthorizeError::Redirect(uri)) => Redirect::to(&uri).into_response(),
+    }
+}
+
+pub async fn authorize_submit(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    Form(form): Form<AuthorizeForm>,
+) -> Response {
+    let request = match validate_authorize(&state, &form.params).await {
+        Ok(request) => request,
+        Err(AuthorizeError::Page(message)) => return error_page(&message),
+        Err(AuthorizeError::Redirect(uri)) => return Redirect::to(&uri).into_response(),
+    };
+
+    let mut query = Vec::new();
+    if let Some(state) = &request.state {
+        query.push(("state", state.as_str()));
+    }
+    query.push(("iss", state.oidc_issuer.as_str()));
+
+    if form.decision != "allow" {
+        query.push(("error", "access_denied"));
+        return Redirect::to(&redirect_with(&request.redirect_uri, &query)).into_response();
+    }
+
+    let user = match login(&state, &form, addr.ip(), &client).await {
+        Ok(user) => {
+            let event = AuditEvent::success("login")
+                .user(user.id)
+                .client(&client)
+                .details(serde_json::json!({ "client_id": request.client.client_id }));
+            audit::record(&state, event).await;
+            user
+        }
+        Err((reason, message)) => {
+            let event = AuditEvent::failure("login")
+                .client(&client)
+                .details(serde_json::json!({
+                    "username": form.username,
+                    "client_id": request.client.client_id,
+                    "reason": reason,
+                }));
+            audit::record(&state, event).await;
+            let page = consent_page(&request, &form.params, Some(&message));
+            return (StatusCode::UNAUTHORIZED, page).into_response();
+        }
+    };
+
+    let code = generate_opaque_token();
+    let stored = sqlx::query(
+        r#"
+        INSERT INTO oauth_authorization_codes
+            (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, NOW(), NOW() + make_interval(secs => $8))
+        "#,
+    )
+    .bind(hash_token(&code))
+    .bind(&request.client.client_id)
+    .bind(user.id)
+    .bind(&request.redirect_uri)
+    .bind(&request.scope)
+    .bind(&request.nonce)
+    .bind(&request.code_challenge)
+    .bind(CODE_TTL_SECS as f64)
+    .execute(&state.db)
+    .await;
+
+    if let Err(e) = stored {
+        warn!("Failed to store authorization code: {}", e);
+        query.push(("error", "server_error"));
+        return Redirect::to(&redirect_with(&request.redirect_uri, &query)).into_response();
+    }
+
+    query.push(("code", &code));
+    Redirect::to(&redirect_with(&request.redirect_uri, &query)).into_response()
+}
+
+pub async fn token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Form(payload): Form<TokenRequest>,
+) -> Result<Response, OAuthError> {
+    if payload.grant_type != "authorization_code" {
+        return Err(OAuthError::new(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "Only authorization_code is supported",
+        ));
+    }
+    if state.keys.is_symmetric() {
+        return Err(OAuthError::invalid_request(
+            "Single sign-on requires an asymmetric JWT_ALGORITHM",
+        ));
+    }
+
+    let client = authenticate_client(
+        &state,
+        &headers,
+        payload.client_id.as_deref(),
+        payload.client_secret.as_deref(),
+    )
+    .await?;
+
+    let code = payload
+        .code
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("code is required"))?;
+    let verifier = payload
+        .code_verifier
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("code_verifier is required"))?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let grant = sqlx::query_as::<_, AuthorizationCode>(
+        "SELECT * FROM oauth_authorization_codes WHERE code_hash = $1 FOR UPDATE",
+    )
+    .bind(hash_token(code))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or_else(|| OAuthError::invalid_grant("Invalid authorization code"))?;
+
+    // A replayed code may have been stolen; the token issued for it goes too
+    if grant.used_at.is_some() {
+        if let (Some(jti), Some(exp)) = (&grant.access_token_jti, grant.access_token_expires_at) {
+            state
+                .revocations
+                .revoke_jti(&state.db, jti, grant.user_id, exp.timestamp())
+                .await?;
+        }
+        warn!(
+            "Authorization code replayed by client {}, issued token revoked",
+            grant.client_id
+        );
+        return Err(OAuthError::invalid_grant("Authorization code already used"));
+    }
+
+    if grant.client_id != client.client_id
+        || grant.expires_at <= chrono::Utc::now()
+        || payload.redirect_uri.as_deref() != Some(grant.redirect_uri.as_str())
+    {
+        return Err(OAuthError::invalid_grant("Invalid authorization code"));
+    }
+    if pkce_challenge(verifier) != grant.code_challenge {
+        return Err(OAuthError::invalid_grant("PKCE verification failed"));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(grant.user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    let now = chrono::Utc::now();
+    let expires_at = now + state.access_token_ttl;
+
+    // Client tokens carry the granted scopes but none of the user's roles
+    let claims = Claims {
+        sub: user.id,
+        exp: expires_at.timestamp() as usize,
+        iat: now.timestamp() as usize,
+        jti: uuid::Uuid::new_v4().to_string(),
+        sid: None,
+        act: None,
+        org_id: None,
+        roles: Vec::new(),
+        permissions: Vec::new(),
+        client_id: Some(client.client_id.clone()),
+        scope: Some(grant.scope.clone()),
+        aud: Some(CLIENT_TOKEN_AUDIENCE.to_string()),
+    };
+    let access_token = state.keys.sign(&claims)?;
+
+    let id_token = if has_scope(&grant.scope, "openid") {
+        let id_claims = IdTokenClaims {
+            iss: state.oidc_issuer.clone(),
+            sub: user.id.to_string(),
+            aud: client.client_id.clone(),
+            exp: expires_at.timestamp() as usize,
+            iat: now.timestamp() as usize,
+            auth_time: grant.auth_time.timestamp() as usize,
+            nonce: grant.nonce.clone(),
+            profile: scoped_claims(&user, &grant.scope),
+        };
+        Some(state.keys.sign(&id_claims)?)
+    } else {
+        None
+    };
+
+    sqlx::query(
+        r#"
+        UPDATE oauth_authorization_codes
+        SET used_at = NOW(), access_token_jti = $1, access_token_expires_at = $2
+        WHERE id = $3
+        "#,
+    )
+    .bind(&claims.jti)
+    .bind(expires_at)
+    .bind(grant.id)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    // Expired codes are no longer needed
+    sqlx::query(
+        "DELETE FROM oauth_authorization_codes WHERE expires_at < NOW() - INTERVAL '1 day'",
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: state.access_token_ttl.num_seconds(),
+            scope: grant.scope,
+            id_token,
+        }),
+    )
+        .into_response())
+}
+
+pub async fn userinfo(
+    State(state): State<Arc<AppState>>,
+    ClientAuthUser { claims }: ClientAuthUser,
+) -> Result<Json<UserInfo>, OAuthError> {
+    let scope = claims.scope.clone().unwrap_or_default();
+    if !has_scope(&scope, "openid") {
+        return Err(OAuthError::new(
+            StatusCode::FORBIDDEN,
+            "insufficient_scope",
+            "The access token lacks the openid scope",
+        ));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(claims.sub)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(UserInfo {
+        sub: user.id.to_string(),
+        profile: scoped_claims(&user, &scope),
+    }))
+}
+
+// RFC 7662: only confidential clients may ask, and any problem with the token
+// itself just makes it inactive
+pub async fn introspect(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Form(payload): Form<TokenParam>,
+) -> Result<Json<Introspection>, OAuthError> {
+    let client = authenticate_client(
+        &state,
+        &headers,
+        payload.client_id.as_deref(),
+        payload.client_secret.as_deref(),
+    )
+    .await?;
+    if !client.is_confidential() {
+        return Err(OAuthError::invalid_client());
+    }
+
+    let claims = match auth::decode_client_token(&state, &payload.token).await {
+        Ok(claims) => claims,
+        Err(_) => match auth::decode_token(&state, &payload.token).await {
+            Ok(claims) => claims,
+            Err(_) => return Ok(Json(Introspection::default())),
+        },
+    };
+
+    Ok(Json(Introspection {
+        active: true,
+        scope: claims.scope,
+        client_id: claims.client_id,
+        token_type: Some("Bearer"),
+        exp: Some(claims.exp),
+        iat: Some(claims.iat),
+        sub: Some(claims.sub.to_string()),
+        iss: Some(state.oidc_issuer.clone()),
+        jti: Some(claims.jti),
+    }))
+}
+
+// RFC 7009: a client may revoke the tokens issued to it. Unknown or foreign
+// tokens are ignored and still answered with 200.
+pub async fn revoke(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    client_info: ClientInfo,
+    Form(payload): Form<TokenParam>,
+) -> Result<StatusCode, OAuthError> {
+    let client = authenticate_client(
+        &state,
+        &headers,
+        payload.client_id.as_deref(),
+        payload.client_secret.as_deref(),
+    )
+    .await?;
+
+    if let Ok(claims) = auth::decode_client_token(&state, &payload.token).await {
+        if claims.client_id.as_deref() == Some(client.client_id.as_str()) {
+            state.revocations.revoke(&state.db, &claims).await?;
+            let event = AuditEvent::success("token_revoked")
+                .subject(claims.sub)
+                .client(&client_info)
+                .details(serde_json::json!({ "client_id": client.client_id, "jti": claims.jti }));
+            audit::record(&state, event).await;
+        }
+    }
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::views::tests::{assert_no_hash_material, user, PASSWORD_HASHES};
+
+    #[test]
+    fn userinfo_never_contains_password_hashes() {
+        for password_hash in PASSWORD_HASHES {
+            let user = user(password_hash);
+            let info = UserInfo {
+                sub: user.id.to_string(),
+                profile: scoped_claims(&user, "openid profile email"),
+            };
+            assert_no_hash_material(&info, password_hash);
+        }
+    }
+
+    #[test]
+    fn computes_s256_challenge() {
+        // Example from RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn appends_redirect_parameters() {
+        assert_eq!(
+            redirect_with(
+                "https://app.example.com/cb",
+                &[("code", "a b"), ("state", "x")]
+            ),
+            "https://app.example.com/cb?code=a%20b&state=x"
+        );
+        assert_eq!(
+            redirect_with("https://app.example.com/cb?tenant=1", &[("code", "c")]),
+            "https://app.example.com/cb?tenant=1&code=c"
+        );
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,851 @@
+use auth_api::authz::{Permission, RequirePermission};
+use auth_api::permission;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::account::require_own_login;
+use crate::audit::{self, AuditEvent};
+use crate::auth::KeyOrAuthUser;
+use crate::cookies::{self, SessionMode};
+use crate::mailer::Email;
+use crate::sessions::ClientInfo;
+use crate::tokens::{generate_opaque_token, hash_token, issue_refresh_token};
+use crate::{generate_token, AppError, AppState, AuthResponse, AuthUser, Claims, User};
+
+// Granted by organization roles, and only within the organization a token
+// acts in
+permission!(pub OrgsRead = "orgs:read");
+permission!(pub OrgsMembersWrite = "orgs:members:write");
+permission!(pub OrgsTeamsWrite = "orgs:teams:write");
+permission!(pub OrgsOwnersWrite = "orgs:owners:write");
+permission!(pub OrgsDelete = "orgs:delete");
+
+const ROLES: &[&str] = &["owner", "admin", "member"];
+
+const OWNER_PERMISSIONS: &[&str] = &[
+    OrgsRead::NAME,
+    OrgsMembersWrite::NAME,
+    OrgsTeamsWrite::NAME,
+    OrgsOwnersWrite::NAME,
+    OrgsDelete::NAME,
+];
+const ADMIN_PERMISSIONS: &[&str] = &[OrgsRead::NAME, OrgsMembersWrite::NAME, OrgsTeamsWrite::NAME];
+const MEMBER_PERMISSIONS: &[&str] = &[OrgsRead::NAME];
+
+const MAX_SLUG_LEN: usize = 64;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Organization {
+    id: i32,
+    name: String,
+    slug: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// An organization of the caller, with their role in it
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct OrgMembership {
+    id: i32,
+    name: String,
+    slug: String,
+    role: String,
+    joined_at: chrono::DateTime<chrono::Utc>,
+    // Whether the token making the request acts in it
+    active: bool,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Member {
+    user_id: i32,
+    username: String,
+    email: String,
+    role: String,
+    joined_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Invitation {
+    id: i32,
+    email: String,
+    role: String,
+    invited_by: Option<i32>,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOrgRequest {
+    name: String,
+    slug: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SwitchOrgRequest {
+    // None to act outside any organization
+    org_id: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetMemberRoleRequest {
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteRequest {
+    email: String,
+    #[serde(default = "default_role")]
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcceptInvitationRequest {
+    token: String,
+}
+
+fn default_role() -> String {
+    "member".to_string()
+}
+
+// What each organization role grants while its organization is active
+fn role_permissions(role: &str) -> &'static [&'static str] {
+    match role {
+        "owner" => OWNER_PERMISSIONS,
+        "admin" => ADMIN_PERMISSIONS,
+        "member" => MEMBER_PERMISSIONS,
+        _ => &[],
+    }
+}
+
+fn check_role(role: &str) -> Result<(), AppError> {
+    if !ROLES.contains(&role) {
+        return Err(AppError::ValidationError(format!(
+            "Unknown organization role `{}`, expected one of {}",
+            role,
+            ROLES.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+// Slugs name organizations in URLs: lowercase letters, digits and inner dashes
+fn check_slug(slug: &str) -> Result<(), AppError> {
+    let valid = !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+
+    if !valid {
+        return Err(AppError::ValidationError(
+            "Slugs use lowercase letters, digits and dashes".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// The organization a token acts in, required by every `/org` route
+pub fn current_org(claims: &Claims) -> Result<i32, AppError> {
+    claims.org_id.ok_or(AppError::ForbiddenError(
+        "No organization selected, switch to one first".to_string(),
+    ))
+}
+
+// Organization of the session and the permissions the user's role grants in
+// it. Leaving the organization drops it at the next refresh.
+pub async fn session_grants(
+    db: &PgPool,
+    user_id: i32,
+    session_id: Uuid,
+) -> Result<Option<(i32, &'static [&'static str])>, AppError> {
+    let membership = sqlx::query_as::<_, (i32, String)>(
+        r#"
+        SELECT m.org_id, m.role FROM sessions s
+        JOIN org_memberships m ON m.org_id = s.org_id AND m.user_id = s.user_id
+        WHERE s.id = $1 AND s.user_id = $2
+        "#,
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .fetch_optional(db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(membership.map(|(org_id, role)| (org_id, role_permissions(&role))))
+}
+
+// Join the organization the invitation is for, if it was sent to the user's
+// address. Members keep the role they already have.
+pub async fn accept_invitation(
+    conn: &mut PgConnection,
+    token: &str,
+    user: &User,
+) -> Result<i32, AppError> {
+    let invitation = sqlx::query_as::<_, (i32, String)>(
+        r#"
+        UPDATE org_invitations SET accepted_at = NOW()
+        WHERE token_hash = $1 AND accepted_at IS NULL AND expires_at > NOW()
+            AND LOWER(email) = LOWER($2)
+        RETURNING org_id, role
+        "#,
+    )
+    .bind(hash_token(token))
+    .bind(&user.email)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    let Some((org_id, role)) = invitation else {
+        return Err(AppError::ValidationError(
+            "Invalid or expired invitation".to_string(),
+        ));
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO org_memberships (org_id, user_id, role) VALUES ($1, $2, $3)
+        ON CONFLICT (org_id, user_id) DO NOTHING
+        "#,
+    )
+    .bind(org_id)
+    .bind(user.id)
+    .bind(&role)
+    .execute(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(org_id)
+}
+
+async fn fetch_org(db: &PgPool, org_id: i32) -> Result<Organization, AppError> {
+    sqlx::query_as::<_, Organization>("SELECT * FROM organizations WHERE id = $1")
+        .bind(org_id)
+        .fetch_optional(db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .ok_or(AppError::ValidationError(
+            "Unknown organization".to_string(),
+        ))
+}
+
+// Role of a member, locking every owner row so two owners can't demote each
+// other at once and leave nobody in charge
+async fn lock_member(
+    conn: &mut PgConnection,
+    org_id: i32,
+    user_id: i32,
+) -> Result<(String, i64), AppError> {
+    let owners = sqlx::query_scalar::<_, i32>(
+        "SELECT user_id FROM org_memberships WHERE org_id = $1 AND role = 'owner' FOR UPDATE",
+    )
+    .bind(org_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    let role = sqlx::query_scalar::<_, String>(
+        "SELECT role FROM org_memberships WHERE org_id = $1 AND user_id = $2 FOR UPDATE",
+    )
+    .bind(org_id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::ValidationError(
+        "Not a member of the organization".to_string(),
+    ))?;
+
+    Ok((role, owners.len() as i64))
+}
+
+// Remove a member, keeping at least one owner. Owners can only be removed by
+// callers allowed to manage owners.
+async fn remove_membership(
+    state: &AppState,
+    org_id: i32,
+    user_id: i32,
+    may_remove_owner: bool,
+) -> Result<String, AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let (role, owners) = lock_member(&mut tx, org_id, user_id).await?;
+    if role == "owner" {
+        if !may_remove_owner {
+            return Err(AppError::ForbiddenError(format!(
+                "Removing an owner requires `{}`",
+                OrgsOwnersWrite::NAME
+            )));
+        }
+        if owners <= 1 {
+            return Err(AppError::ValidationError(
+                "An organization needs at least one owner".to_string(),
+            ));
+        }
+    }
+
+    sqlx::query("DELETE FROM org_memberships WHERE org_id = $1 AND user_id = $2")
+        .bind(org_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    // Their tokens still name the organization; refreshing drops it
+    state.revocations.revoke_all(&state.db, user_id).await?;
+
+    Ok(role)
+}
+
+async fn send_invitation_email(
+    state: &AppState,
+    org: &Organization,
+    invited_by: &str,
+    email: &str,
+    token: &str,
+) -> Result<(), AppError> {
+    let link = format!("{}/accept-invitation?token={}", state.app_url, token);
+    let email = Email {
+        to: email.to_string(),
+        subject: format!("Join {}", org.name),
+        body: format!(
+            "Hi,\n\n{} invited you to join {}. Accept the invitation here, or create an account with this address:\n\n{}\n\nThe invitation expires in {} days.\n",
+            invited_by,
+            org.name,
+            link,
+            state.invitation_ttl.num_days()
+        ),
+    };
+
+    state
+        .mailer
+        .send(email)
+        .await
+        .map_err(|e| AppError::ValidationError(e.to_string()))
+}
+
+// Handler functions
+pub async fn create_org(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<CreateOrgRequest>,
+) -> Result<(StatusCode, Json<Organization>), AppError> {
+    require_own_login(&claims)?;
+    check_slug(&payload.slug)?;
+    if payload.name.trim().is_empty() {
+        return Err(AppError::ValidationError(
+            "Organization name must not be empty".to_string(),
+        ));
+    }
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let org = sqlx::query_as::<_, Organization>(
+        r#"
+        INSERT INTO organizations (name, slug) VALUES ($1, $2)
+        ON CONFLICT (slug) DO NOTHING
+        RETURNING *
+        "#,
+    )
+    .bind(payload.name.trim())
+    .bind(&payload.slug)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::ValidationError(format!(
+        "Slug `{}` is already taken",
+        payload.slug
+    )))?;
+
+    // Whoever creates the organization owns it
+    sqlx::query("INSERT INTO org_memberships (org_id, user_id, role) VALUES ($1, $2, 'owner')")
+        .bind(org.id)
+        .bind(claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    let event = AuditEvent::success("org_created")
+        .by(&claims)
+        .subject(claims.sub)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org.id, "slug": org.slug }));
+    audit::record(&state, event).await;
+
+    Ok((StatusCode::CREATED, Json(org)))
+}
+
+pub async fn list_orgs(
+    State(state): State<Arc<AppState>>,
+    KeyOrAuthUser { claims }: KeyOrAuthUser,
+) -> Result<Json<Vec<OrgMembership>>, AppError> {
+    let orgs = sqlx::query_as::<_, OrgMembership>(
+        r#"
+        SELECT o.id, o.name, o.slug, m.role, m.joined_at,
+            COALESCE(o.id = $2, FALSE) AS active
+        FROM org_memberships m
+        JOIN organizations o ON o.id = m.org_id
+        WHERE m.user_id = $1
+        ORDER BY o.name
+        "#,
+    )
+    .bind(claims.sub)
+    .bind(claims.org_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(orgs))
+}
+
+// Move the session into another organization. Its refresh tokens are replaced
+// and the presented access token revoked, so none of them keeps the old one.
+pub async fn switch_org(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    mode: SessionMode,
+    Json(payload): Json<SwitchOrgRequest>,
+) -> Result<Response, AppError> {
+    require_own_login(&claims)?;
+    let session_id = claims
+        .sid
+        .as_deref()
+        .and_then(|sid| Uuid::parse_str(sid).ok())
+        .ok_or(AppError::ValidationError(
+            "Switching organizations needs a login session".to_string(),
+        ))?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    if let Some(org_id) = payload.org_id {
+        let member = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM org_memberships WHERE org_id = $1 AND user_id = $2)",
+        )
+        .bind(org_id)
+        .bind(claims.sub)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+        if !member {
+            return Err(AppError::ForbiddenError(
+                "Not a member of this organization".to_string(),
+            ));
+        }
+    }
+
+    let switched = sqlx::query(
+        "UPDATE sessions SET org_id = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL",
+    )
+    .bind(payload.org_id)
+    .bind(session_id)
+    .bind(claims.sub)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+    if switched == 0 {
+        return Err(AppError::AuthError("Session has ended".to_string()));
+    }
+
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = NOW() WHERE session_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(session_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    let (_, refresh_token) =
+        issue_refresh_token(&mut tx, &state, claims.sub, None, Some(session_id)).await?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(claims.sub)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    state.revocations.revoke(&state.db, &claims).await?;
+    let token = generate_token(&state, user.id, Some(session_id)).await?;
+
+    let event = AuditEvent::success("org_switched")
+        .by(&claims)
+        .subject(claims.sub)
+        .client(&client)
+        .details(serde_json::json!({ "from": claims.org_id, "to": payload.org_id }));
+    audit::record(&state, event).await;
+
+    let response = AuthResponse {
+        token,
+        refresh_token,
+        expires_in: state.access_token_ttl.num_seconds(),
+        user: user.into(),
+    };
+
+    Ok(cookies::respond(&state, mode, response))
+}
+
+pub async fn accept_invitation_handler(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<AcceptInvitationRequest>,
+) -> Result<Json<Organization>, AppError> {
+    require_own_login(&claims)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(claims.sub)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+    let org_id = accept_invitation(&mut tx, &payload.token, &user).await?;
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    let event = AuditEvent::success("org_invitation_accepted")
+        .by(&claims)
+        .subject(claims.sub)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id }));
+    audit::record(&state, event).await;
+
+    Ok(Json(fetch_org(&state.db, org_id).await?))
+}
+
+pub async fn get_org(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsRead>,
+) -> Result<Json<Organization>, AppError> {
+    let org_id = current_org(&claims)?;
+
+    Ok(Json(fetch_org(&state.db, org_id).await?))
+}
+
+pub async fn delete_org(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsDelete>,
+    client: ClientInfo,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let members = sqlx::query_scalar::<_, i32>(
+        r#"
+        WITH deleted AS (DELETE FROM organizations WHERE id = $1 RETURNING id)
+        SELECT m.user_id FROM org_memberships m JOIN deleted d ON d.id = m.org_id
+        "#,
+    )
+    .bind(org_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    for user_id in &members {
+        if let Err(e) = state.revocations.revoke_all(&state.db, *user_id).await {
+            warn!("Failed to revoke tokens of user {}: {:?}", user_id, e);
+        }
+    }
+
+    let event = AuditEvent::success("org_deleted")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "members": members.len() }));
+    audit::record(&state, event).await;
+
+    info!("Organization {} deleted by user {}", org_id, claims.sub);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_members(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsRead>,
+) -> Result<Json<Vec<Member>>, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let members = sqlx::query_as::<_, Member>(
+        r#"
+        SELECT u.id AS user_id, u.username, u.email, m.role, m.joined_at
+        FROM org_memberships m
+        JOIN users u ON u.id = m.user_id
+        WHERE m.org_id = $1
+        ORDER BY u.username
+        "#,
+    )
+    .bind(org_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(members))
+}
+
+pub async fn set_member_role(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsMembersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<SetMemberRoleRequest>,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+    check_role(&payload.role)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let (role, owners) = lock_member(&mut tx, org_id, user_id).await?;
+    if role == payload.role {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    if (role == "owner" || payload.role == "owner") && !claims.has_permission(OrgsOwnersWrite::NAME)
+    {
+        return Err(AppError::ForbiddenError(format!(
+            "Granting or taking the owner role requires `{}`",
+            OrgsOwnersWrite::NAME
+        )));
+    }
+    if role == "owner" && owners <= 1 {
+        return Err(AppError::ValidationError(
+            "An organization needs at least one owner".to_string(),
+        ));
+    }
+
+    sqlx::query("UPDATE org_memberships SET role = $1 WHERE org_id = $2 AND user_id = $3")
+        .bind(&payload.role)
+        .bind(org_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    // Tokens carry the permissions of the old role until refreshed
+    state.revocations.revoke_all(&state.db, user_id).await?;
+
+    let event = AuditEvent::success("org_member_role_changed")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "from": role, "to": payload.role }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsMembersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+    let may_remove_owner = claims.has_permission(OrgsOwnersWrite::NAME);
+    let role = remove_membership(&state, org_id, user_id, may_remove_owner).await?;
+
+    let event = AuditEvent::success("org_member_removed")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "role": role }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Any member may leave, except the last owner
+pub async fn leave_org(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+) -> Result<StatusCode, AppError> {
+    require_own_login(&claims)?;
+    let org_id = current_org(&claims)?;
+    let role = remove_membership(&state, org_id, claims.sub, true).await?;
+
+    let event = AuditEvent::success("org_member_removed")
+        .by(&claims)
+        .subject(claims.sub)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "role": role }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_invitations(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsMembersWrite>,
+) -> Result<Json<Vec<Invitation>>, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let invitations = sqlx::query_as::<_, Invitation>(
+        r#"
+        SELECT id, email, role, invited_by, expires_at, created_at FROM org_invitations
+        WHERE org_id = $1 AND accepted_at IS NULL AND expires_at > NOW()
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(org_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(invitations))
+}
+
+pub async fn invite(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsMembersWrite>,
+    client: ClientInfo,
+    Json(payload): Json<InviteRequest>,
+) -> Result<(StatusCode, Json<Invitation>), AppError> {
+    let org_id = current_org(&claims)?;
+    check_role(&payload.role)?;
+    let email = payload.email.trim();
+    if !email.contains('@') {
+        return Err(AppError::ValidationError(
+            "Invalid email address".to_string(),
+        ));
+    }
+    if payload.role == "owner" && !claims.has_permission(OrgsOwnersWrite::NAME) {
+        return Err(AppError::ForbiddenError(format!(
+            "Inviting an owner requires `{}`",
+            OrgsOwnersWrite::NAME
+        )));
+    }
+
+    let member = sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM org_memberships m JOIN users u ON u.id = m.user_id
+            WHERE m.org_id = $1 AND LOWER(u.email) = LOWER($2)
+        )
+        "#,
+    )
+    .bind(org_id)
+    .bind(email)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    if member {
+        return Err(AppError::ValidationError(
+            "Already a member of the organization".to_string(),
+        ));
+    }
+
+    let org = fetch_org(&state.db, org_id).await?;
+    let inviter = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = $1")
+        .bind(claims.sub)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    let token = generate_opaque_token();
+    let invitation = sqlx::query_as::<_, Invitation>(
+        r#"
+        INSERT INTO org_invitations (org_id, email, role, token_hash, invited_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, email, role, invited_by, expires_at, created_at
+        "#,
+    )
+    .bind(org_id)
+    .bind(email)
+    .bind(&payload.role)
+    .bind(hash_token(&token))
+    .bind(claims.sub)
+    .bind(chrono::Utc::now() + state.invitation_ttl)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    send_invitation_email(&state, &org, &inviter, email, &token).await?;
+
+    let event = AuditEvent::success("org_member_invited")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({
+            "org_id": org_id,
+            "invitation_id": invitation.id,
+            "email": invitation.email,
+            "role": invitation.role,
+        }));
+    audit::record(&state, event).await;
+
+    Ok((StatusCode::CREATED, Json(invitation)))
+}
+
+pub async fn revoke_invitation(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsMembersWrite>,
+    client: ClientInfo,
+    Path(invitation_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let deleted = sqlx::query(
+        "DELETE FROM org_invitations WHERE id = $1 AND org_id = $2 AND accepted_at IS NULL",
+    )
+    .bind(invitation_id)
+    .bind(org_id)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(AppError::ValidationError("Unknown invitation".to_string()));
+    }
+
+    let event = AuditEvent::success("org_invitation_revoked")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "invitation_id": invitation_id }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_grant_nested_permissions() {
+        let owner = role_permissions("owner");
+        let admin = role_permissions("admin");
+        let member = role_permissions("member");
+
+        assert!(member.iter().all(|p| admin.contains(p)));
+        assert!(admin.iter().all(|p| owner.contains(p)));
+        assert!(!admin.contains(&OrgsOwnersWrite::NAME));
+        assert!(!admin.contains(&OrgsDelete::NAME));
+        assert!(role_permissions("superuser").is_empty());
+        assert!(ROLES.iter().all(|role| !role_permissions(role).is_empty()));
+    }
+
+    #[test]
+    fn validates_slugs() {
+        assert!(check_slug("acme").is_ok());
+        assert!(check_slug("acme-42").is_ok());
+        assert!(check_slug("").is_err());
+        assert!(check_slug("-acme").is_err());
+        assert!(check_slug("acme-").is_err());
+        assert!(check_slug("Acme").is_err());
+        assert!(check_slug("acme corp").is_err());
+        assert!(check_slug(&"a".repeat(MAX_SLUG_LEN + 1)).is_err());
+    }
+}
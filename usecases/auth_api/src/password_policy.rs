@@ -0,0 +1,276 @@
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::AppError;
+
+// Longer inputs only make hashing slower; bcrypt ignores bytes past 72 anyway
+const MAX_LENGTH: usize = 128;
+
+// Length of the SHA-1 prefix breached hashes are grouped by, as in the
+// Pwned Passwords range API
+const PREFIX_LEN: usize = 5;
+
+// Words and keyboard walks tried first by any guessing attack
+const COMMON_WORDS: &[&str] = &[
+    "password", "passwort", "qwerty", "azerty", "asdf", "zxcv", "letmein", "welcome", "admin",
+    "login", "monkey", "dragon", "master", "shadow", "sunshine", "princess", "football",
+    "baseball", "iloveyou", "trustno1", "superman", "batman", "secret", "abc123", "hello",
+    "freedom", "whatever", "starwars", "computer", "summer", "winter", "spring", "autumn",
+];
+
+// Known breached passwords, stored as uppercase SHA-1 hashes split into a
+// prefix and the rest, so only a prefix is ever needed to look one up
+pub enum BreachedPasswords {
+    // One file of `HASH[:COUNT]` lines, loaded into memory
+    Loaded(HashMap<String, HashSet<String>>),
+    // A directory of `<PREFIX>.txt` files holding `SUFFIX[:COUNT]` lines, as
+    // written by the Pwned Passwords downloader; files are read on lookup
+    Directory(PathBuf),
+}
+
+impl BreachedPasswords {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        if path.is_dir() {
+            return Ok(BreachedPasswords::Directory(path));
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut hashes: HashMap<String, HashSet<String>> = HashMap::new();
+        for line in contents.lines() {
+            let hash = line.split(':').next().unwrap_or_default().trim();
+            if hash.len() != 40 {
+                continue;
+            }
+            let hash = hash.to_ascii_uppercase();
+            let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+            hashes
+                .entry(prefix.to_string())
+                .or_default()
+                .insert(suffix.to_string());
+        }
+        info!(
+            "Loaded {} breached password hashes",
+            hashes.values().map(HashSet::len).sum::<usize>()
+        );
+
+        Ok(BreachedPasswords::Loaded(hashes))
+    }
+
+    pub async fn contains(&self, password: &str) -> Result<bool, AppError> {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+
+        match self {
+            BreachedPasswords::Loaded(hashes) => {
+                Ok(hashes.get(prefix).is_some_and(|s| s.contains(suffix)))
+            }
+            BreachedPasswords::Directory(dir) => {
+                let file = dir.join(format!("{}.txt", prefix));
+                let contents = match tokio::fs::read_to_string(&file).await {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                    Err(e) => return Err(AppError::ValidationError(e.to_string())),
+                };
+
+                Ok(contents.lines().any(|line| {
+                    line.split(':')
+                        .next()
+                        .is_some_and(|s| s.trim().eq_ignore_ascii_case(suffix))
+                }))
+            }
+        }
+    }
+}
+
+// Rough strength estimate from 0 (trivial) to 4 (very strong), in the spirit
+// of zxcvbn: the entropy of the character set, counting repeats, runs like
+// `abcd` or `4321` and common words for much less than random characters
+pub fn strength_score(password: &str) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+
+    let mut charset = 0u32;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        charset += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        charset += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        charset += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        charset += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        charset += 100;
+    }
+    if charset == 0 {
+        return 0;
+    }
+
+    // Common words count as a single guess from a short list
+    let mut lowered = password.to_lowercase();
+    let mut word_bits = 0.0;
+    for word in COMMON_WORDS {
+        while let Some(at) = lowered.find(word) {
+            lowered.replace_range(at..at + word.len(), "\u{0}");
+            word_bits += (COMMON_WORDS.len() as f64).log2();
+        }
+    }
+
+    // Repeated characters and runs add little
+    let rest: Vec<char> = lowered.chars().filter(|c| *c != '\u{0}').collect();
+    let mut effective = 0.0;
+    for (i, c) in rest.iter().enumerate() {
+        let step = i
+            .checked_sub(1)
+            .map(|p| *c as i64 - rest[p] as i64)
+            .unwrap_or(i64::MAX);
+        let previous_step = i
+            .checked_sub(2)
+            .map(|p| rest[p + 1] as i64 - rest[p] as i64);
+        let predictable = step == 0 || (step.abs() == 1 && previous_step.is_none_or(|s| s == step));
+        effective += if predictable { 0.25 } else { 1.0 };
+    }
+
+    let bits = effective * (charset as f64).log2() + word_bits;
+    match bits {
+        b if b < 25.0 => 0,
+        b if b < 35.0 => 1,
+        b if b < 50.0 => 2,
+        b if b < 65.0 => 3,
+        _ => 4,
+    }
+}
+
+pub struct PasswordPolicy {
+    min_length: usize,
+    min_score: u8,
+    breached: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    pub fn new(min_length: usize, min_score: u8, breached: Option<BreachedPasswords>) -> Self {
+        PasswordPolicy {
+            min_length,
+            min_score,
+            breached,
+        }
+    }
+
+    // Read `PASSWORD_MIN_LENGTH`, `PASSWORD_MIN_SCORE` (0 to 4) and
+    // `BREACHED_PASSWORDS_PATH`
+    pub fn from_env() -> Result<Self, String> {
+        let breached = std::env::var("BREACHED_PASSWORDS_PATH")
+            .ok()
+            .map(BreachedPasswords::load)
+            .transpose()?;
+
+        Ok(PasswordPolicy::new(
+            crate::env_or("PASSWORD_MIN_LENGTH", 10) as usize,
+            crate::env_or("PASSWORD_MIN_SCORE", 3).clamp(0, 4) as u8,
+            breached,
+        ))
+    }
+
+    // Check a new password for the account `username` / `email`, reporting
+    // every problem at once
+    pub async fn check(&self, password: &str, username: &str, email: &str) -> Result<(), AppError> {
+        let mut problems = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            problems.push(format!(
+                "Password must be at least {} characters",
+                self.min_length
+            ));
+        }
+        if length > MAX_LENGTH {
+            problems.push(format!(
+                "Password must be at most {} characters",
+                MAX_LENGTH
+            ));
+        }
+
+        let lowered = password.to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default();
+        let personal = [username, local_part, email]
+            .iter()
+            .map(|s| s.to_lowercase())
+            .any(|s| s.chars().count() >= 3 && lowered.contains(&s));
+        if personal {
+            problems.push("Password must not contain your username or email".to_string());
+        }
+
+        if strength_score(password) < self.min_score {
+            problems.push("Password is too easy to guess".to_string());
+        }
+
+        if let Some(breached) = &self.breached {
+            if breached.contains(password).await? {
+                problems.push(
+                    "Password appears in a known data breach, choose another one".to_string(),
+                );
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::ValidationError(problems.join("; ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_weak_passwords_low() {
+        assert_eq!(strength_score(""), 0);
+        assert_eq!(strength_score("aaaaaaaaaaaa"), 0);
+        assert_eq!(strength_score("abcdefghijkl"), 0);
+        assert_eq!(strength_score("123456789"), 0);
+        assert!(strength_score("Password1!") <= 1);
+        assert!(strength_score("qwertyuiop") <= 1);
+    }
+
+    #[test]
+    fn scores_strong_passwords_high() {
+        assert!(strength_score("correct horse battery staple") >= 3);
+        assert!(strength_score("x7#Kq!2mZr9@") >= 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_personal_and_breached_passwords() {
+        let hash = hex::encode_upper(Sha1::digest(b"hunter2-but-longer"));
+        let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+        let breached = BreachedPasswords::Loaded(HashMap::from([(
+            prefix.to_string(),
+            HashSet::from([suffix.to_string()]),
+        )]));
+        let policy = PasswordPolicy::new(10, 0, Some(breached));
+
+        assert!(policy
+            .check("hunter2-but-longer", "alice", "alice@example.com")
+            .await
+            .is_err());
+        assert!(policy
+            .check("xx-Alice-xx-2024", "alice", "alice@example.com")
+            .await
+            .is_err());
+        assert!(policy
+            .check("short", "alice", "alice@example.com")
+            .await
+            .is_err());
+        assert!(policy
+            .check("violet tractor marmalade", "alice", "alice@example.com")
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::account::require_own_login;
+use crate::audit::{self, AuditEvent};
+use crate::mailer::Email;
+use crate::sessions::{self, ClientInfo};
+use crate::tokens::{generate_opaque_token, hash_token, revoke_user_refresh_tokens};
+use crate::{api_keys, login};
+use crate::{AppError, AppState, AuthUser, User};
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+// Create a reset token for the account behind `email`, if any, and mail it
+async fn send_reset_email(
+    state: &AppState,
+    email: &str,
+    client: &ClientInfo,
+) -> Result<(), AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    let Some(user) = user else {
+        return Ok(());
+    };
+
+    audit::record(
+        state,
+        AuditEvent::success("password_reset_requested")
+            .subject(user.id)
+            .client(client),
+    )
+    .await;
+
+    send_reset_link(
+        state,
+        &user,
+        "Someone asked to reset your password. If this wasn't you, ignore this email.",
+    )
+    .await
+}
+
+// Create a reset token for the user and mail them a link, explaining why with
+// `reason`
+pub async fn send_reset_link(state: &AppState, user: &User, reason: &str) -> Result<(), AppError> {
+    let token = generate_opaque_token();
+    sqlx::query(
+        "INSERT INTO password_reset_tokens (user_id, token_hash, expires_at) VALUES ($1, $2, $3)",
+    )
+    .bind(user.id)
+    .bind(hash_token(&token))
+    .bind(chrono::Utc::now() + state.password_reset_ttl)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    let link = format!("{}/reset-password?token={}", state.app_url, token);
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\n{} Choose a new password here:\n\n{}\n\nThe link expires in {} minutes.\n",
+            user.username,
+            reason,
+            link,
+            state.password_reset_ttl.num_minutes()
+        ),
+    };
+
+    state
+        .mailer
+        .send(email)
+        .await
+        .map_err(|e| AppError::ValidationError(e.to_string()))
+}
+
+// Handler functions
+
+// The lookup and delivery run in the background and the answer is always 202,
+// so neither the body nor the timing tells whether the email is registered
+pub async fn forgot_password(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> StatusCode {
+    tokio::spawn(async move {
+        if let Err(e) = send_reset_email(&state, &payload.email, &client).await {
+            warn!("Failed to send password reset email: {:?}", e);
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+pub async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let user_id = sqlx::query_scalar::<_, i32>(
+        r#"
+        UPDATE password_reset_tokens SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id
+        "#,
+    )
+    .bind(hash_token(&payload.token))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+    let Some(user_id) = user_id else {
+        let event = AuditEvent::failure("password_reset")
+            .client(&client)
+            .details(serde_json::json!({ "reason": "invalid_token" }));
+        audit::record(&state, event).await;
+        return Err(AppError::AuthError(
+            "Invalid or expired reset token".to_string(),
+        ));
+    };
+
+    // A rejected password rolls back, so the token can be used again
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+    state
+        .password_policy
+        .check(&payload.new_password, &user.username, &user.email)
+        .await?;
+    let password_hash = state.password_hasher.hash(&payload.new_password)?;
+
+    sqlx::query(
+        "UPDATE users SET password_hash = $1, password_reset_required = FALSE WHERE id = $2",
+    )
+    .bind(&password_hash)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    // Any other link still in flight is now stale
+    sqlx::query(
+        "UPDATE password_reset_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    // Whoever knew the old password must not stay logged in
+    revoke_user_refresh_tokens(&mut tx, user_id).await?;
+    api_keys::delete_user_keys(&mut tx, user_id).await?;
+    sessions::end_user_sessions(&mut tx, user_id).await?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    state.revocations.revoke_all(&state.db, user_id).await?;
+
+    audit::record(
+        &state,
+        AuditEvent::success("password_reset")
+            .user(user_id)
+            .client(&client),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn change_password(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    // An impersonation must not outlive its token by changing the password
+    require_own_login(&claims)?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(claims.sub)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    // Guesses of the current password count towards the login lockout, so a
+    // stolen access token doesn't allow unlimited tries
+    let attempt = state
+        .login_throttle
+        .begin(&user.username, addr.ip())
+        .await?;
+
+    if !state
+        .password_hasher
+        .verify(&payload.current_password, &user.password_hash)?
+    {
+        login::record_failure(&state, attempt, Some(&user), &client).await;
+        let event = AuditEvent::failure("password_change")
+            .by(&claims)
+            .subject(user.id)
+            .client(&client)
+            .details(serde_json::json!({ "reason": "invalid_credentials" }));
+        audit::record(&state, event).await;
+        return Err(AppError::AuthError("Invalid credentials".to_string()));
+    }
+    state.login_throttle.record_success(attempt).await?;
+
+    state
+        .password_policy
+        .check(&payload.new_password, &user.username, &user.email)
+        .await?;
+    let password_hash = state.password_hasher.hash(&payload.new_password)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+        .bind(&password_hash)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    // Only the session changing the password stays logged in
+    let current = claims
+        .sid
+        .as_deref()
+        .and_then(|sid| Uuid::parse_str(sid).ok());
+    let ended = sessions::end_other_sessions(&mut tx, user.id, current).await?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    for session_id in ended {
+        state.revocations.forget_session(session_id);
+    }
+
+    audit::record(
+        &state,
+        AuditEvent::success("password_change")
+            .by(&claims)
+            .subject(user.id)
+            .client(&client),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,256 @@
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::{AppError, Claims};
+
+// Server-side revocation list for access tokens.
+//
+// Individual tokens are revoked by `jti`; "logout everywhere" stores a per-user
+// cutoff and rejects every token issued before it. `iat` only has whole seconds,
+// so tokens from the second of the cutoff stay valid: a client refreshing right
+// after the cutoff must not be locked out. Postgres is the source
+// of truth and an in-memory cache sits in front of it:
+// - revocations are cached until the token would have expired anyway
+// - "not revoked" answers, live sessions and user cutoffs are cached for `ttl`,
+//   so a revocation made by another instance takes at most `ttl` to be seen here
+//
+// Tokens carrying a `sid` are also rejected once their session has ended, and
+// tokens of deleted users once the deletion is noticed.
+pub struct RevocationList {
+    ttl: Duration,
+    revoked: Mutex<HashMap<String, i64>>,
+    not_revoked: Mutex<HashMap<String, Instant>>,
+    cutoffs: Mutex<HashMap<i32, (Option<i64>, Instant)>>,
+    live_sessions: Mutex<HashMap<Uuid, Instant>>,
+}
+
+impl RevocationList {
+    pub fn new(ttl: Duration) -> Self {
+        RevocationList {
+            ttl,
+            revoked: Mutex::new(HashMap::new()),
+            not_revoked: Mutex::new(HashMap::new()),
+            cutoffs: Mutex::new(HashMap::new()),
+            live_sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn is_revoked(&self, db: &PgPool, claims: &Claims) -> Result<bool, AppError> {
+        if let Some(cutoff) = self.cutoff(db, claims.sub).await? {
+            if issued_before(claims.iat, cutoff) {
+                return Ok(true);
+            }
+        }
+
+        if let Some(sid) = &claims.sid {
+            let sid = Uuid::parse_str(sid)
+                .map_err(|_| AppError::AuthError("Invalid token".to_string()))?;
+            if !self.session_live(db, sid).await? {
+                return Ok(true);
+            }
+        }
+
+        self.is_jti_revoked(db, &claims.jti, claims.exp as i64)
+            .await
+    }
+
+    // Whether the token with this id was revoked, e.g. a used MFA challenge
+    pub async fn is_jti_revoked(&self, db: &PgPool, jti: &str, exp: i64) -> Result<bool, AppError> {
+        if self.revoked.lock().unwrap().contains_key(jti) {
+            return Ok(true);
+        }
+        if let Some(checked) = self.not_revoked.lock().unwrap().get(jti) {
+            if checked.elapsed() < self.ttl {
+                return Ok(false);
+            }
+        }
+
+        let revoked = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1)",
+        )
+        .bind(jti)
+        .fetch_one(db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        if revoked {
+            self.revoked.lock().unwrap().insert(jti.to_string(), exp);
+        } else {
+            let mut not_revoked = self.not_revoked.lock().unwrap();
+            not_revoked.retain(|_, checked| checked.elapsed() < self.ttl);
+            not_revoked.insert(jti.to_string(), Instant::now());
+        }
+
+        Ok(revoked)
+    }
+
+    // Revoke a single access token
+    pub async fn revoke(&self, db: &PgPool, claims: &Claims) -> Result<(), AppError> {
+        self.revoke_jti(db, &claims.jti, claims.sub, claims.exp as i64)
+            .await?;
+        Ok(())
+    }
+
+    // Revoke a token known only by its id, e.g. one issued for a replayed code.
+    // Returns false when it was already revoked.
+    pub async fn revoke_jti(
+        &self,
+        db: &PgPool,
+        jti: &str,
+        user_id: i32,
+        exp: i64,
+    ) -> Result<bool, AppError> {
+        let expires_at = chrono::DateTime::from_timestamp(exp, 0)
+            .ok_or(AppError::ValidationError("Invalid expiry".to_string()))?;
+
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, user_id, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(jti)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .rows_affected();
+
+        // Entries for tokens that expired are no longer needed
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+            .execute(db)
+            .await
+            .map_err(AppError::DatabaseError)?;
+
+        let now = chrono::Utc::now().timestamp();
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.retain(|_, exp| *exp > now);
+        revoked.insert(jti.to_string(), exp);
+        self.not_revoked.lock().unwrap().remove(jti);
+
+        Ok(inserted > 0)
+    }
+
+    // Reject every access token issued to the user so far
+    pub async fn revoke_all(&self, db: &PgPool, user_id: i32) -> Result<(), AppError> {
+        let cutoff = sqlx::query_scalar::<_, chrono::DateTime<chrono::Utc>>(
+            "UPDATE users SET tokens_revoked_before = NOW() WHERE id = $1 RETURNING tokens_revoked_before",
+        )
+        .bind(user_id)
+        .fetch_one(db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        self.cutoffs
+            .lock()
+            .unwrap()
+            .insert(user_id, (Some(cutoff.timestamp()), Instant::now()));
+
+        Ok(())
+    }
+
+    // Drop the cached cutoff of a user that was just deleted, so their tokens
+    // are rejected right away
+    pub fn forget_user(&self, user_id: i32) {
+        self.cutoffs.lock().unwrap().remove(&user_id);
+    }
+
+    // Drop the cached answer for a session that was just ended
+    pub fn forget_session(&self, session_id: Uuid) {
+        self.live_sessions.lock().unwrap().remove(&session_id);
+    }
+
+    async fn session_live(&self, db: &PgPool, session_id: Uuid) -> Result<bool, AppError> {
+        if let Some(checked) = self.live_sessions.lock().unwrap().get(&session_id) {
+            if checked.elapsed() < self.ttl {
+                return Ok(true);
+            }
+        }
+
+        let live = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = $1 AND revoked_at IS NULL)",
+        )
+        .bind(session_id)
+        .fetch_one(db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        if live {
+            let mut live_sessions = self.live_sessions.lock().unwrap();
+            live_sessions.retain(|_, checked| checked.elapsed() < self.ttl);
+            live_sessions.insert(session_id, Instant::now());
+        }
+
+        Ok(live)
+    }
+
+    async fn cutoff(&self, db: &PgPool, user_id: i32) -> Result<Option<i64>, AppError> {
+        if let Some((cutoff, fetched)) = self.cutoffs.lock().unwrap().get(&user_id) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(*cutoff);
+            }
+        }
+
+        let cutoff = sqlx::query_scalar::<_, Option<chrono::DateTime<chrono::Utc>>>(
+            "SELECT tokens_revoked_before FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+        // Every token of a deleted user is revoked
+        let cutoff = match cutoff {
+            Some(cutoff) => cutoff.map(|cutoff| cutoff.timestamp()),
+            None => Some(i64::MAX),
+        };
+
+        let mut cutoffs = self.cutoffs.lock().unwrap();
+        cutoffs.retain(|_, (_, fetched)| fetched.elapsed() < self.ttl);
+        cutoffs.insert(user_id, (cutoff, Instant::now()));
+
+        Ok(cutoff)
+    }
+}
+
+// Whether a token issued at `iat` predates the cutoff. The cutoff is rounded
+// down to whole seconds, like `iat`, and tokens from that second still pass.
+fn issued_before(iat: usize, cutoff: i64) -> bool {
+    (iat as i64) < cutoff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_from_the_cutoff_second_stay_valid() {
+        let cutoff = chrono::DateTime::parse_from_rfc3339("2024-03-20T10:00:00.400Z").unwrap();
+        let refreshed = chrono::DateTime::parse_from_rfc3339("2024-03-20T10:00:00.900Z").unwrap();
+
+        assert!(!issued_before(
+            refreshed.timestamp() as usize,
+            cutoff.timestamp()
+        ));
+        assert!(!issued_before(
+            cutoff.timestamp() as usize + 1,
+            cutoff.timestamp()
+        ));
+        assert!(issued_before(
+            cutoff.timestamp() as usize - 1,
+            cutoff.timestamp()
+        ));
+    }
+
+    #[test]
+    fn deleted_users_have_no_valid_tokens() {
+        assert!(issued_before(
+            chrono::Utc::now().timestamp() as usize,
+            i64::MAX
+        ));
+    }
+}
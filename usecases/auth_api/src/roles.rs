@@ -0,0 +1,454 @@
+use auth_api::authz::RequirePermission;
+use auth_api::permission;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::audit::{self, AuditEvent};
+use crate::sessions::ClientInfo;
+use crate::{AppError, AppState, Claims};
+
+permission!(pub RolesRead = "roles:read");
+permission!(pub RolesWrite = "roles:write");
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Role {
+    id: i32,
+    name: String,
+    description: Option<String>,
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoleRequest {
+    name: String,
+    description: Option<String>,
+    #[serde(default)]
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPermissionsRequest {
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssignRoleRequest {
+    role: String,
+}
+
+// Role names and the union of their permissions, embedded into access tokens
+pub async fn load_grants(
+    db: &PgPool,
+    user_id: i32,
+) -> Result<(Vec<String>, Vec<String>), AppError> {
+    let roles = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT r.name FROM user_roles ur
+        JOIN roles r ON r.id = ur.role_id
+        WHERE ur.user_id = $1
+        ORDER BY r.name
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    let permissions = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT DISTINCT p.name FROM user_roles ur
+        JOIN role_permissions rp ON rp.role_id = ur.role_id
+        JOIN permissions p ON p.id = rp.permission_id
+        WHERE ur.user_id = $1
+        ORDER BY p.name
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok((roles, permissions))
+}
+
+// Grant `admin` to the account with `BOOTSTRAP_ADMIN_EMAIL`, so the first
+// administrator doesn't have to be created by hand in SQL. The address must be
+// verified, or anyone registering it first would become administrator.
+pub async fn bootstrap_admin(db: &PgPool) -> Result<(), sqlx::Error> {
+    let Ok(email) = std::env::var("BOOTSTRAP_ADMIN_EMAIL") else {
+        return Ok(());
+    };
+
+    let granted = sqlx::query(
+        r#"
+        INSERT INTO user_roles (user_id, role_id)
+        SELECT u.id, r.id FROM users u, roles r
+        WHERE u.email = $1 AND u.email_verified_at IS NOT NULL AND r.name = 'admin'
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(&email)
+    .execute(db)
+    .await?
+    .rows_affected();
+
+    if granted > 0 {
+        info!("Granted admin role to {}", email);
+    }
+
+    Ok(())
+}
+
+// All roles with their permissions, or only the one called `name`
+async fn fetch_roles(conn: &mut PgConnection, name: Option<&str>) -> Result<Vec<Role>, AppError> {
+    sqlx::query_as::<_, Role>(
+        r#"
+        SELECT r.id, r.name, r.description,
+            COALESCE(ARRAY_AGG(p.name ORDER BY p.name) FILTER (WHERE p.name IS NOT NULL), '{}')
+                AS permissions
+        FROM roles r
+        LEFT JOIN role_permissions rp ON rp.role_id = r.id
+        LEFT JOIN permissions p ON p.id = rp.permission_id
+        WHERE $1::TEXT IS NULL OR r.name = $1
+        GROUP BY r.id
+        ORDER BY r.name
+        "#,
+    )
+    .bind(name)
+    .fetch_all(conn)
+    .await
+    .map_err(AppError::DatabaseError)
+}
+
+async fn fetch_role(conn: &mut PgConnection, name: &str) -> Result<Role, AppError> {
+    fetch_roles(conn, Some(name))
+        .await?
+        .pop()
+        .ok_or(AppError::ValidationError(format!(
+            "Unknown role `{}`",
+            name
+        )))
+}
+
+// Roles must not grant more than the caller already holds
+fn ensure_grantable(claims: &Claims, permissions: &[String]) -> Result<(), AppError> {
+    match permissions.iter().find(|p| !claims.has_permission(p)) {
+        Some(permission) => Err(AppError::ForbiddenError(format!(
+            "Missing permission `{}`",
+            permission
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Users holding the role
+async fn role_holders(conn: &mut PgConnection, role_id: i32) -> Result<Vec<i32>, AppError> {
+    sqlx::query_scalar::<_, i32>("SELECT user_id FROM user_roles WHERE role_id = $1")
+        .bind(role_id)
+        .fetch_all(conn)
+        .await
+        .map_err(AppError::DatabaseError)
+}
+
+// Access tokens carry the permissions they were issued with, so tokens of
+// users whose grants changed are revoked; refreshing picks up the new ones
+async fn revoke_tokens(state: &AppState, user_ids: &[i32]) {
+    for user_id in user_ids {
+        if let Err(e) = state.revocations.revoke_all(&state.db, *user_id).await {
+            warn!("Failed to revoke tokens of user {}: {:?}", user_id, e);
+        }
+    }
+}
+
+// Replace the permissions of a role, creating unknown permission names
+async fn set_role_permissions(
+    conn: &mut PgConnection,
+    role_id: i32,
+    permissions: &[String],
+) -> Result<(), AppError> {
+    if permissions.iter().any(|p| p.trim().is_empty()) {
+        return Err(AppError::ValidationError(
+            "Permission names must not be empty".to_string(),
+        ));
+    }
+
+    sqlx::query(
+        "INSERT INTO permissions (name) SELECT UNNEST($1::TEXT[]) ON CONFLICT (name) DO NOTHING",
+    )
+    .bind(permissions)
+    .execute(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    sqlx::query("DELETE FROM role_permissions WHERE role_id = $1")
+        .bind(role_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT $1, id FROM permissions WHERE name = ANY($2)
+        "#,
+    )
+    .bind(role_id)
+    .bind(permissions)
+    .execute(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(())
+}
+
+// Handler functions
+pub async fn list_roles(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<RolesRead>,
+) -> Result<Json<Vec<Role>>, AppError> {
+    let mut conn = state.db.acquire().await.map_err(AppError::DatabaseError)?;
+    let roles = fetch_roles(&mut conn, None).await?;
+
+    Ok(Json(roles))
+}
+
+pub async fn create_role(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<RolesWrite>,
+    client: ClientInfo,
+    Json(payload): Json<CreateRoleRequest>,
+) -> Result<(StatusCode, Json<Role>), AppError> {
+    if payload.name.trim().is_empty() {
+        return Err(AppError::ValidationError(
+            "Role name must not be empty".to_string(),
+        ));
+    }
+    ensure_grantable(&claims, &payload.permissions)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let role_id = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO roles (name, description) VALUES ($1, $2) ON CONFLICT (name) DO NOTHING RETURNING id",
+    )
+    .bind(&payload.name)
+    .bind(&payload.description)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::ValidationError(format!(
+        "Role `{}` already exists",
+        payload.name
+    )))?;
+
+    set_role_permissions(&mut tx, role_id, &payload.permissions).await?;
+    let role = fetch_role(&mut tx, &payload.name).await?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    let event = AuditEvent::success("role_created")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "role": role.name, "permissions": role.permissions }));
+    audit::record(&state, event).await;
+
+    Ok((StatusCode::CREATED, Json(role)))
+}
+
+pub async fn update_role_permissions(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<RolesWrite>,
+    client: ClientInfo,
+    Path(name): Path<String>,
+    Json(payload): Json<SetPermissionsRequest>,
+) -> Result<Json<Role>, AppError> {
+    ensure_grantable(&claims, &payload.permissions)?;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let role = fetch_role(&mut tx, &name).await?;
+    set_role_permissions(&mut tx, role.id, &payload.permissions).await?;
+    let holders = role_holders(&mut tx, role.id).await?;
+    let role = fetch_role(&mut tx, &name).await?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    revoke_tokens(&state, &holders).await;
+
+    let event = AuditEvent::success("role_updated")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "role": role.name, "permissions": role.permissions }));
+    audit::record(&state, event).await;
+
+    Ok(Json(role))
+}
+
+pub async fn delete_role(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<RolesWrite>,
+    client: ClientInfo,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let role = fetch_role(&mut tx, &name).await?;
+    let holders = role_holders(&mut tx, role.id).await?;
+    sqlx::query("DELETE FROM roles WHERE id = $1")
+        .bind(role.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    revoke_tokens(&state, &holders).await;
+
+    let event = AuditEvent::success("role_deleted")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "role": name }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_user_roles(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<RolesRead>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let (roles, _) = load_grants(&state.db, user_id).await?;
+
+    Ok(Json(roles))
+}
+
+pub async fn assign_role(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<RolesWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<AssignRoleRequest>,
+) -> Result<StatusCode, AppError> {
+    let mut conn = state.db.acquire().await.map_err(AppError::DatabaseError)?;
+    let role = fetch_role(&mut conn, &payload.role).await?;
+    ensure_grantable(&claims, &role.permissions)?;
+
+    let assigned = sqlx::query(
+        r#"
+        INSERT INTO user_roles (user_id, role_id)
+        SELECT u.id, r.id FROM users u, roles r
+        WHERE u.id = $1 AND r.name = $2
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(user_id)
+    .bind(&payload.role)
+    .execute(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+
+    if assigned == 0 {
+        // Either the user or the role is unknown, or the role was already held
+        let (roles, _) = load_grants(&state.db, user_id).await?;
+        if !roles.contains(&payload.role) {
+            return Err(AppError::ValidationError(
+                "Unknown user or role".to_string(),
+            ));
+        }
+    }
+
+    if assigned > 0 {
+        let event = AuditEvent::success("role_assigned")
+            .by(&claims)
+            .subject(user_id)
+            .client(&client)
+            .details(serde_json::json!({ "role": payload.role }));
+        audit::record(&state, event).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_role(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<RolesWrite>,
+    client: ClientInfo,
+    Path((user_id, role)): Path<(i32, String)>,
+) -> Result<StatusCode, AppError> {
+    let removed = sqlx::query(
+        r#"
+        DELETE FROM user_roles
+        WHERE user_id = $1 AND role_id = (SELECT id FROM roles WHERE name = $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(&role)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+
+    if removed > 0 {
+        // Tokens carry the permissions of the role until refreshed
+        state.revocations.revoke_all(&state.db, user_id).await?;
+
+        let event = AuditEvent::success("role_removed")
+            .by(&claims)
+            .subject(user_id)
+            .client(&client)
+            .details(serde_json::json!({ "role": role }));
+        audit::record(&state, event).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(permissions: &[&str]) -> Claims {
+        Claims {
+            sub: 1,
+            exp: 0,
+            iat: 0,
+            jti: "0b7e1c9a-8f1d-4a53-9d8e-2f6f3c1d5a42".to_string(),
+            sid: None,
+            act: None,
+            org_id: None,
+            roles: Vec::new(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            client_id: None,
+            scope: None,
+            aud: None,
+        }
+    }
+
+    fn grantable(claims: &Claims, permissions: &[&str]) -> bool {
+        let permissions: Vec<String> = permissions.iter().map(|p| p.to_string()).collect();
+        ensure_grantable(claims, &permissions).is_ok()
+    }
+
+    #[test]
+    fn only_held_permissions_can_be_granted() {
+        let manager = claims(&["roles:write", "users:*"]);
+        assert!(grantable(&manager, &[]));
+        assert!(grantable(
+            &manager,
+            &["roles:write", "users:read", "users:*"]
+        ));
+        assert!(!grantable(&manager, &["*"]));
+        assert!(!grantable(&manager, &["users:read", "audit:read"]));
+
+        let admin = claims(&["*"]);
+        assert!(grantable(&admin, &["*", "audit:read"]));
+    }
+}
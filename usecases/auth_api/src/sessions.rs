@@ -0,0 +1,230 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Path, State},
+    http::{header::USER_AGENT, request::Parts, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use sqlx::PgConnection;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::account::require_own_login;
+use crate::audit::{self, AuditEvent};
+use crate::{AppError, AppState, AuthUser};
+
+// Longest user agent kept for a session
+const MAX_USER_AGENT_LEN: usize = 512;
+
+// Where a login comes from, recorded with its session
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect());
+        let ip_address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+        Ok(ClientInfo {
+            user_agent,
+            ip_address,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Session {
+    id: Uuid,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    mfa: bool,
+    created_at: chrono::DateTime<chrono::Utc>,
+    last_seen_at: chrono::DateTime<chrono::Utc>,
+    // Whether this is the session of the token making the request
+    current: bool,
+}
+
+pub async fn start_session(
+    conn: &mut PgConnection,
+    user_id: i32,
+    client: &ClientInfo,
+    mfa: bool,
+) -> Result<Uuid, AppError> {
+    let id = Uuid::new_v4();
+
+    // Members of a single organization start in it
+    sqlx::query(
+        r#"
+        INSERT INTO sessions (id, user_id, user_agent, ip_address, mfa, org_id)
+        VALUES ($1, $2, $3, $4, $5, (
+            SELECT MIN(org_id) FROM org_memberships WHERE user_id = $2 HAVING COUNT(*) = 1
+        ))
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(&client.user_agent)
+    .bind(&client.ip_address)
+    .bind(mfa)
+    .execute(conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(id)
+}
+
+pub async fn touch_session(conn: &mut PgConnection, session_id: Uuid) -> Result<(), AppError> {
+    sqlx::query("UPDATE sessions SET last_seen_at = NOW() WHERE id = $1")
+        .bind(session_id)
+        .execute(conn)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    Ok(())
+}
+
+// End one session of the user: its refresh tokens stop working and its access
+// tokens are rejected. Returns false when there was no such live session.
+pub async fn end_session(
+    state: &AppState,
+    user_id: i32,
+    session_id: Uuid,
+) -> Result<bool, AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let ended = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = NOW() WHERE session_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(session_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    state.revocations.forget_session(session_id);
+
+    Ok(ended > 0)
+}
+
+// Mark every session of the user as ended. Their tokens are revoked separately.
+pub async fn end_user_sessions(conn: &mut PgConnection, user_id: i32) -> Result<(), AppError> {
+    sqlx::query("UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+        .bind(user_id)
+        .execute(conn)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    Ok(())
+}
+
+// End every session of the user except `keep`, along with the refresh tokens
+// issued to them. Returns the sessions that were ended.
+pub async fn end_other_sessions(
+    conn: &mut PgConnection,
+    user_id: i32,
+    keep: Option<Uuid>,
+) -> Result<Vec<Uuid>, AppError> {
+    let ended = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        UPDATE sessions SET revoked_at = NOW()
+        WHERE user_id = $1 AND revoked_at IS NULL AND id IS DISTINCT FROM $2
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(keep)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    sqlx::query(
+        r#"
+        UPDATE refresh_tokens SET revoked_at = NOW()
+        WHERE user_id = $1 AND revoked_at IS NULL AND session_id IS DISTINCT FROM $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(keep)
+    .execute(&mut *conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(ended)
+}
+
+// Handler functions
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+) -> Result<Json<Vec<Session>>, AppError> {
+    // Sessions idle for longer than a refresh token lives can't come back
+    let idle_since = chrono::Utc::now() - state.refresh_token_ttl;
+
+    let sessions = sqlx::query_as::<_, Session>(
+        r#"
+        SELECT id, user_agent, ip_address, mfa, created_at, last_seen_at,
+            COALESCE(id::TEXT = $3, FALSE) AS current
+        FROM sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND last_seen_at > $2
+        ORDER BY last_seen_at DESC
+        "#,
+    )
+    .bind(claims.sub)
+    .bind(idle_since)
+    .bind(&claims.sid)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(sessions))
+}
+
+pub async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    Path(session_id): Path<Uuid>,
+) -> Result<StatusCode, AppError> {
+    require_own_login(&claims)?;
+
+    if !end_session(&state, claims.sub, session_id).await? {
+        return Err(AppError::ValidationError("Unknown session".to_string()));
+    }
+
+    audit::record(
+        &state,
+        AuditEvent::success("session_revoked")
+            .by(&claims)
+            .subject(claims.sub)
+            .client(&client)
+            .details(serde_json::json!({ "session_id": session_id })),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
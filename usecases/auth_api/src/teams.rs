@@ -0,0 +1,210 @@
+use auth_api::authz::RequirePermission;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::audit::{self, AuditEvent};
+use crate::orgs::{current_org, OrgsRead, OrgsTeamsWrite};
+use crate::sessions::ClientInfo;
+use crate::{AppError, AppState};
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Team {
+    id: i32,
+    name: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    // User ids
+    members: Vec<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTeamRequest {
+    name: String,
+}
+
+// Handler functions
+pub async fn list_teams(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsRead>,
+) -> Result<Json<Vec<Team>>, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let teams = sqlx::query_as::<_, Team>(
+        r#"
+        SELECT t.id, t.name, t.created_at,
+            COALESCE(ARRAY_AGG(tm.user_id ORDER BY tm.user_id) FILTER (WHERE tm.user_id IS NOT NULL), '{}')
+                AS members
+        FROM teams t
+        LEFT JOIN team_members tm ON tm.team_id = t.id
+        WHERE t.org_id = $1
+        GROUP BY t.id
+        ORDER BY t.name
+        "#,
+    )
+    .bind(org_id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(teams))
+}
+
+pub async fn create_team(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsTeamsWrite>,
+    client: ClientInfo,
+    Json(payload): Json<CreateTeamRequest>,
+) -> Result<(StatusCode, Json<Team>), AppError> {
+    let org_id = current_org(&claims)?;
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(AppError::ValidationError(
+            "Team name must not be empty".to_string(),
+        ));
+    }
+
+    let team = sqlx::query_as::<_, Team>(
+        r#"
+        INSERT INTO teams (org_id, name) VALUES ($1, $2)
+        ON CONFLICT (org_id, name) DO NOTHING
+        RETURNING id, name, created_at, '{}'::INTEGER[] AS members
+        "#,
+    )
+    .bind(org_id)
+    .bind(name)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::ValidationError(format!(
+        "Team `{}` already exists",
+        name
+    )))?;
+
+    let event = AuditEvent::success("team_created")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "team_id": team.id, "name": team.name }));
+    audit::record(&state, event).await;
+
+    Ok((StatusCode::CREATED, Json(team)))
+}
+
+pub async fn delete_team(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsTeamsWrite>,
+    client: ClientInfo,
+    Path(team_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let deleted = sqlx::query("DELETE FROM teams WHERE id = $1 AND org_id = $2")
+        .bind(team_id)
+        .bind(org_id)
+        .execute(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(AppError::ValidationError("Unknown team".to_string()));
+    }
+
+    let event = AuditEvent::success("team_deleted")
+        .by(&claims)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "team_id": team_id }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn add_team_member(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsTeamsWrite>,
+    client: ClientInfo,
+    Path((team_id, user_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+
+    // The membership foreign key keeps outsiders out of the organization's teams
+    let added = sqlx::query(
+        r#"
+        INSERT INTO team_members (team_id, org_id, user_id)
+        SELECT id, org_id, $3 FROM teams WHERE id = $1 AND org_id = $2
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(team_id)
+    .bind(org_id)
+    .bind(user_id)
+    .execute(&state.db)
+    .await
+    .map_err(|e| {
+        if e.as_database_error()
+            .is_some_and(|e| e.is_foreign_key_violation())
+        {
+            AppError::ValidationError("Not a member of the organization".to_string())
+        } else {
+            AppError::DatabaseError(e)
+        }
+    })?
+    .rows_affected();
+
+    if added == 0 {
+        // Either the team is unknown, or the user was already in it
+        let known = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM teams WHERE id = $1 AND org_id = $2)",
+        )
+        .bind(team_id)
+        .bind(org_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+        if !known {
+            return Err(AppError::ValidationError("Unknown team".to_string()));
+        }
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let event = AuditEvent::success("team_member_added")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client)
+        .details(serde_json::json!({ "org_id": org_id, "team_id": team_id }));
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_team_member(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<OrgsTeamsWrite>,
+    client: ClientInfo,
+    Path((team_id, user_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, AppError> {
+    let org_id = current_org(&claims)?;
+
+    let removed =
+        sqlx::query("DELETE FROM team_members WHERE team_id = $1 AND org_id = $2 AND user_id = $3")
+            .bind(team_id)
+            .bind(org_id)
+            .bind(user_id)
+            .execute(&state.db)
+            .await
+            .map_err(AppError::DatabaseError)?
+            .rows_affected();
+
+    if removed > 0 {
+        let event = AuditEvent::success("team_member_removed")
+            .by(&claims)
+            .subject(user_id)
+            .client(&client)
+            .details(serde_json::json!({ "org_id": org_id, "team_id": team_id }));
+        audit::record(&state, event).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
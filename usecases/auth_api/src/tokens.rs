@@ -0,0 +1,308 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use std::sync::Arc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::account;
+use crate::audit::{self, AuditEvent};
+use crate::cookies::{self, SessionMode};
+use crate::sessions::{end_session, end_user_sessions, start_session, touch_session, ClientInfo};
+use crate::users;
+use crate::{generate_token, AppError, AppState, AuthResponse, AuthUser, User};
+
+// Refresh token model
+#[derive(Debug, sqlx::FromRow)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: Uuid,
+    pub session_id: Option<Uuid>,
+    pub replaced_by: Option<i32>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogoutRequest {
+    refresh_token: Option<String>,
+}
+
+// Generate a random opaque token. Only its hash is ever persisted.
+pub fn generate_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Issue a refresh token. A new login starts a new family; rotation passes the
+// family and session of the token being replaced.
+pub async fn issue_refresh_token(
+    conn: &mut PgConnection,
+    state: &AppState,
+    user_id: i32,
+    family_id: Option<Uuid>,
+    session_id: Option<Uuid>,
+) -> Result<(i32, String), AppError> {
+    let token = generate_opaque_token();
+    let expires_at = chrono::Utc::now() + state.refresh_token_ttl;
+
+    let id = sqlx::query_scalar::<_, i32>(
+        r#"
+        INSERT INTO refresh_tokens (user_id, family_id, session_id, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(family_id.unwrap_or_else(Uuid::new_v4))
+    .bind(session_id)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .fetch_one(conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok((id, token))
+}
+
+// Access and refresh token pair returned after a successful login, which
+// starts a new session. `mfa` tells whether the login passed a second factor.
+pub async fn issue_token_pair(
+    state: &AppState,
+    mut user: User,
+    client: &ClientInfo,
+    mfa: bool,
+) -> Result<AuthResponse, AppError> {
+    if user.deletion_scheduled_at.is_some() {
+        account::cancel_deletion(state, &user, client).await?;
+        user.deletion_scheduled_at = None;
+    }
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+    let session_id = start_session(&mut tx, user.id, client, mfa).await?;
+    let (_, refresh_token) =
+        issue_refresh_token(&mut tx, state, user.id, None, Some(session_id)).await?;
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    let token = generate_token(state, user.id, Some(session_id)).await?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: state.access_token_ttl.num_seconds(),
+        user: user.into(),
+    })
+}
+
+pub async fn revoke_family(conn: &mut PgConnection, family_id: Uuid) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(family_id)
+    .execute(conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(())
+}
+
+pub async fn revoke_user_refresh_tokens(
+    conn: &mut PgConnection,
+    user_id: i32,
+) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(conn)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(())
+}
+
+// Handler functions
+
+// Cookie clients send no body: their refresh token comes from its cookie and
+// the new pair goes back into cookies
+pub async fn refresh(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    mode: SessionMode,
+    headers: HeaderMap,
+    payload: Option<Json<RefreshRequest>>,
+) -> Result<Response, AppError> {
+    let (presented, mode) = match payload {
+        Some(Json(payload)) => (payload.refresh_token, mode),
+        None => cookies::refresh_token(&state, &headers)
+            .map(|token| (token, SessionMode::Cookie))
+            .ok_or(AppError::AuthError("Missing refresh token".to_string()))?,
+    };
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let current = sqlx::query_as::<_, RefreshToken>(
+        "SELECT * FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE",
+    )
+    .bind(hash_token(&presented))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::AuthError("Invalid refresh token".to_string()))?;
+
+    if current.revoked_at.is_some() {
+        return Err(AppError::AuthError("Refresh token revoked".to_string()));
+    }
+
+    // A token that was already rotated is being presented again, so either
+    // the client or an attacker holds a stolen copy. Kill the whole family.
+    if current.replaced_by.is_some() {
+        revoke_family(&mut tx, current.family_id).await?;
+        tx.commit().await.map_err(AppError::DatabaseError)?;
+        warn!(
+            "Refresh token reuse detected for user {}, family {} revoked",
+            current.user_id, current.family_id
+        );
+        let event = AuditEvent::failure("refresh_token_reuse")
+            .subject(current.user_id)
+            .client(&client)
+            .details(serde_json::json!({ "family_id": current.family_id }));
+        audit::record(&state, event).await;
+        return Err(AppError::AuthError(
+            "Refresh token reuse detected".to_string(),
+        ));
+    }
+
+    if current.expires_at <= chrono::Utc::now() {
+        return Err(AppError::AuthError("Refresh token expired".to_string()));
+    }
+
+    let (next_id, refresh_token) = issue_refresh_token(
+        &mut tx,
+        &state,
+        current.user_id,
+        Some(current.family_id),
+        current.session_id,
+    )
+    .await?;
+    sqlx::query("UPDATE refresh_tokens SET replaced_by = $1 WHERE id = $2")
+        .bind(next_id)
+        .bind(current.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(current.user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    users::check_active(&user)?;
+    state.unverified_policy.check(&user)?;
+
+    if let Some(session_id) = current.session_id {
+        touch_session(&mut tx, session_id).await?;
+    }
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    let token = generate_token(&state, user.id, current.session_id).await?;
+
+    let response = AuthResponse {
+        token,
+        refresh_token,
+        expires_in: state.access_token_ttl.num_seconds(),
+        user: user.into(),
+    };
+
+    Ok(cookies::respond(&state, mode, response))
+}
+
+// Revoke the presented access token and end its session. Tokens from before
+// sessions existed name their refresh token in the body instead.
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+    payload: Option<Json<LogoutRequest>>,
+) -> Result<(StatusCode, HeaderMap), AppError> {
+    state.revocations.revoke(&state.db, &claims).await?;
+
+    if let Some(session_id) = claims.sid.as_deref().and_then(|s| Uuid::parse_str(s).ok()) {
+        end_session(&state, claims.sub, session_id).await?;
+    }
+
+    if let Some(refresh_token) = payload.and_then(|Json(p)| p.refresh_token) {
+        let mut conn = state.db.acquire().await.map_err(AppError::DatabaseError)?;
+        let family_id = sqlx::query_scalar::<_, Uuid>(
+            "SELECT family_id FROM refresh_tokens WHERE token_hash = $1 AND user_id = $2",
+        )
+        .bind(hash_token(&refresh_token))
+        .bind(claims.sub)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+        if let Some(family_id) = family_id {
+            revoke_family(&mut conn, family_id).await?;
+        }
+    }
+
+    audit::record(
+        &state,
+        AuditEvent::success("logout")
+            .by(&claims)
+            .subject(claims.sub)
+            .client(&client)
+            .details(serde_json::json!({ "session_id": claims.sid })),
+    )
+    .await;
+
+    Ok((StatusCode::NO_CONTENT, cookies::clear(&state)))
+}
+
+// Invalidate every access and refresh token the user holds
+pub async fn logout_all(
+    State(state): State<Arc<AppState>>,
+    AuthUser { claims }: AuthUser,
+    client: ClientInfo,
+) -> Result<(StatusCode, HeaderMap), AppError> {
+    account::require_own_login(&claims)?;
+
+    let mut conn = state.db.acquire().await.map_err(AppError::DatabaseError)?;
+    revoke_user_refresh_tokens(&mut conn, claims.sub).await?;
+    end_user_sessions(&mut conn, claims.sub).await?;
+
+    state.revocations.revoke_all(&state.db, claims.sub).await?;
+
+    audit::record(
+        &state,
+        AuditEvent::success("logout_all")
+            .by(&claims)
+            .subject(claims.sub)
+            .client(&client),
+    )
+    .await;
+
+    Ok((StatusCode::NO_CONTENT, cookies::clear(&state)))
+}
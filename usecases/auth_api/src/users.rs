@@ -0,0 +1,419 @@
+use auth_api::authz::{Actor, RequirePermission};
+use auth_api::permission;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::audit::{self, AuditEvent};
+use crate::sessions::{end_user_sessions, ClientInfo};
+use crate::tokens::revoke_user_refresh_tokens;
+use crate::views::AdminUserView;
+use crate::{account, api_keys, auth, mfa, passwords, roles, AppError, AppState, Claims, User};
+
+permission!(pub UsersRead = "users:read");
+permission!(pub UsersWrite = "users:write");
+permission!(pub UsersImpersonate = "users:impersonate");
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct ListUsersQuery {
+    // Part of the username or email
+    q: Option<String>,
+    disabled: Option<bool>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    users: Vec<AdminUserView>,
+    total: i64,
+    page: i64,
+    per_page: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDetails {
+    #[serde(flatten)]
+    user: AdminUserView,
+    roles: Vec<String>,
+    mfa_enabled: bool,
+    active_sessions: i64,
+    last_seen_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationResponse {
+    token: String,
+    expires_in: i64,
+    user: AdminUserView,
+}
+
+// Refuse logins to accounts an administrator has disabled or flagged for a
+// password reset
+pub fn check_active(user: &User) -> Result<(), AppError> {
+    if user.disabled_at.is_some() {
+        return Err(AppError::ForbiddenError("Account is disabled".to_string()));
+    }
+    if user.password_reset_required {
+        return Err(AppError::ForbiddenError(
+            "Password reset required, follow the link sent by email".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Substring pattern for ILIKE, with the wildcards in `q` matched literally
+fn like_pattern(q: &str) -> String {
+    let escaped = q
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+async fn fetch_user(state: &AppState, user_id: i32) -> Result<User, AppError> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .ok_or(AppError::ValidationError("Unknown user".to_string()))
+}
+
+// Revoke every refresh and access token of the user and end their sessions
+async fn sign_out_everywhere(state: &AppState, user_id: i32) -> Result<(), AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+    revoke_user_refresh_tokens(&mut tx, user_id).await?;
+    end_user_sessions(&mut tx, user_id).await?;
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    state.revocations.revoke_all(&state.db, user_id).await
+}
+
+fn refuse_self(claims: &Claims, user_id: i32, action: &str) -> Result<(), AppError> {
+    if claims.sub == user_id {
+        return Err(AppError::ValidationError(format!(
+            "You can't {} your own account",
+            action
+        )));
+    }
+
+    Ok(())
+}
+
+// Handler functions
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<UsersRead>,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Json<UserPage>, AppError> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let pattern = query.q.as_deref().map(like_pattern);
+
+    let filter = r#"
+        WHERE ($1::TEXT IS NULL OR username ILIKE $1 OR email ILIKE $1)
+            AND ($2::BOOLEAN IS NULL OR (disabled_at IS NOT NULL) = $2)
+    "#;
+
+    let total = sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM users {}", filter))
+        .bind(&pattern)
+        .bind(query.disabled)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    let users = sqlx::query_as::<_, User>(&format!(
+        "SELECT * FROM users {} ORDER BY id LIMIT $3 OFFSET $4",
+        filter
+    ))
+    .bind(&pattern)
+    .bind(query.disabled)
+    .bind(per_page)
+    .bind((page - 1) * per_page)
+    .fetch_all(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(UserPage {
+        users: users.into_iter().map(AdminUserView::from).collect(),
+        total,
+        page,
+        per_page,
+    }))
+}
+
+pub async fn get_user(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<UsersRead>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<UserDetails>, AppError> {
+    let user = fetch_user(&state, user_id).await?;
+    let (roles, _) = roles::load_grants(&state.db, user_id).await?;
+    let mfa_enabled = mfa::is_enabled(&state, user_id).await?;
+
+    // Same notion of a live session as the user's own session list
+    let idle_since = chrono::Utc::now() - state.refresh_token_ttl;
+    let (active_sessions, last_seen_at) =
+        sqlx::query_as::<_, (i64, Option<chrono::DateTime<chrono::Utc>>)>(
+            r#"
+            SELECT COUNT(*), MAX(last_seen_at) FROM sessions
+            WHERE user_id = $1 AND revoked_at IS NULL AND last_seen_at > $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(idle_since)
+        .fetch_one(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?;
+
+    Ok(Json(UserDetails {
+        user: user.into(),
+        roles,
+        mfa_enabled,
+        active_sessions,
+        last_seen_at,
+    }))
+}
+
+// A disabled account can't log in, and everything it holds stops working
+pub async fn disable_user(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<UsersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    refuse_self(&claims, user_id, "disable")?;
+
+    let updated =
+        sqlx::query("UPDATE users SET disabled_at = COALESCE(disabled_at, NOW()) WHERE id = $1")
+            .bind(user_id)
+            .execute(&state.db)
+            .await
+            .map_err(AppError::DatabaseError)?
+            .rows_affected();
+    if updated == 0 {
+        return Err(AppError::ValidationError("Unknown user".to_string()));
+    }
+
+    sign_out_everywhere(&state, user_id).await?;
+    info!("User {} disabled by admin {}", user_id, claims.sub);
+
+    let event = AuditEvent::success("user_disabled")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client);
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn enable_user(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<UsersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let updated = sqlx::query("UPDATE users SET disabled_at = NULL WHERE id = $1")
+        .bind(user_id)
+        .execute(&state.db)
+        .await
+        .map_err(AppError::DatabaseError)?
+        .rows_affected();
+    if updated == 0 {
+        return Err(AppError::ValidationError("Unknown user".to_string()));
+    }
+
+    info!("User {} enabled by admin {}", user_id, claims.sub);
+
+    let event = AuditEvent::success("user_enabled")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client);
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Sign the user out, drop their API keys and block logins until they choose a
+// new password through the emailed link
+pub async fn force_password_reset(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<UsersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET password_reset_required = TRUE WHERE id = $1 RETURNING *",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::ValidationError("Unknown user".to_string()))?;
+
+    api_keys::delete_user_keys(&mut tx, user_id).await?;
+    revoke_user_refresh_tokens(&mut tx, user_id).await?;
+    end_user_sessions(&mut tx, user_id).await?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    state.revocations.revoke_all(&state.db, user_id).await?;
+
+    passwords::send_reset_link(
+        &state,
+        &user,
+        "An administrator asked you to choose a new password. You can't log in until you do.",
+    )
+    .await?;
+
+    let event = AuditEvent::success("password_reset_forced")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client);
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<UsersWrite>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    refuse_self(&claims, user_id, "delete")?;
+
+    let user = fetch_user(&state, user_id).await?;
+
+    account::purge_user(&state, &user).await?;
+
+    info!("User {} deleted by admin {}", user_id, claims.sub);
+
+    let event = AuditEvent::success("user_deleted")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client);
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Issue an access token for the user, marked with the administrator in `act`.
+// There is no refresh token: impersonation ends when the token expires.
+pub async fn impersonate(
+    State(state): State<Arc<AppState>>,
+    RequirePermission { claims, .. }: RequirePermission<UsersImpersonate>,
+    client: ClientInfo,
+    Path(user_id): Path<i32>,
+) -> Result<Json<ImpersonationResponse>, AppError> {
+    if !auth::is_own_login(&claims) {
+        return Err(AppError::ForbiddenError(
+            "Impersonation requires an administrator's own login".to_string(),
+        ));
+    }
+    refuse_self(&claims, user_id, "impersonate")?;
+
+    let user = fetch_user(&state, user_id).await?;
+    if user.disabled_at.is_some() {
+        return Err(AppError::ValidationError("Account is disabled".to_string()));
+    }
+
+    // Impersonating must not grant more than the administrator already holds
+    let (roles, permissions) = roles::load_grants(&state.db, user_id).await?;
+    if let Some(permission) = permissions.iter().find(|p| !claims.has_permission(p)) {
+        return Err(AppError::ForbiddenError(format!(
+            "User holds `{}`, which you don't",
+            permission
+        )));
+    }
+
+    let now = chrono::Utc::now();
+    let impersonation = Claims {
+        sub: user_id,
+        exp: (now + state.access_token_ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        jti: uuid::Uuid::new_v4().to_string(),
+        sid: None,
+        act: Some(Actor { sub: claims.sub }),
+        org_id: None,
+        roles,
+        permissions,
+        client_id: None,
+        scope: None,
+        aud: None,
+    };
+    let token = state.keys.sign(&impersonation)?;
+
+    info!("Admin {} impersonating user {}", claims.sub, user_id);
+
+    let event = AuditEvent::success("impersonation_started")
+        .by(&claims)
+        .subject(user_id)
+        .client(&client)
+        .details(serde_json::json!({ "jti": impersonation.jti }));
+    audit::record(&state, event).await;
+
+    Ok(Json(ImpersonationResponse {
+        token,
+        expires_in: state.access_token_ttl.num_seconds(),
+        user: user.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::views::tests::{assert_no_hash_material, user, PASSWORD_HASHES};
+
+    // `GET /admin/users`, `GET /admin/users/:user_id` and impersonation
+    #[test]
+    fn admin_responses_never_contain_password_hashes() {
+        for password_hash in PASSWORD_HASHES {
+            let page = UserPage {
+                users: vec![user(password_hash).into()],
+                total: 1,
+                page: 1,
+                per_page: 50,
+            };
+            assert_no_hash_material(&page, password_hash);
+
+            let details = UserDetails {
+                user: user(password_hash).into(),
+                roles: vec!["admin".to_string()],
+                mfa_enabled: true,
+                active_sessions: 1,
+                last_seen_at: Some(chrono::Utc::now()),
+            };
+            assert_no_hash_material(&details, password_hash);
+
+            let impersonation = ImpersonationResponse {
+                token: "access".to_string(),
+                expires_in: 900,
+                user: user(password_hash).into(),
+            };
+            assert_no_hash_material(&impersonation, password_hash);
+        }
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(like_pattern("alice"), "%alice%");
+        assert_eq!(like_pattern("50%_off"), "%50\\%\\_off%");
+        assert_eq!(like_pattern("a\\b"), "%a\\\\b%");
+    }
+}
@@ -0,0 +1,241 @@
+use axum::{extract::State, http::StatusCode, Json};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::audit::{self, AuditEvent};
+use crate::mailer::Email;
+use crate::sessions::ClientInfo;
+use crate::views::PublicUser;
+use crate::{AppError, AppState, User};
+
+// Audience of verification tokens, so they can't be used as access tokens
+const AUDIENCE: &str = "email-verification";
+
+// Minimum time between two verification emails for the same user
+const RESEND_COOLDOWN_SECS: i64 = 60;
+
+// What an account may do before its email address is verified
+#[derive(Debug, Clone, Copy)]
+pub enum UnverifiedPolicy {
+    // No restriction
+    Allow,
+    // Login keeps working for a while after registering
+    Grace(chrono::Duration),
+    // No tokens are issued until the address is verified
+    Block,
+}
+
+impl UnverifiedPolicy {
+    // Read `UNVERIFIED_POLICY` (allow, grace or block) and `UNVERIFIED_GRACE_HOURS`
+    pub fn from_env() -> Self {
+        match std::env::var("UNVERIFIED_POLICY").as_deref() {
+            Ok("block") => UnverifiedPolicy::Block,
+            Ok("grace") => {
+                let hours = crate::env_or("UNVERIFIED_GRACE_HOURS", 72);
+                UnverifiedPolicy::Grace(chrono::Duration::hours(hours))
+            }
+            _ => UnverifiedPolicy::Allow,
+        }
+    }
+
+    // Reject users who may not receive tokens yet
+    pub fn check(&self, user: &User) -> Result<(), AppError> {
+        if user.email_verified_at.is_some() {
+            return Ok(());
+        }
+
+        let allowed = match self {
+            UnverifiedPolicy::Allow => true,
+            UnverifiedPolicy::Grace(grace) => chrono::Utc::now() < user.created_at + *grace,
+            UnverifiedPolicy::Block => false,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(AppError::ForbiddenError(
+                "Email address not verified".to_string(),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VerificationClaims {
+    sub: i32,
+    // The address being verified; the token is void once it changes
+    email: String,
+    aud: String,
+    exp: usize,
+    jti: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendRequest {
+    email: String,
+}
+
+// Issue a single-use verification token and mail the link to the user
+pub async fn send_verification_email(state: &AppState, user: &User) -> Result<(), AppError> {
+    let jti = Uuid::new_v4();
+    let expires_at = chrono::Utc::now() + state.email_verification_ttl;
+
+    sqlx::query(
+        "INSERT INTO email_verification_tokens (jti, user_id, expires_at) VALUES ($1, $2, $3)",
+    )
+    .bind(jti)
+    .bind(user.id)
+    .bind(expires_at)
+    .execute(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    let claims = VerificationClaims {
+        sub: user.id,
+        email: user.email.clone(),
+        aud: AUDIENCE.to_string(),
+        exp: expires_at.timestamp() as usize,
+        jti,
+    };
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    )
+    .map_err(|e| AppError::ValidationError(e.to_string()))?;
+
+    let link = format!("{}/verify-email?token={}", state.app_url, token);
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hi {},\n\nConfirm your email address by opening this link:\n\n{}\n\nThe link expires in {} hours.\n",
+            user.username,
+            link,
+            state.email_verification_ttl.num_hours()
+        ),
+    };
+
+    // A delivery problem shouldn't undo the caller's work; the user can resend
+    if let Err(e) = state.mailer.send(email).await {
+        warn!(
+            "Failed to send verification email to user {}: {}",
+            user.id, e
+        );
+    }
+
+    Ok(())
+}
+
+// Handler functions
+pub async fn verify_email(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<Json<PublicUser>, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[AUDIENCE]);
+
+    let claims = decode::<VerificationClaims>(
+        &payload.token,
+        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map_err(|_| AppError::AuthError("Invalid verification token".to_string()))?
+    .claims;
+
+    let mut tx = state.db.begin().await.map_err(AppError::DatabaseError)?;
+
+    let consumed = sqlx::query(
+        r#"
+        UPDATE email_verification_tokens SET used_at = NOW()
+        WHERE jti = $1 AND user_id = $2 AND used_at IS NULL
+        "#,
+    )
+    .bind(claims.jti)
+    .bind(claims.sub)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(AppError::AuthError(
+            "Verification token already used".to_string(),
+        ));
+    }
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW())
+        WHERE id = $1 AND email = $2
+        RETURNING *
+        "#,
+    )
+    .bind(claims.sub)
+    .bind(&claims.email)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::DatabaseError)?
+    .ok_or(AppError::AuthError(
+        "Invalid verification token".to_string(),
+    ))?;
+
+    tx.commit().await.map_err(AppError::DatabaseError)?;
+
+    audit::record(
+        &state,
+        AuditEvent::success("email_verified")
+            .user(user.id)
+            .client(&client),
+    )
+    .await;
+
+    Ok(Json(user.into()))
+}
+
+// Always answers 202 so the endpoint can't be used to probe for accounts
+pub async fn resend_verification(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ResendRequest>,
+) -> Result<StatusCode, AppError> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE email = $1 AND email_verified_at IS NULL",
+    )
+    .bind(&payload.email)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    let Some(user) = user else {
+        return Ok(StatusCode::ACCEPTED);
+    };
+
+    let recently_sent = sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM email_verification_tokens
+            WHERE user_id = $1 AND created_at > NOW() - make_interval(secs => $2)
+        )
+        "#,
+    )
+    .bind(user.id)
+    .bind(RESEND_COOLDOWN_SECS as f64)
+    .fetch_one(&state.db)
+    .await
+    .map_err(AppError::DatabaseError)?;
+
+    if !recently_sent {
+        send_verification_email(&state, &user).await?;
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+
+use crate::User;
+
+// What responses say about a user. `User` is the database row and can't be
+// serialized, so a handler has to pick one of these and credentials never
+// reach a client.
+
+// A user as they see themselves
+#[derive(Debug, Serialize)]
+pub struct PublicUser {
+    id: i32,
+    username: String,
+    email: String,
+    email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    deletion_scheduled_at: Option<chrono::DateTime<chrono::Utc>>,
+    magic_link_enabled: bool,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        PublicUser {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            email_verified_at: user.email_verified_at,
+            created_at: user.created_at,
+            deletion_scheduled_at: user.deletion_scheduled_at,
+            magic_link_enabled: user.magic_link_enabled,
+        }
+    }
+}
+
+// A user as administrators see it, with its account status
+#[derive(Debug, Serialize)]
+pub struct AdminUserView {
+    id: i32,
+    username: String,
+    email: String,
+    email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    disabled_at: Option<chrono::DateTime<chrono::Utc>>,
+    password_reset_required: bool,
+    deletion_scheduled_at: Option<chrono::DateTime<chrono::Utc>>,
+    magic_link_enabled: bool,
+}
+
+impl From<User> for AdminUserView {
+    fn from(user: User) -> Self {
+        AdminUserView {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            email_verified_at: user.email_verified_at,
+            created_at: user.created_at,
+            disabled_at: user.disabled_at,
+            password_reset_required: user.password_reset_required,
+            deletion_scheduled_at: user.deletion_scheduled_at,
+            magic_link_enabled: user.magic_link_enabled,
+        }
+    }
+}
+
+// The hash check is shared with the tests of every module building a response
+// from a user
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::AuthResponse;
+
+    const ARGON2_HASH: &str =
+        "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$9sTbSlTio3Biev89thdrlKKiCaYsjjYVJxGAL3swxpQ";
+    const BCRYPT_HASH: &str = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+
+    // One hash per supported scheme
+    pub(crate) const PASSWORD_HASHES: [&str; 2] = [ARGON2_HASH, BCRYPT_HASH];
+
+    pub(crate) fn user(password_hash: &str) -> User {
+        User {
+            id: 7,
+            username: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+            password_hash: password_hash.to_string(),
+            email_verified_at: Some(chrono::Utc::now()),
+            created_at: chrono::Utc::now(),
+            disabled_at: None,
+            password_reset_required: false,
+            deletion_scheduled_at: None,
+            magic_link_enabled: true,
+        }
+    }
+
+    // The views, and `AuthResponse`, which register, login, refresh, MFA
+    // verification, the magic link callback and org switching answer with.
+    // The other response types are checked next to their handlers.
+    fn responses(password_hash: &str) -> Vec<serde_json::Value> {
+        let auth = AuthResponse {
+            token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            expires_in: 900,
+            user: user(password_hash).into(),
+        };
+
+        vec![
+            serde_json::to_value(PublicUser::from(user(password_hash))).unwrap(),
+            serde_json::to_value(AdminUserView::from(user(password_hash))).unwrap(),
+            serde_json::to_value(auth).unwrap(),
+        ]
+    }
+
+    pub(crate) fn assert_no_hash_material<T: Serialize>(body: &T, password_hash: &str) {
+        let text = serde_json::to_string(body).unwrap();
+        assert!(!text.contains(password_hash), "hash leaked in {}", text);
+        assert!(!text.contains("password_hash"), "hash field in {}", text);
+        assert!(!text.contains("$argon2"), "argon2 hash in {}", text);
+        assert!(!text.contains("$2b$"), "bcrypt hash in {}", text);
+    }
+
+    #[test]
+    fn responses_never_contain_password_hashes() {
+        for password_hash in PASSWORD_HASHES {
+            for body in responses(password_hash) {
+                assert_no_hash_material(&body, password_hash);
+            }
+        }
+    }
+
+    #[test]
+    fn debug_output_redacts_password_hash() {
+        let text = format!("{:?}", user(ARGON2_HASH));
+        assert!(text.contains("alice"));
+        assert!(!text.contains(ARGON2_HASH));
+    }
+}
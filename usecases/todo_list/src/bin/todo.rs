@@ -0,0 +1,530 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Command-line client for the todo API
+#[derive(Debug, Parser)]
+#[command(name = "todo", about = "Manage todos from the terminal")]
+struct Cli {
+    /// Base URL of the todo_list API
+    #[arg(long, env = "TODO_API_URL", default_value = "http://127.0.0.1:3000")]
+    api_url: String,
+
+    /// Base URL of the auth_api service
+    #[arg(long, env = "TODO_AUTH_URL", default_value = "http://127.0.0.1:3000")]
+    auth_url: String,
+
+    /// Workspace the todos belong to
+    #[arg(long, short, env = "TODO_WORKSPACE", global = true)]
+    workspace: Option<i32>,
+
+    /// Output format
+    #[arg(long, short, value_enum, default_value_t = OutputFormat::Table, global = true)]
+    output: OutputFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Log in against auth_api and store the token locally
+    Login {
+        username: String,
+        /// Password (prompted for when omitted)
+        #[arg(long, env = "TODO_PASSWORD")]
+        password: Option<String>,
+        /// Authenticator code, prompted for when the account has MFA on
+        #[arg(long)]
+        otp: Option<String>,
+    },
+    /// Create a new todo
+    Add {
+        title: String,
+        /// Project to file the todo under
+        #[arg(long)]
+        project: Option<i32>,
+        /// Parse tags, priority, due date and recurrence out of the title
+        #[arg(long, short)]
+        quick: bool,
+        /// Time zone quick-add dates are interpreted in
+        #[arg(long, env = "TODO_TIME_ZONE")]
+        time_zone: Option<String>,
+    },
+    /// List todos
+    Ls {
+        /// Only show completed todos
+        #[arg(long, conflicts_with = "pending")]
+        done: bool,
+        /// Only show todos that are not completed
+        #[arg(long)]
+        pending: bool,
+        /// Only show todos created by the logged in user
+        #[arg(long)]
+        mine: bool,
+        /// Only show todos in this project
+        #[arg(long)]
+        project: Option<i32>,
+        /// Only show todos whose title contains this text
+        #[arg(long)]
+        search: Option<String>,
+    },
+    /// Mark a todo as completed
+    Done { id: i32 },
+    /// Change the title or completion state of a todo
+    Edit {
+        id: i32,
+        #[arg(long)]
+        title: Option<String>,
+        /// Mark the todo as not completed
+        #[arg(long)]
+        undone: bool,
+    },
+    /// Delete a todo
+    Rm { id: i32 },
+}
+
+// Mirrors the Todo model served by the API
+#[derive(Debug, Serialize, Deserialize)]
+struct Todo {
+    id: i32,
+    title: String,
+    completed: bool,
+    user_id: i32,
+    project_id: Option<i32>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Credentials persisted between invocations
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    token: String,
+    user_id: i32,
+    username: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    token: String,
+    user: LoginUser,
+}
+
+// `/auth/login` answers with tokens, or with a challenge when the account has
+// MFA on
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LoginReply {
+    Tokens(LoginResponse),
+    MfaRequired { mfa_token: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginUser {
+    id: i32,
+    username: String,
+}
+
+type CliResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> CliResult<()> {
+    let client = reqwest::Client::new();
+
+    match cli.command {
+        Command::Login {
+            username,
+            password,
+            otp,
+        } => {
+            let password = match password {
+                Some(password) => password,
+                None => prompt_password("Password: ")?,
+            };
+            let response = client
+                .post(format!("{}/auth/login", cli.auth_url))
+                .json(&serde_json::json!({ "username": username, "password": password }))
+                .send()
+                .await?;
+            let login = match check(response).await?.json::<LoginReply>().await? {
+                LoginReply::Tokens(login) => login,
+                LoginReply::MfaRequired { mfa_token } => {
+                    let code = match otp {
+                        Some(otp) => otp,
+                        None => prompt("Authenticator code: ")?,
+                    };
+                    let response = client
+                        .post(format!("{}/auth/mfa/verify", cli.auth_url))
+                        .json(&serde_json::json!({ "mfa_token": mfa_token, "code": code }))
+                        .send()
+                        .await?;
+                    check(response).await?.json().await?
+                }
+            };
+
+            let session = Session {
+                token: login.token,
+                user_id: login.user.id,
+                username: login.user.username,
+            };
+            save_session(&session)?;
+            println!("Logged in as {}", session.username);
+        }
+        Command::Add {
+            title,
+            project,
+            quick,
+            time_zone,
+        } => {
+            let session = load_session()?;
+            let response = client
+                .post(todos_url(&cli.api_url, cli.workspace)?)
+                .bearer_auth(&session.token)
+                .json(&serde_json::json!({
+                    "title": title,
+                    "project_id": project,
+                    "quick_add": quick,
+                    "time_zone": time_zone,
+                }))
+                .send()
+                .await?;
+            let todo: Todo = check(response).await?.json().await?;
+            print_todos(&[todo], cli.output)?;
+        }
+        Command::Ls {
+            done,
+            pending,
+            mine,
+            project,
+            search,
+        } => {
+            let session = load_session()?;
+            let mut query: Vec<(&str, String)> = Vec::new();
+            if done || pending {
+                query.push(("completed", done.to_string()));
+            }
+            if mine {
+                query.push(("user_id", session.user_id.to_string()));
+            }
+            if let Some(project) = project {
+                query.push(("project_id", project.to_string()));
+            }
+            if let Some(search) = search {
+                query.push(("search", search));
+            }
+            let response = client
+                .get(todos_url(&cli.api_url, cli.workspace)?)
+                .bearer_auth(&session.token)
+                .query(&query)
+                .send()
+                .await?;
+            let todos: Vec<Todo> = check(response).await?.json().await?;
+            print_todos(&todos, cli.output)?;
+        }
+        Command::Done { id } => {
+            let url = format!("{}/{}", todos_url(&cli.api_url, cli.workspace)?, id);
+            let todo = update(&client, &url, None, Some(true)).await?;
+            print_todos(&[todo], cli.output)?;
+        }
+        Command::Edit { id, title, undone } => {
+            let completed = if undone { Some(false) } else { None };
+            let url = format!("{}/{}", todos_url(&cli.api_url, cli.workspace)?, id);
+            let todo = update(&client, &url, title, completed).await?;
+            print_todos(&[todo], cli.output)?;
+        }
+        Command::Rm { id } => {
+            let session = load_session()?;
+            let response = client
+                .delete(format!(
+                    "{}/{}",
+                    todos_url(&cli.api_url, cli.workspace)?,
+                    id
+                ))
+                .bearer_auth(&session.token)
+                .send()
+                .await?;
+            check(response).await?;
+            println!("Deleted todo {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+// Helper functions
+fn todos_url(api_url: &str, workspace: Option<i32>) -> CliResult<String> {
+    let workspace =
+        workspace.ok_or("no workspace selected, pass --workspace or set TODO_WORKSPACE")?;
+    Ok(format!("{}/workspaces/{}/todos", api_url, workspace))
+}
+
+async fn update(
+    client: &reqwest::Client,
+    url: &str,
+    title: Option<String>,
+    completed: Option<bool>,
+) -> CliResult<Todo> {
+    let session = load_session()?;
+    let response = client
+        .put(url)
+        .bearer_auth(&session.token)
+        .json(&serde_json::json!({ "title": title, "completed": completed }))
+        .send()
+        .await?;
+    Ok(check(response).await?.json().await?)
+}
+
+// Turn non-2xx responses into errors carrying the server's message
+async fn check(response: reqwest::Response) -> CliResult<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(format!("{} {}", status, body).into())
+}
+
+fn print_todos(todos: &[Todo], format: OutputFormat) -> CliResult<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(todos)?),
+        OutputFormat::Table => {
+            let width = todos
+                .iter()
+                .map(|t| t.title.chars().count())
+                .max()
+                .unwrap_or(0)
+                .max("TITLE".len());
+            println!("{:>5}  {:<4}  {:<width$}  CREATED", "ID", "DONE", "TITLE");
+            for todo in todos {
+                println!(
+                    "{:>5}  {:<4}  {:<width$}  {}",
+                    todo.id,
+                    if todo.completed { "x" } else { "" },
+                    todo.title,
+                    todo.created_at.format("%Y-%m-%d %H:%M"),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn session_path() -> CliResult<PathBuf> {
+    if let Ok(path) = std::env::var("TODO_SESSION_FILE") {
+        return Ok(PathBuf::from(path));
+    }
+    let home = std::env::var("HOME").map_err(|_| "HOME is not set")?;
+    Ok(PathBuf::from(home)
+        .join(".config")
+        .join("todo")
+        .join("session.json"))
+}
+
+fn save_session(session: &Session) -> CliResult<()> {
+    write_session(&session_path()?, session)
+}
+
+fn load_session() -> CliResult<Session> {
+    read_session(&session_path()?)
+}
+
+fn write_session(path: &Path, session: &Session) -> CliResult<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // The token grants full access, so the file is private to the current
+    // user from the moment it exists
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+
+    // The mode only applies to new files, tighten one left by older versions
+    // before the token goes in
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    file.write_all(&serde_json::to_vec_pretty(session)?)?;
+    Ok(())
+}
+
+fn read_session(path: &Path) -> CliResult<Session> {
+    let contents =
+        std::fs::read(path).map_err(|_| "not logged in, run `todo login <username>` first")?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+// Read the password without echoing it to the terminal
+fn prompt_password(message: &str) -> CliResult<String> {
+    Ok(rpassword::prompt_password(message)?)
+}
+
+fn prompt(message: &str) -> CliResult<String> {
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("todo").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn parses_global_flags_after_subcommand() {
+        let cli = parse(&[
+            "ls",
+            "--pending",
+            "-w",
+            "3",
+            "-o",
+            "json",
+            "--search",
+            "milk",
+        ]);
+        assert_eq!(cli.workspace, Some(3));
+        assert!(matches!(cli.output, OutputFormat::Json));
+        match cli.command {
+            Command::Ls {
+                done,
+                pending,
+                search,
+                ..
+            } => {
+                assert!(!done && pending);
+                assert_eq!(search.as_deref(), Some("milk"));
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_conflicting_filters() {
+        assert!(Cli::try_parse_from(["todo", "ls", "--done", "--pending"]).is_err());
+    }
+
+    #[test]
+    fn parses_quick_add() {
+        match parse(&["add", "Buy milk tomorrow", "--quick", "--project", "2"]).command {
+            Command::Add {
+                title,
+                project,
+                quick,
+                time_zone,
+            } => {
+                assert_eq!(title, "Buy milk tomorrow");
+                assert_eq!(project, Some(2));
+                assert!(quick);
+                assert_eq!(time_zone, None);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tells_tokens_from_mfa_challenges() {
+        let tokens = r#"{"token": "t", "refresh_token": "r", "expires_in": 900,
+            "user": {"id": 4, "username": "alice", "email": "alice@example.com"}}"#;
+        match serde_json::from_str::<LoginReply>(tokens).unwrap() {
+            LoginReply::Tokens(login) => {
+                assert_eq!(login.token, "t");
+                assert_eq!(login.user.id, 4);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let challenge = r#"{"mfa_required": true, "mfa_token": "m", "expires_in": 300}"#;
+        match serde_json::from_str::<LoginReply>(challenge).unwrap() {
+            LoginReply::MfaRequired { mfa_token } => assert_eq!(mfa_token, "m"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn todos_url_requires_workspace() {
+        assert_eq!(
+            todos_url("http://api", Some(4)).unwrap(),
+            "http://api/workspaces/4/todos"
+        );
+        assert!(todos_url("http://api", None).is_err());
+    }
+
+    #[test]
+    fn session_round_trips_through_private_file() {
+        let dir = std::env::temp_dir().join(format!("todo-session-{}", std::process::id()));
+        let path = dir.join("nested").join("session.json");
+        let session = Session {
+            token: "secret".to_string(),
+            user_id: 7,
+            username: "alice".to_string(),
+        };
+
+        write_session(&path, &session).unwrap();
+        let loaded = read_session(&path).unwrap();
+        assert_eq!(loaded.token, "secret");
+        assert_eq!(loaded.user_id, 7);
+        assert_eq!(loaded.username, "alice");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(read_session(&path).is_err());
+    }
+
+    #[test]
+    fn tightens_existing_session_file() {
+        let dir = std::env::temp_dir().join(format!("todo-session-old-{}", std::process::id()));
+        let path = dir.join("session.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "{}").unwrap();
+
+        let session = Session {
+            token: "secret".to_string(),
+            user_id: 1,
+            username: "bob".to_string(),
+        };
+        write_session(&path, &session).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(read_session(&path).unwrap().username, "bob");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
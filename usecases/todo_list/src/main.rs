@@ -1,9 +1,10 @@
+use auth_api::authz::{Authenticated, Authenticator, JwksAuthenticator, JwtAuthenticator};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     routing::{delete, get, post, put},
-    Json, Router,
+    Extension, Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
@@ -12,6 +13,11 @@ use tokio::sync::RwLock;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
+mod quick_add;
+mod stats;
+mod templates;
+mod workspaces;
+
 // Define our Todo model
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 struct Todo {
@@ -19,20 +25,51 @@ struct Todo {
     title: String,
     completed: bool,
     user_id: i32,
+    workspace_id: i32,
+    project_id: Option<i32>,
+    parent_id: Option<i32>,
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
+    tags: Vec<String>,
+    priority: Option<String>,
+    recurrence: Option<sqlx::types::Json<quick_add::Recurrence>>,
     created_at: chrono::DateTime<chrono::Utc>,
+    completed_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 // Define our request/response types
 #[derive(Debug, Deserialize)]
 struct CreateTodoRequest {
     title: String,
-    user_id: i32,
+    project_id: Option<i32>,
+    parent_id: Option<i32>,
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    tags: Vec<String>,
+    priority: Option<quick_add::Priority>,
+    recurrence: Option<quick_add::Recurrence>,
+    // Parse tags, priority, due date and recurrence out of the title
+    #[serde(default)]
+    quick_add: bool,
+    // IANA time zone quick-add dates are interpreted in, defaults to UTC
+    time_zone: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListTodosQuery {
+    completed: Option<bool>,
+    user_id: Option<i32>,
+    project_id: Option<i32>,
+    parent_id: Option<i32>,
+    tag: Option<String>,
+    priority: Option<quick_add::Priority>,
+    search: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct UpdateTodoRequest {
     title: Option<String>,
     completed: Option<bool>,
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 // Define our application state
@@ -40,6 +77,108 @@ struct AppState {
     db: PgPool,
 }
 
+// Schema statements, executed in order on startup
+const SCHEMA: &[&str] = &[
+    r#"
+    CREATE TABLE IF NOT EXISTS workspaces (
+        id SERIAL PRIMARY KEY,
+        name VARCHAR(255) NOT NULL,
+        settings JSONB NOT NULL DEFAULT '{}',
+        created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS workspace_members (
+        workspace_id INTEGER NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        role VARCHAR(16) NOT NULL DEFAULT 'member',
+        joined_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (workspace_id, user_id)
+    )
+    "#,
+    // Upgrade member tables created before roles existed: the earliest member
+    // of a workspace without an owner becomes its owner
+    "ALTER TABLE workspace_members ADD COLUMN IF NOT EXISTS role VARCHAR(16) NOT NULL DEFAULT 'member'",
+    r#"
+    UPDATE workspace_members m SET role = 'owner'
+    WHERE (m.workspace_id, m.user_id) IN (
+        SELECT DISTINCT ON (workspace_id) workspace_id, user_id
+        FROM workspace_members
+        ORDER BY workspace_id, joined_at, user_id
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM workspace_members o
+        WHERE o.workspace_id = m.workspace_id AND o.role = 'owner'
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS projects (
+        id SERIAL PRIMARY KEY,
+        workspace_id INTEGER NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+        name VARCHAR(255) NOT NULL,
+        created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS todos (
+        id SERIAL PRIMARY KEY,
+        title VARCHAR(255) NOT NULL,
+        completed BOOLEAN NOT NULL DEFAULT false,
+        user_id INTEGER NOT NULL,
+        workspace_id INTEGER NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+        project_id INTEGER REFERENCES projects(id) ON DELETE SET NULL,
+        parent_id INTEGER REFERENCES todos(id) ON DELETE CASCADE,
+        due_at TIMESTAMP WITH TIME ZONE,
+        tags TEXT[] NOT NULL DEFAULT '{}',
+        priority VARCHAR(16),
+        recurrence JSONB,
+        created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        completed_at TIMESTAMP WITH TIME ZONE,
+        FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+    )
+    "#,
+    // Upgrade tables created before workspaces existed. Rows left without a
+    // workspace are invisible to every workspace-scoped query.
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS workspace_id INTEGER REFERENCES workspaces(id) ON DELETE CASCADE",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS project_id INTEGER REFERENCES projects(id) ON DELETE SET NULL",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS parent_id INTEGER REFERENCES todos(id) ON DELETE CASCADE",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS due_at TIMESTAMP WITH TIME ZONE",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}'",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS priority VARCHAR(16)",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS recurrence JSONB",
+    "ALTER TABLE todos ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP WITH TIME ZONE",
+    // Todos go with their user when auth_api purges the account
+    r#"
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM pg_constraint
+            WHERE conname = 'todos_user_id_fkey' AND confdeltype <> 'c'
+        ) THEN
+            ALTER TABLE todos DROP CONSTRAINT todos_user_id_fkey;
+            ALTER TABLE todos ADD CONSTRAINT todos_user_id_fkey
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        END IF;
+    END
+    $$
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS templates (
+        id SERIAL PRIMARY KEY,
+        workspace_id INTEGER NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+        name VARCHAR(255) NOT NULL,
+        body JSONB NOT NULL,
+        created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_todos_workspace_id ON todos(workspace_id)",
+    "CREATE INDEX IF NOT EXISTS idx_todos_parent_id ON todos(parent_id)",
+    "CREATE INDEX IF NOT EXISTS idx_todos_created_at ON todos(workspace_id, created_at)",
+    "CREATE INDEX IF NOT EXISTS idx_todos_completed_at ON todos(workspace_id, completed_at)",
+    "CREATE INDEX IF NOT EXISTS idx_templates_workspace_id ON templates(workspace_id)",
+    "CREATE INDEX IF NOT EXISTS idx_projects_workspace_id ON projects(workspace_id)",
+];
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize tracing
@@ -56,32 +195,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let pool = PgPool::connect(&database_url).await?;
 
     // Create tables if they don't exist
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS todos (
-            id SERIAL PRIMARY KEY,
-            title VARCHAR(255) NOT NULL,
-            completed BOOLEAN NOT NULL DEFAULT false,
-            user_id INTEGER NOT NULL,
-            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (user_id) REFERENCES users(id)
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await?;
+    for statement in SCHEMA {
+        sqlx::query(statement).execute(&pool).await?;
+    }
+
+    // Row-level security is optional defense in depth on top of the
+    // workspace filters every handler applies
+    let enable_rls = std::env::var("TODO_ENABLE_RLS")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if enable_rls {
+        for table in workspaces::SCOPED_TABLES {
+            for statement in workspaces::rls_statements(table) {
+                sqlx::query(&statement).execute(&pool).await?;
+            }
+        }
+        info!("Row-level security enabled for workspace tables");
+    }
 
     // Create application state
     let state = Arc::new(AppState { db: pool });
 
-    // Build our application with a route
-    let app = Router::new()
-        .route("/todos", get(list_todos))
-        .route("/todos", post(create_todo))
-        .route("/todos/:id", get(get_todo))
-        .route("/todos/:id", put(update_todo))
-        .route("/todos/:id", delete(delete_todo))
-        .with_state(state);
+    // Callers authenticate with access tokens issued by auth_api, verified
+    // against its published keys or the shared HS256 secret
+    let authenticator: Arc<dyn Authenticator> = match std::env::var("AUTH_JWKS_URL") {
+        Ok(url) => Arc::new(JwksAuthenticator::new(url)),
+        Err(_) => {
+            let secret =
+                std::env::var("JWT_SECRET").expect("AUTH_JWKS_URL or JWT_SECRET must be set");
+            Arc::new(JwtAuthenticator::from_secret(secret.as_bytes()))
+        }
+    };
+
+    let app = router(state, authenticator);
 
     // Run it
     let addr = "127.0.0.1:3000";
@@ -93,45 +239,208 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Build our application with its routes
+fn router(state: Arc<AppState>, authenticator: Arc<dyn Authenticator>) -> Router {
+    Router::new()
+        .route("/workspaces", post(workspaces::create_workspace))
+        .route("/workspaces/:workspace_id", get(workspaces::get_workspace))
+        .route(
+            "/workspaces/:workspace_id/settings",
+            get(workspaces::get_settings).put(workspaces::update_settings),
+        )
+        .route(
+            "/workspaces/:workspace_id/members",
+            get(workspaces::list_members).post(workspaces::add_member),
+        )
+        .route(
+            "/workspaces/:workspace_id/members/:user_id",
+            delete(workspaces::remove_member),
+        )
+        .route(
+            "/workspaces/:workspace_id/projects",
+            get(workspaces::list_projects).post(workspaces::create_project),
+        )
+        .route(
+            "/workspaces/:workspace_id/projects/:id/template",
+            post(templates::save_project_as_template),
+        )
+        .route(
+            "/workspaces/:workspace_id/templates",
+            get(templates::list_templates).post(templates::create_template),
+        )
+        .route(
+            "/workspaces/:workspace_id/templates/:id",
+            get(templates::get_template).delete(templates::delete_template),
+        )
+        .route(
+            "/workspaces/:workspace_id/templates/:id/instantiate",
+            post(templates::instantiate_template),
+        )
+        .route("/workspaces/:workspace_id/todos", get(list_todos))
+        .route("/workspaces/:workspace_id/todos", post(create_todo))
+        .route(
+            "/workspaces/:workspace_id/todos/stats",
+            get(stats::todo_stats),
+        )
+        .route("/workspaces/:workspace_id/todos/:id", get(get_todo))
+        .route("/workspaces/:workspace_id/todos/:id", put(update_todo))
+        .route("/workspaces/:workspace_id/todos/:id", delete(delete_todo))
+        .route(
+            "/workspaces/:workspace_id/todos/:id/template",
+            post(templates::save_todo_as_template),
+        )
+        .layer(Extension(authenticator))
+        .with_state(state)
+}
+
+// Substring pattern for ILIKE, with the wildcards in `q` matched literally
+fn like_pattern(q: &str) -> String {
+    let escaped = q
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
 // Handler functions
 async fn list_todos(
     State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+    Query(query): Query<ListTodosQuery>,
 ) -> Result<Json<Vec<Todo>>, (StatusCode, String)> {
-    let todos = sqlx::query_as::<_, Todo>("SELECT * FROM todos ORDER BY created_at DESC")
-        .fetch_all(&state.db)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let todos = sqlx::query_as::<_, Todo>(
+        r#"
+        SELECT * FROM todos
+        WHERE workspace_id = $1
+          AND ($2::BOOLEAN IS NULL OR completed = $2)
+          AND ($3::INTEGER IS NULL OR user_id = $3)
+          AND ($4::INTEGER IS NULL OR project_id = $4)
+          AND ($5::INTEGER IS NULL OR parent_id = $5)
+          AND ($6::TEXT IS NULL OR $6 = ANY(tags))
+          AND ($7::TEXT IS NULL OR priority = $7)
+          AND ($8::TEXT IS NULL OR title ILIKE $8 ESCAPE '\')
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(workspace_id)
+    .bind(query.completed)
+    .bind(query.user_id)
+    .bind(query.project_id)
+    .bind(query.parent_id)
+    .bind(query.tag)
+    .bind(query.priority.map(|p| p.as_str()))
+    .bind(query.search.as_deref().map(like_pattern))
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     Ok(Json(todos))
 }
 
 async fn create_todo(
     State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
     Json(payload): Json<CreateTodoRequest>,
 ) -> Result<Json<Todo>, (StatusCode, String)> {
+    let mut title = payload.title;
+    let mut tags = payload.tags;
+    let mut priority = payload.priority;
+    let mut due_at = payload.due_at;
+    let mut recurrence = payload.recurrence;
+
+    // Explicit fields win over anything parsed out of the title
+    if payload.quick_add {
+        let tz = match &payload.time_zone {
+            Some(name) => name.parse::<chrono_tz::Tz>().map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown time zone \"{}\"", name),
+                )
+            })?,
+            None => chrono_tz::UTC,
+        };
+        let parsed = quick_add::parse(&title, chrono::Utc::now().with_timezone(&tz));
+
+        title = parsed.title;
+        for tag in parsed.tags {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        priority = priority.or(parsed.priority);
+        due_at = due_at.or(parsed.due_at);
+        recurrence = recurrence.or(parsed.recurrence);
+    }
+
+    if title.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Title must not be empty".to_string(),
+        ));
+    }
+
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let settings = workspaces::load_settings(&mut tx, workspace_id).await?;
+
+    let project_id = payload.project_id.or(settings.default_project_id);
+    if let Some(project_id) = project_id {
+        workspaces::ensure_project(&mut tx, workspace_id, project_id).await?;
+    }
+
+    // Subtasks must live in the same workspace as their parent
+    if let Some(parent_id) = payload.parent_id {
+        sqlx::query("SELECT id FROM todos WHERE id = $1 AND workspace_id = $2")
+            .bind(parent_id)
+            .bind(workspace_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .ok_or((StatusCode::NOT_FOUND, "Parent todo not found".to_string()))?;
+    }
+
+    workspaces::ensure_open_todo_capacity(&mut tx, workspace_id, claims.sub, &settings, 1).await?;
+
     let todo = sqlx::query_as::<_, Todo>(
         r#"
-        INSERT INTO todos (title, user_id)
-        VALUES ($1, $2)
+        INSERT INTO todos
+            (title, user_id, workspace_id, project_id, parent_id, due_at, tags, priority, recurrence)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
         RETURNING *
         "#,
     )
-    .bind(&payload.title)
-    .bind(payload.user_id)
-    .fetch_one(&state.db)
+    .bind(&title)
+    .bind(claims.sub)
+    .bind(workspace_id)
+    .bind(project_id)
+    .bind(payload.parent_id)
+    .bind(due_at)
+    .bind(&tags)
+    .bind(priority.map(|p| p.as_str()))
+    .bind(recurrence.map(sqlx::types::Json))
+    .fetch_one(&mut *tx)
     .await
     .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
     Ok(Json(todo))
 }
 
 async fn get_todo(
     State(state): State<Arc<AppState>>,
-    Path(id): Path<i32>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, id)): Path<(i32, i32)>,
 ) -> Result<Json<Todo>, (StatusCode, String)> {
-    let todo = sqlx::query_as::<_, Todo>("SELECT * FROM todos WHERE id = $1")
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let todo = sqlx::query_as::<_, Todo>("SELECT * FROM todos WHERE id = $1 AND workspace_id = $2")
         .bind(id)
-        .fetch_optional(&state.db)
+        .bind(workspace_id)
+        .fetch_optional(&mut *tx)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
         .ok_or((StatusCode::NOT_FOUND, "Todo not found".to_string()))?;
@@ -141,37 +450,74 @@ async fn get_todo(
 
 async fn update_todo(
     State(state): State<Arc<AppState>>,
-    Path(id): Path<i32>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, id)): Path<(i32, i32)>,
     Json(payload): Json<UpdateTodoRequest>,
 ) -> Result<Json<Todo>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+
+    // Reopening a todo counts against its owner's open todo limit
+    if payload.completed == Some(false) {
+        let current = sqlx::query_as::<_, (i32, bool)>(
+            "SELECT user_id, completed FROM todos WHERE id = $1 AND workspace_id = $2 FOR UPDATE",
+        )
+        .bind(id)
+        .bind(workspace_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Todo not found".to_string()))?;
+
+        if let (owner, true) = current {
+            let settings = workspaces::load_settings(&mut tx, workspace_id).await?;
+            workspaces::ensure_open_todo_capacity(&mut tx, workspace_id, owner, &settings, 1)
+                .await?;
+        }
+    }
+
     let todo = sqlx::query_as::<_, Todo>(
         r#"
         UPDATE todos
         SET 
             title = COALESCE($1, title),
-            completed = COALESCE($2, completed)
-        WHERE id = $3
+            completed = COALESCE($2, completed),
+            completed_at = CASE
+                WHEN $2 IS TRUE AND NOT completed THEN NOW()
+                WHEN $2 IS FALSE THEN NULL
+                ELSE completed_at
+            END,
+            due_at = COALESCE($5, due_at)
+        WHERE id = $3 AND workspace_id = $4
         RETURNING *
         "#,
     )
     .bind(payload.title)
     .bind(payload.completed)
     .bind(id)
-    .fetch_optional(&state.db)
+    .bind(workspace_id)
+    .bind(payload.due_at)
+    .fetch_optional(&mut *tx)
     .await
     .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
     .ok_or((StatusCode::NOT_FOUND, "Todo not found".to_string()))?;
 
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
     Ok(Json(todo))
 }
 
 async fn delete_todo(
     State(state): State<Arc<AppState>>,
-    Path(id): Path<i32>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, id)): Path<(i32, i32)>,
 ) -> Result<StatusCode, (StatusCode, String)> {
-    let result = sqlx::query("DELETE FROM todos WHERE id = $1")
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let result = sqlx::query("DELETE FROM todos WHERE id = $1 AND workspace_id = $2")
         .bind(id)
-        .execute(&state.db)
+        .bind(workspace_id)
+        .execute(&mut *tx)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
@@ -179,5 +525,21 @@ async fn delete_todo(
         return Err((StatusCode::NOT_FOUND, "Todo not found".to_string()));
     }
 
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
     Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(like_pattern("milk"), "%milk%");
+        assert_eq!(like_pattern("50%_off"), "%50\\%\\_off%");
+        assert_eq!(like_pattern("a\\b"), "%a\\\\b%");
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,914 @@
+// Quick-add parser
+//
+// Turns free text such as "Pay rent every month on the 1st #finance !high
+// tomorrow 9am" into a title plus structured fields. Recognised fragments are
+// removed from the title; everything else is kept verbatim.
+//
+// Supported syntax:
+// - tags: `#finance`
+// - priority: `!low`, `!medium`, `!high`, `!urgent` or `!1` (urgent) to `!4` (low)
+// - dates: `today`, `tomorrow`, `monday`, `on fri`, `this friday`,
+//   `next tuesday`, `next week`, `next month`, `april 3`, `3rd april`,
+//   `2024-04-01`, `in 3 days`
+// - times: `9am`, `9:30pm`, `at 9`, `17:00`, `noon`, `midnight`
+// - relative instants: `in 2 hours`, `in 30 minutes`
+// - recurrence: `daily`, `weekly`, `every day`, `every other week`,
+//   `every 3 months`, `every monday`, `every weekday`, `every month on the 1st`
+//
+// Dates and times are interpreted in the time zone of `now`. Day name
+// abbreviations such as `sun` or `wed` only count after `on`, `next` or
+// `this`, and amounts above `MAX_AMOUNT` are left in the title.
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Medium => "medium",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recurrence {
+    pub frequency: Frequency,
+    pub interval: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weekdays: Vec<Weekday>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub day_of_month: Option<u32>,
+}
+
+impl Recurrence {
+    fn every(frequency: Frequency, interval: u32) -> Self {
+        Recurrence {
+            frequency,
+            interval,
+            weekdays: Vec::new(),
+            day_of_month: None,
+        }
+    }
+
+    // First date on or after `date` that matches the rule, if representable
+    pub fn first_on_or_after(&self, date: NaiveDate) -> Option<NaiveDate> {
+        if !self.weekdays.is_empty() {
+            return (0..7)
+                .map_while(|offset| date.checked_add_signed(Duration::days(offset)))
+                .find(|d| self.weekdays.contains(&d.weekday()));
+        }
+
+        if let Some(day) = self.day_of_month {
+            let this_month = day_in_month(date.year(), date.month(), day)?;
+            if this_month >= date {
+                return Some(this_month);
+            }
+            let (year, month) = add_months(date.year(), date.month(), 1)?;
+            return day_in_month(year, month, day);
+        }
+
+        Some(date)
+    }
+}
+
+// Result of parsing a quick-add string
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuickAdd {
+    pub title: String,
+    pub tags: Vec<String>,
+    pub priority: Option<Priority>,
+    pub due_at: Option<DateTime<Utc>>,
+    pub recurrence: Option<Recurrence>,
+}
+
+// Time used when a date is given without a time of day
+const END_OF_DAY: (u32, u32, u32) = (23, 59, 59);
+
+// Largest number accepted in "in 3 days" or "in 2 hours". Bigger amounts are
+// more likely part of the title than a due date, and would overflow dates.
+const MAX_AMOUNT: i64 = 10_000;
+
+pub fn parse<Tz: TimeZone>(input: &str, now: DateTime<Tz>) -> QuickAdd {
+    let tokens: Vec<&str> = input.split_whitespace().collect();
+    let words: Vec<String> = tokens.iter().map(|t| normalize(t)).collect();
+    let today = now.date_naive();
+
+    let mut title = Vec::new();
+    let mut tags: Vec<String> = Vec::new();
+    let mut priority = None;
+    let mut date = None;
+    let mut time = None;
+    let mut offset = None;
+    let mut recurrence = None;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let rest = &words[i..];
+
+        if let Some(tag) = parse_tag(tokens[i]) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+            i += 1;
+        } else if let Some(p) = parse_priority(&words[i]) {
+            priority = Some(p);
+            i += 1;
+        } else if let Some((used, r)) = parse_recurrence(rest) {
+            recurrence = Some(r);
+            i += used;
+        } else if let Some((used, d)) = parse_offset(rest) {
+            offset = Some(d);
+            i += used;
+        } else if let Some((used, d)) = parse_date(rest, today) {
+            date = Some(d);
+            i += used;
+        } else if let Some((used, t)) = parse_time(rest) {
+            time = Some(t);
+            i += used;
+        } else {
+            title.push(tokens[i]);
+            i += 1;
+        }
+    }
+
+    let end_of_day =
+        NaiveTime::from_hms_opt(END_OF_DAY.0, END_OF_DAY.1, END_OF_DAY.2).expect("valid time");
+    let due_at = if let Some(offset) = offset {
+        now.clone()
+            .checked_add_signed(offset)
+            .map(|due| due.with_timezone(&Utc))
+    } else if date.is_some() || time.is_some() || recurrence.is_some() {
+        let at = time.unwrap_or(end_of_day);
+        let first = |from: NaiveDate| match (&date, &recurrence) {
+            (Some(d), _) => Some(*d),
+            (None, Some(r)) => r.first_on_or_after(from),
+            (None, None) => Some(from),
+        };
+
+        // A bare time or rule that already passed today moves to the next match
+        let mut day = first(today);
+        if date.is_none()
+            && day == Some(today)
+            && to_utc(&now.timezone(), today, at) <= now.with_timezone(&Utc)
+        {
+            day = today.succ_opt().and_then(first);
+        }
+        day.map(|day| to_utc(&now.timezone(), day, at))
+    } else {
+        None
+    };
+
+    QuickAdd {
+        title: title.join(" "),
+        tags,
+        priority,
+        due_at,
+        recurrence,
+    }
+}
+
+// Resolve a local date and time, moving forward past DST gaps
+fn to_utc<Tz: TimeZone>(tz: &Tz, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+    let local = date.and_time(time);
+    (0..3)
+        .map_while(|hours| local.checked_add_signed(Duration::hours(hours)))
+        .find_map(|local| tz.from_local_datetime(&local).earliest())
+        .map(|dt| dt.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+}
+
+// Lowercase and drop trailing punctuation so "Tomorrow," matches "tomorrow"
+fn normalize(token: &str) -> String {
+    token.trim_end_matches([',', '.', ';']).to_lowercase()
+}
+
+fn parse_tag(token: &str) -> Option<String> {
+    let tag = token.strip_prefix('#')?.trim_end_matches([',', '.', ';']);
+    let valid = tag.chars().next()?.is_alphabetic()
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '/'));
+    valid.then(|| tag.to_lowercase())
+}
+
+fn parse_priority(word: &str) -> Option<Priority> {
+    match word.strip_prefix('!')? {
+        "low" | "4" => Some(Priority::Low),
+        "medium" | "med" | "normal" | "3" => Some(Priority::Medium),
+        "high" | "2" => Some(Priority::High),
+        "urgent" | "1" => Some(Priority::Urgent),
+        _ => None,
+    }
+}
+
+fn parse_recurrence(words: &[String]) -> Option<(usize, Recurrence)> {
+    let (mut used, mut recurrence) = match words.first()?.as_str() {
+        "daily" => (1, Recurrence::every(Frequency::Daily, 1)),
+        "weekly" => (1, Recurrence::every(Frequency::Weekly, 1)),
+        "monthly" => (1, Recurrence::every(Frequency::Monthly, 1)),
+        "yearly" | "annually" => (1, Recurrence::every(Frequency::Yearly, 1)),
+        "every" => parse_every(&words[1..]).map(|(n, r)| (n + 1, r))?,
+        _ => return None,
+    };
+
+    // Optional anchor: "on the 1st" for months, "on friday" for weeks
+    if words.get(used).map(String::as_str) == Some("on") {
+        match recurrence.frequency {
+            Frequency::Monthly => {
+                let skip = usize::from(words.get(used + 1).map(String::as_str) == Some("the"));
+                if let Some(day) = words.get(used + 1 + skip).and_then(|w| parse_ordinal(w)) {
+                    recurrence.day_of_month = Some(day);
+                    used += 2 + skip;
+                }
+            }
+            Frequency::Weekly if recurrence.weekdays.is_empty() => {
+                if let Some(day) = words.get(used + 1).and_then(|w| parse_weekday(w)) {
+                    recurrence.weekdays = vec![day];
+                    used += 2;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some((used, recurrence))
+}
+
+fn parse_every(words: &[String]) -> Option<(usize, Recurrence)> {
+    let first = words.first()?.as_str();
+
+    match first {
+        "weekday" | "weekdays" => {
+            let mut r = Recurrence::every(Frequency::Weekly, 1);
+            r.weekdays = vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ];
+            return Some((1, r));
+        }
+        "weekend" | "weekends" => {
+            let mut r = Recurrence::every(Frequency::Weekly, 1);
+            r.weekdays = vec![Weekday::Sat, Weekday::Sun];
+            return Some((1, r));
+        }
+        _ => {}
+    }
+
+    if let Some(day) = parse_weekday(first).or_else(|| parse_weekday(first.strip_suffix('s')?)) {
+        let mut r = Recurrence::every(Frequency::Weekly, 1);
+        r.weekdays = vec![day];
+        return Some((1, r));
+    }
+
+    let (interval, unit_at) = match first {
+        "other" => (2, 1),
+        _ => match first.parse::<u32>() {
+            Ok(n) if n > 0 => (n, 1),
+            Ok(_) => return None,
+            Err(_) => (1, 0),
+        },
+    };
+    let frequency = parse_unit(words.get(unit_at)?)?;
+
+    Some((unit_at + 1, Recurrence::every(frequency, interval)))
+}
+
+fn parse_unit(word: &str) -> Option<Frequency> {
+    match word.trim_end_matches('s') {
+        "day" => Some(Frequency::Daily),
+        "week" => Some(Frequency::Weekly),
+        "month" => Some(Frequency::Monthly),
+        "year" => Some(Frequency::Yearly),
+        _ => None,
+    }
+}
+
+// Relative instants: "in 2 hours", "in 45 minutes"
+fn parse_offset(words: &[String]) -> Option<(usize, Duration)> {
+    if words.first()? != "in" {
+        return None;
+    }
+    let amount = parse_amount(words.get(1)?)?;
+    let duration = match words.get(2)?.trim_end_matches('s') {
+        "minute" | "min" => Duration::try_minutes(amount)?,
+        "hour" | "hr" => Duration::try_hours(amount)?,
+        _ => return None,
+    };
+    Some((3, duration))
+}
+
+fn parse_date(words: &[String], today: NaiveDate) -> Option<(usize, NaiveDate)> {
+    let first = words.first()?.as_str();
+
+    match first {
+        "today" => return Some((1, today)),
+        "tomorrow" | "tmrw" | "tmr" => return Some((1, today.succ_opt()?)),
+        "on" => {
+            return parse_weekday(words.get(1)?)
+                .and_then(|day| Some((2, upcoming(today, day)?)))
+                .or_else(|| parse_month_day(&words[1..], today).map(|(n, d)| (n + 1, d)));
+        }
+        "this" => {
+            let day = parse_weekday(words.get(1)?)?;
+            return Some((2, upcoming(today, day)?));
+        }
+        "next" => {
+            let second = words.get(1)?.as_str();
+            if let Some(day) = parse_weekday(second) {
+                let offset = Duration::days(day.num_days_from_monday() as i64);
+                return Some((2, start_of_next_week(today)?.checked_add_signed(offset)?));
+            }
+            return match second {
+                "week" => Some((2, start_of_next_week(today)?)),
+                "month" => {
+                    let (year, month) = add_months(today.year(), today.month(), 1)?;
+                    Some((2, NaiveDate::from_ymd_opt(year, month, 1)?))
+                }
+                _ => None,
+            };
+        }
+        "in" => {
+            let amount = parse_amount(words.get(1)?)?;
+            let date = match words.get(2)?.trim_end_matches('s') {
+                "day" => today.checked_add_signed(Duration::try_days(amount)?)?,
+                "week" => today.checked_add_signed(Duration::try_weeks(amount)?)?,
+                // Clamped to the end of shorter months
+                "month" => today.checked_add_months(Months::new(u32::try_from(amount).ok()?))?,
+                _ => return None,
+            };
+            return Some((3, date));
+        }
+        _ => {}
+    }
+
+    if let Some(day) = parse_weekday_name(first) {
+        return Some((1, upcoming(today, day)?));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(first, "%Y-%m-%d") {
+        return Some((1, date));
+    }
+
+    parse_month_day(words, today)
+}
+
+// "april 3", "apr 3rd", "3 april", "3rd of april". Dates that already passed
+// this year roll over to next year.
+fn parse_month_day(words: &[String], today: NaiveDate) -> Option<(usize, NaiveDate)> {
+    let (used, month, day) = if let Some(month) = parse_month(words.first()?) {
+        (2, month, parse_ordinal(words.get(1)?)?)
+    } else {
+        let day = parse_ordinal(words.first()?)?;
+        let skip = usize::from(words.get(1).map(String::as_str) == Some("of"));
+        (2 + skip, parse_month(words.get(1 + skip)?)?, day)
+    };
+
+    let date = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+    if date >= today {
+        Some((used, date))
+    } else {
+        Some((
+            used,
+            NaiveDate::from_ymd_opt(today.year().checked_add(1)?, month, day)?,
+        ))
+    }
+}
+
+fn parse_time(words: &[String]) -> Option<(usize, NaiveTime)> {
+    let (skip, explicit) = match words.first()?.as_str() {
+        "at" => (1, true),
+        _ => (0, false),
+    };
+    let word = words.get(skip)?.as_str();
+
+    match word {
+        "noon" | "midday" => return Some((skip + 1, NaiveTime::from_hms_opt(12, 0, 0)?)),
+        "midnight" => return Some((skip + 1, NaiveTime::from_hms_opt(0, 0, 0)?)),
+        _ => {}
+    }
+
+    // "9 am" written as two words
+    if let Some(meridiem) = words.get(skip + 1).filter(|w| *w == "am" || *w == "pm") {
+        if let Some(time) = parse_clock(&format!("{}{}", word, meridiem), explicit) {
+            return Some((skip + 2, time));
+        }
+    }
+
+    parse_clock(word, explicit).map(|time| (skip + 1, time))
+}
+
+// Accepts "9am", "9:30pm", "17:00" and, after "at", a bare hour like "9"
+fn parse_clock(word: &str, explicit: bool) -> Option<NaiveTime> {
+    let (digits, meridiem) = if let Some(d) = word.strip_suffix("am") {
+        (d, Some(false))
+    } else if let Some(d) = word.strip_suffix("pm") {
+        (d, Some(true))
+    } else {
+        (word, None)
+    };
+
+    let (hour, minute) = match digits.split_once(':') {
+        Some((h, m)) if m.len() == 2 => (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?),
+        Some(_) => return None,
+        None if meridiem.is_some() || explicit => (digits.parse::<u32>().ok()?, 0),
+        None => return None,
+    };
+
+    let hour = match meridiem {
+        Some(pm) if (1..=12).contains(&hour) => hour % 12 + if pm { 12 } else { 0 },
+        Some(_) => return None,
+        None => hour,
+    };
+
+    NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+fn parse_amount(word: &str) -> Option<i64> {
+    match word {
+        "a" | "an" | "one" => Some(1),
+        "two" => Some(2),
+        "three" => Some(3),
+        _ => word
+            .parse::<i64>()
+            .ok()
+            .filter(|n| (1..=MAX_AMOUNT).contains(n)),
+    }
+}
+
+fn parse_ordinal(word: &str) -> Option<u32> {
+    let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let suffix = &word[digits.len()..];
+    if !matches!(suffix, "" | "st" | "nd" | "rd" | "th") {
+        return None;
+    }
+    digits.parse::<u32>().ok().filter(|d| (1..=31).contains(d))
+}
+
+fn parse_weekday(word: &str) -> Option<Weekday> {
+    match word {
+        "monday" | "mon" => Some(Weekday::Mon),
+        "tuesday" | "tue" | "tues" => Some(Weekday::Tue),
+        "wednesday" | "wed" => Some(Weekday::Wed),
+        "thursday" | "thu" | "thurs" => Some(Weekday::Thu),
+        "friday" | "fri" => Some(Weekday::Fri),
+        "saturday" | "sat" => Some(Weekday::Sat),
+        "sunday" | "sun" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+// Full day names only. Abbreviations are also common words ("Fix the sun
+// roof"), so a bare one stays in the title.
+fn parse_weekday_name(word: &str) -> Option<Weekday> {
+    const NAMES: [&str; 7] = [
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+    ];
+    NAMES.contains(&word).then(|| parse_weekday(word)).flatten()
+}
+
+fn parse_month(word: &str) -> Option<u32> {
+    let months = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let names = [
+        "january",
+        "february",
+        "march",
+        "april",
+        "may",
+        "june",
+        "july",
+        "august",
+        "september",
+        "october",
+        "november",
+        "december",
+    ];
+    let word = if word == "sept" { "sep" } else { word };
+    months
+        .iter()
+        .zip(names.iter())
+        .position(|(short, long)| word == *short || word == *long)
+        .map(|i| i as u32 + 1)
+}
+
+// Soonest date on or after `today` falling on `day`
+fn upcoming(today: NaiveDate, day: Weekday) -> Option<NaiveDate> {
+    let ahead = (day.num_days_from_monday() + 7 - today.weekday().num_days_from_monday()) % 7;
+    today.checked_add_signed(Duration::days(ahead as i64))
+}
+
+// Monday of the week after the one containing `today`
+fn start_of_next_week(today: NaiveDate) -> Option<NaiveDate> {
+    today.checked_add_signed(Duration::days(
+        7 - today.weekday().num_days_from_monday() as i64,
+    ))
+}
+
+fn add_months(year: i32, month: u32, months: u32) -> Option<(i32, u32)> {
+    let zero_based = month.checked_sub(1)?.checked_add(months)?;
+    let year = year.checked_add(i32::try_from(zero_based / 12).ok()?)?;
+    Some((year, zero_based % 12 + 1))
+}
+
+// The given day of a month, clamped to the month's last day. None when the
+// month is outside the supported range.
+fn day_in_month(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+    (1..=day)
+        .rev()
+        .find_map(|d| NaiveDate::from_ymd_opt(year, month, d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    // Wednesday 2024-03-20 10:30 UTC
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 20, 10, 30, 0).unwrap()
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> Option<DateTime<Utc>> {
+        Some(Utc.with_ymd_and_hms(y, m, d, h, min, s).unwrap())
+    }
+
+    fn due(input: &str) -> Option<DateTime<Utc>> {
+        parse(input, now()).due_at
+    }
+
+    #[test]
+    fn test_plain_title() {
+        let parsed = parse("Buy milk", now());
+        assert_eq!(parsed.title, "Buy milk");
+        assert!(parsed.tags.is_empty());
+        assert_eq!(parsed.priority, None);
+        assert_eq!(parsed.due_at, None);
+        assert_eq!(parsed.recurrence, None);
+    }
+
+    #[test]
+    fn test_full_example() {
+        let parsed = parse(
+            "Pay rent every month on the 1st #finance !high tomorrow 9am",
+            now(),
+        );
+        assert_eq!(parsed.title, "Pay rent");
+        assert_eq!(parsed.tags, vec!["finance"]);
+        assert_eq!(parsed.priority, Some(Priority::High));
+        assert_eq!(parsed.due_at, utc(2024, 3, 21, 9, 0, 0));
+        assert_eq!(
+            parsed.recurrence,
+            Some(Recurrence {
+                frequency: Frequency::Monthly,
+                interval: 1,
+                weekdays: vec![],
+                day_of_month: Some(1),
+            })
+        );
+    }
+
+    #[test]
+    fn test_tags() {
+        let parsed = parse("Plan trip #Travel #family #travel", now());
+        assert_eq!(parsed.title, "Plan trip");
+        assert_eq!(parsed.tags, vec!["travel", "family"]);
+    }
+
+    #[test]
+    fn test_numeric_hash_stays_in_title() {
+        let parsed = parse("Fix issue #123 #bugs", now());
+        assert_eq!(parsed.title, "Fix issue #123");
+        assert_eq!(parsed.tags, vec!["bugs"]);
+    }
+
+    #[test]
+    fn test_priorities() {
+        assert_eq!(parse("a !low", now()).priority, Some(Priority::Low));
+        assert_eq!(parse("a !med", now()).priority, Some(Priority::Medium));
+        assert_eq!(parse("a !URGENT", now()).priority, Some(Priority::Urgent));
+        assert_eq!(parse("a !1", now()).priority, Some(Priority::Urgent));
+        assert_eq!(parse("a !4", now()).priority, Some(Priority::Low));
+        assert_eq!(parse("Wow !", now()).title, "Wow !");
+        assert_eq!(parse("a !later", now()).title, "a !later");
+    }
+
+    #[test]
+    fn test_today_and_tomorrow_without_time() {
+        assert_eq!(due("Call mom today"), utc(2024, 3, 20, 23, 59, 59));
+        assert_eq!(due("Call mom tomorrow"), utc(2024, 3, 21, 23, 59, 59));
+        assert_eq!(due("Call mom Tomorrow,"), utc(2024, 3, 21, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_time_formats() {
+        assert_eq!(due("x tomorrow 9am"), utc(2024, 3, 21, 9, 0, 0));
+        assert_eq!(due("x tomorrow 9:30pm"), utc(2024, 3, 21, 21, 30, 0));
+        assert_eq!(due("x tomorrow at 17:45"), utc(2024, 3, 21, 17, 45, 0));
+        assert_eq!(due("x tomorrow at 8"), utc(2024, 3, 21, 8, 0, 0));
+        assert_eq!(due("x tomorrow 7 pm"), utc(2024, 3, 21, 19, 0, 0));
+        assert_eq!(due("x tomorrow 12am"), utc(2024, 3, 21, 0, 0, 0));
+        assert_eq!(due("x tomorrow 12pm"), utc(2024, 3, 21, 12, 0, 0));
+        assert_eq!(due("x tomorrow noon"), utc(2024, 3, 21, 12, 0, 0));
+        assert_eq!(due("x tomorrow midnight"), utc(2024, 3, 21, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_invalid_times_stay_in_title() {
+        assert_eq!(parse("Read chapter 9", now()).title, "Read chapter 9");
+        assert_eq!(parse("Meet at 13pm", now()).title, "Meet at 13pm");
+        assert_eq!(parse("Run 25:00", now()).title, "Run 25:00");
+    }
+
+    #[test]
+    fn test_time_only_later_today() {
+        assert_eq!(due("Standup 11am"), utc(2024, 3, 20, 11, 0, 0));
+    }
+
+    #[test]
+    fn test_time_only_already_passed_moves_to_tomorrow() {
+        assert_eq!(due("Standup 9am"), utc(2024, 3, 21, 9, 0, 0));
+    }
+
+    #[test]
+    fn test_weekdays() {
+        // 2024-03-20 is a Wednesday
+        assert_eq!(due("x friday"), utc(2024, 3, 22, 23, 59, 59));
+        assert_eq!(due("x on fri"), utc(2024, 3, 22, 23, 59, 59));
+        assert_eq!(due("x wednesday"), utc(2024, 3, 20, 23, 59, 59));
+        assert_eq!(due("x monday 10am"), utc(2024, 3, 25, 10, 0, 0));
+        assert_eq!(due("x next friday"), utc(2024, 3, 29, 23, 59, 59));
+        assert_eq!(due("x next monday"), utc(2024, 3, 25, 23, 59, 59));
+        assert_eq!(due("x next wed"), utc(2024, 3, 27, 23, 59, 59));
+        assert_eq!(due("x this sat"), utc(2024, 3, 23, 23, 59, 59));
+        assert_eq!(due("x this friday"), utc(2024, 3, 22, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_bare_weekday_abbreviations_stay_in_title() {
+        let parsed = parse("Fix the sun roof", now());
+        assert_eq!(parsed.title, "Fix the sun roof");
+        assert_eq!(parsed.due_at, None);
+
+        for input in ["Sat exam prep", "Wed invitations", "Mon cheri", "Fri tuna"] {
+            assert_eq!(parse(input, now()).title, input);
+            assert_eq!(parse(input, now()).due_at, None);
+        }
+
+        // "this" without a day is plain text as well
+        assert_eq!(parse("Do this now", now()).title, "Do this now");
+    }
+
+    #[test]
+    fn test_next_week_and_month() {
+        assert_eq!(due("x next week"), utc(2024, 3, 25, 23, 59, 59));
+        assert_eq!(due("x next month"), utc(2024, 4, 1, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_relative_days() {
+        assert_eq!(due("x in 3 days"), utc(2024, 3, 23, 23, 59, 59));
+        assert_eq!(due("x in a week"), utc(2024, 3, 27, 23, 59, 59));
+        assert_eq!(due("x in 2 weeks 8am"), utc(2024, 4, 3, 8, 0, 0));
+        assert_eq!(due("x in 1 month"), utc(2024, 4, 20, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_relative_instants() {
+        assert_eq!(due("x in 2 hours"), utc(2024, 3, 20, 12, 30, 0));
+        assert_eq!(due("x in 45 minutes"), utc(2024, 3, 20, 11, 15, 0));
+        assert_eq!(due("x in an hour"), utc(2024, 3, 20, 11, 30, 0));
+    }
+
+    #[test]
+    fn test_in_without_unit_stays_in_title() {
+        let parsed = parse("Check in on Bob", now());
+        assert_eq!(parsed.title, "Check in on Bob");
+        assert_eq!(parsed.due_at, None);
+    }
+
+    #[test]
+    fn test_absolute_dates() {
+        assert_eq!(due("x 2024-05-01"), utc(2024, 5, 1, 23, 59, 59));
+        assert_eq!(due("x april 3"), utc(2024, 4, 3, 23, 59, 59));
+        assert_eq!(due("x 3rd of April 9am"), utc(2024, 4, 3, 9, 0, 0));
+        assert_eq!(due("x on Dec 24th"), utc(2024, 12, 24, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_month_day_in_the_past_rolls_over() {
+        assert_eq!(due("x jan 5"), utc(2025, 1, 5, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_simple_recurrence() {
+        let daily = parse("Water plants daily", now());
+        assert_eq!(daily.title, "Water plants");
+        assert_eq!(
+            daily.recurrence,
+            Some(Recurrence::every(Frequency::Daily, 1))
+        );
+        assert_eq!(daily.due_at, utc(2024, 3, 20, 23, 59, 59));
+
+        assert_eq!(
+            parse("x every year", now()).recurrence,
+            Some(Recurrence::every(Frequency::Yearly, 1))
+        );
+        assert_eq!(
+            parse("x annually", now()).recurrence,
+            Some(Recurrence::every(Frequency::Yearly, 1))
+        );
+    }
+
+    #[test]
+    fn test_recurrence_intervals() {
+        assert_eq!(
+            parse("x every other week", now()).recurrence,
+            Some(Recurrence::every(Frequency::Weekly, 2))
+        );
+        assert_eq!(
+            parse("x every 3 months", now()).recurrence,
+            Some(Recurrence::every(Frequency::Monthly, 3))
+        );
+        assert_eq!(parse("x every 0 days", now()).recurrence, None);
+    }
+
+    #[test]
+    fn test_weekday_recurrence() {
+        let parsed = parse("Gym every monday 7am", now());
+        assert_eq!(parsed.title, "Gym");
+        assert_eq!(
+            parsed.recurrence.as_ref().unwrap().weekdays,
+            vec![Weekday::Mon]
+        );
+        assert_eq!(parsed.due_at, utc(2024, 3, 25, 7, 0, 0));
+
+        let weekdays = parse("Standup every weekday 9am", now());
+        assert_eq!(weekdays.recurrence.as_ref().unwrap().weekdays.len(), 5);
+        // 9am already passed on Wednesday, so the first one is Thursday
+        assert_eq!(weekdays.due_at, utc(2024, 3, 21, 9, 0, 0));
+
+        let thursdays = parse("x every thurs", now());
+        assert_eq!(thursdays.recurrence.unwrap().weekdays, vec![Weekday::Thu]);
+
+        let weekly = parse("Review every week on friday", now());
+        assert_eq!(weekly.title, "Review");
+        assert_eq!(weekly.recurrence.unwrap().weekdays, vec![Weekday::Fri]);
+    }
+
+    #[test]
+    fn test_monthly_recurrence_first_due_date() {
+        let parsed = parse("Pay rent every month on the 1st", now());
+        assert_eq!(parsed.title, "Pay rent");
+        assert_eq!(parsed.due_at, utc(2024, 4, 1, 23, 59, 59));
+
+        let parsed = parse("Invoice monthly on 31", now());
+        assert_eq!(parsed.recurrence.unwrap().day_of_month, Some(31));
+        assert_eq!(parsed.due_at, utc(2024, 3, 31, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_first_on_or_after_clamps_to_month_end() {
+        let mut r = Recurrence::every(Frequency::Monthly, 1);
+        r.day_of_month = Some(31);
+        let date = NaiveDate::from_ymd_opt(2024, 2, 10).unwrap();
+        assert_eq!(
+            r.first_on_or_after(date),
+            NaiveDate::from_ymd_opt(2024, 2, 29)
+        );
+    }
+
+    #[test]
+    fn test_first_on_or_after_at_end_of_calendar() {
+        let mut r = Recurrence::every(Frequency::Monthly, 1);
+        r.day_of_month = Some(1);
+        assert_eq!(r.first_on_or_after(NaiveDate::MAX), None);
+
+        let mut r = Recurrence::every(Frequency::Weekly, 1);
+        r.weekdays = vec![NaiveDate::MAX.weekday().succ()];
+        assert_eq!(r.first_on_or_after(NaiveDate::MAX), None);
+    }
+
+    #[test]
+    fn test_add_months_wraps_year() {
+        assert_eq!(add_months(2024, 12, 1), Some((2025, 1)));
+        assert_eq!(add_months(2024, 11, 14), Some((2026, 1)));
+    }
+
+    #[test]
+    fn test_add_months_overflow() {
+        assert_eq!(add_months(2024, 12, u32::MAX), None);
+        assert_eq!(add_months(i32::MAX, 12, 1), None);
+        assert_eq!(day_in_month(i32::MAX, 1, 1), None);
+    }
+
+    #[test]
+    fn test_huge_amounts_stay_in_title() {
+        for input in [
+            "x in 99999999999 days",
+            "x in 5000000 months",
+            "x in 999999999999999 minutes",
+            "x in 9999999999999 weeks",
+            "x in 99999999999 hours",
+            "x in 10001 days",
+        ] {
+            let parsed = parse(input, now());
+            assert_eq!(parsed.title, input, "{}", input);
+            assert_eq!(parsed.due_at, None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_largest_amounts() {
+        assert_eq!(due("x in 10000 days"), utc(2051, 8, 6, 23, 59, 59));
+        assert_eq!(due("x in 10000 months"), utc(2857, 7, 20, 23, 59, 59));
+        assert_eq!(due("x in 10000 minutes"), utc(2024, 3, 27, 9, 10, 0));
+    }
+
+    #[test]
+    fn test_dates_at_end_of_calendar() {
+        let last = Utc.from_utc_datetime(&NaiveDate::MAX.and_hms_opt(12, 0, 0).unwrap());
+        assert_eq!(parse("x tomorrow", last).due_at, None);
+        assert_eq!(parse("x next month", last).due_at, None);
+        assert_eq!(parse("x in 3 days", last).due_at, None);
+        assert_eq!(parse("x in 20 hours", last).due_at, None);
+        assert_eq!(parse("x 9am", last).due_at, None);
+    }
+
+    #[test]
+    fn test_time_zone_offset() {
+        // 10:30 UTC is 19:30 in UTC+9, so "tomorrow" is the 21st local time
+        let tokyo = FixedOffset::east_opt(9 * 3600).unwrap();
+        let parsed = parse("x tomorrow 9am", now().with_timezone(&tokyo));
+        assert_eq!(parsed.due_at, utc(2024, 3, 21, 0, 0, 0));
+
+        // ...while in UTC-11 it is still the 19th
+        let samoa = FixedOffset::west_opt(11 * 3600).unwrap();
+        let parsed = parse("x tomorrow 9am", now().with_timezone(&samoa));
+        assert_eq!(parsed.due_at, utc(2024, 3, 20, 20, 0, 0));
+    }
+
+    #[test]
+    fn test_named_time_zone() {
+        let berlin = chrono_tz::Europe::Berlin;
+        let parsed = parse("x tomorrow 9am", now().with_timezone(&berlin));
+        assert_eq!(parsed.due_at, utc(2024, 3, 21, 8, 0, 0));
+
+        // After the switch to summer time Berlin is UTC+2
+        let parsed = parse("x april 3 9am", now().with_timezone(&berlin));
+        assert_eq!(parsed.due_at, utc(2024, 4, 3, 7, 0, 0));
+    }
+
+    #[test]
+    fn test_dst_gap_moves_forward() {
+        // 02:30 does not exist in Berlin on 2024-03-31
+        let berlin = chrono_tz::Europe::Berlin;
+        let parsed = parse("x 2024-03-31 at 2:30", now().with_timezone(&berlin));
+        assert_eq!(parsed.due_at, utc(2024, 3, 31, 1, 30, 0));
+    }
+
+    #[test]
+    fn test_fragments_anywhere_in_input() {
+        let parsed = parse("!urgent tomorrow Submit report #work", now());
+        assert_eq!(parsed.title, "Submit report");
+        assert_eq!(parsed.priority, Some(Priority::Urgent));
+        assert_eq!(parsed.tags, vec!["work"]);
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let parsed = parse("   ", now());
+        assert_eq!(parsed.title, "");
+        assert_eq!(parsed.due_at, None);
+    }
+}
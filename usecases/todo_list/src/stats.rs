@@ -0,0 +1,613 @@
+use auth_api::authz::Authenticated;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Datelike, NaiveDate, Offset, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::workspaces;
+use crate::AppState;
+
+// Longest timeline a single request may ask for
+const MAX_BUCKETS: i64 = 366;
+
+// Number of buckets reported when no `from` is given
+const DEFAULT_BUCKETS: i32 = 12;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Day,
+    #[default]
+    Week,
+    Month,
+}
+
+impl Bucket {
+    // Lower bound on the bucket length, used to pick the default range. Days
+    // around DST changes and months can be longer.
+    fn approx_duration(&self) -> chrono::Duration {
+        match self {
+            Bucket::Day => chrono::Duration::days(1),
+            Bucket::Week => chrono::Duration::weeks(1),
+            Bucket::Month => chrono::Duration::days(28),
+        }
+    }
+
+    // First day of the bucket containing `date`. Weeks start on Monday, and
+    // the earliest week can start before the first representable date.
+    fn start_of(&self, date: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Bucket::Day => Some(date),
+            Bucket::Week => date.checked_sub_days(chrono::Days::new(
+                date.weekday().num_days_from_monday() as u64,
+            )),
+            Bucket::Month => date.with_day(1),
+        }
+    }
+
+    // First day of the following bucket
+    fn next(&self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Bucket::Day => start.succ_opt(),
+            Bucket::Week => start.checked_add_signed(chrono::Duration::weeks(1)),
+            Bucket::Month => start.checked_add_months(chrono::Months::new(1)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    #[serde(default)]
+    bucket: Bucket,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    user_id: Option<i32>,
+    project_id: Option<i32>,
+    // IANA time zone buckets and streak days follow, defaults to UTC
+    time_zone: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TodoStats {
+    bucket: Bucket,
+    time_zone: String,
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    totals: StateCounts,
+    completion_rate: Option<f64>,
+    average_seconds_to_complete: Option<f64>,
+    timeline: Vec<BucketStats>,
+    users: Vec<UserStats>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct StateCounts {
+    total: i64,
+    open: i64,
+    completed: i64,
+    overdue: i64,
+}
+
+// One point of the burn-down chart
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct BucketStats {
+    bucket_start: chrono::DateTime<chrono::Utc>,
+    created: i64,
+    completed: i64,
+    open_at_end: i64,
+    // Share of all todos existing at the end of the bucket that were done
+    #[sqlx(skip)]
+    completion_rate: Option<f64>,
+    #[serde(skip)]
+    total_at_end: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct UserStats {
+    user_id: i32,
+    open: i64,
+    completed: i64,
+    overdue: i64,
+    average_seconds_to_complete: Option<f64>,
+    current_streak_days: i64,
+    longest_streak_days: i64,
+}
+
+// Local midnight starting `day`, or the first instant after it when a DST
+// change skips midnight
+fn start_of_day<Tz: TimeZone>(tz: &Tz, day: NaiveDate) -> chrono::DateTime<chrono::Utc> {
+    let midnight = day.and_hms_opt(0, 0, 0).expect("midnight exists");
+    (0..3)
+        .find_map(|hours| {
+            tz.from_local_datetime(&(midnight + chrono::Duration::hours(hours)))
+                .earliest()
+        })
+        .map(|start| start.with_timezone(&chrono::Utc))
+        .unwrap_or_else(|| chrono::Utc.from_utc_datetime(&midnight))
+}
+
+// Date in `tz` at the instant `at`, or `None` when the local date falls
+// outside the supported range
+fn local_date<Tz: TimeZone>(tz: &Tz, at: chrono::DateTime<chrono::Utc>) -> Option<NaiveDate> {
+    let offset = tz.offset_from_utc_datetime(&at.naive_utc()).fix();
+    at.naive_utc()
+        .checked_add_offset(offset)
+        .map(|local| local.date())
+}
+
+// Start of the default range, `DEFAULT_BUCKETS` buckets ending at `to`
+fn default_from(
+    bucket: Bucket,
+    to: chrono::DateTime<chrono::Utc>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    to.checked_sub_signed(bucket.approx_duration() * (DEFAULT_BUCKETS - 1))
+}
+
+// Boundaries of the timeline buckets: the start of the bucket containing
+// `from`, the start of every following one up to the bucket containing `to`,
+// and the end of that last bucket. Buckets begin at midnight in `tz`.
+fn bucket_boundaries<Tz: TimeZone>(
+    bucket: Bucket,
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    tz: &Tz,
+) -> Result<Vec<chrono::DateTime<chrono::Utc>>, String> {
+    if from > to {
+        return Err("`from` must not be after `to`".to_string());
+    }
+
+    let last = local_date(tz, to).ok_or_else(|| "`to` is out of range".to_string())?;
+    let mut day = local_date(tz, from)
+        .and_then(|day| bucket.start_of(day))
+        .ok_or_else(|| "`from` is out of range".to_string())?;
+    let mut boundaries = vec![start_of_day(tz, day)];
+    while day <= last {
+        if boundaries.len() > MAX_BUCKETS as usize {
+            return Err(format!("At most {} buckets can be requested", MAX_BUCKETS));
+        }
+        day = bucket
+            .next(day)
+            .ok_or_else(|| "`to` is out of range".to_string())?;
+        boundaries.push(start_of_day(tz, day));
+    }
+
+    Ok(boundaries)
+}
+
+// Handler functions
+pub async fn todo_stats(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<TodoStats>, (StatusCode, String)> {
+    let bucket = query.bucket;
+    let tz = match &query.time_zone {
+        Some(name) => name.parse::<chrono_tz::Tz>().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown time zone \"{}\"", name),
+            )
+        })?,
+        None => chrono_tz::UTC,
+    };
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    let from = match query.from {
+        Some(from) => from,
+        None => default_from(bucket, to)
+            .ok_or((StatusCode::BAD_REQUEST, "`to` is out of range".to_string()))?,
+    };
+    let boundaries =
+        bucket_boundaries(bucket, from, to, &tz).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+
+    let totals = sqlx::query_as::<_, StateCounts>(
+        r#"
+        SELECT
+            COUNT(*) AS total,
+            COUNT(*) FILTER (WHERE NOT completed) AS open,
+            COUNT(*) FILTER (WHERE completed) AS completed,
+            COUNT(*) FILTER (WHERE NOT completed AND due_at < NOW()) AS overdue
+        FROM todos
+        WHERE workspace_id = $1
+          AND ($2::INTEGER IS NULL OR user_id = $2)
+          AND ($3::INTEGER IS NULL OR project_id = $3)
+        "#,
+    )
+    .bind(workspace_id)
+    .bind(query.user_id)
+    .bind(query.project_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let average_seconds_to_complete = sqlx::query_scalar::<_, Option<f64>>(
+        r#"
+        SELECT AVG(EXTRACT(EPOCH FROM completed_at - created_at))::FLOAT8
+        FROM todos
+        WHERE workspace_id = $1
+          AND completed_at IS NOT NULL
+          AND ($2::INTEGER IS NULL OR user_id = $2)
+          AND ($3::INTEGER IS NULL OR project_id = $3)
+        "#,
+    )
+    .bind(workspace_id)
+    .bind(query.user_id)
+    .bind(query.project_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Running totals over the buckets give the burn-down line. Todos created
+    // before the first bucket are carried in through `baseline`; completed
+    // todos without a `completed_at` predate its tracking and count as done
+    // before the range. `width_bucket` finds the bucket of a timestamp among
+    // the boundaries computed above, so buckets follow the time zone.
+    let mut timeline = sqlx::query_as::<_, BucketStats>(
+        r#"
+        WITH scoped AS (
+            SELECT * FROM todos
+            WHERE workspace_id = $1
+              AND ($3::INTEGER IS NULL OR user_id = $3)
+              AND ($4::INTEGER IS NULL OR project_id = $4)
+        ),
+        bounds AS (
+            SELECT $2::TIMESTAMPTZ[] AS boundaries, ($2::TIMESTAMPTZ[])[1] AS range_start
+        ),
+        buckets AS (
+            SELECT i, boundaries[i] AS bucket_start
+            FROM bounds, generate_series(1, array_length(boundaries, 1) - 1) AS i
+        ),
+        baseline AS (
+            SELECT
+                COUNT(*) AS created,
+                COUNT(*) FILTER (
+                    WHERE completed AND (completed_at IS NULL OR completed_at < range_start)
+                ) AS completed
+            FROM scoped, bounds
+            WHERE created_at < range_start
+        ),
+        created AS (
+            SELECT width_bucket(created_at, boundaries) AS i, COUNT(*) AS n
+            FROM scoped, bounds
+            WHERE created_at >= range_start
+            GROUP BY 1
+        ),
+        done AS (
+            SELECT width_bucket(completed_at, boundaries) AS i, COUNT(*) AS n
+            FROM scoped, bounds
+            WHERE completed_at >= range_start
+            GROUP BY 1
+        ),
+        series AS (
+            SELECT
+                b.bucket_start,
+                COALESCE(c.n, 0) AS created,
+                COALESCE(d.n, 0) AS completed,
+                SUM(COALESCE(c.n, 0)) OVER w AS cumulative_created,
+                SUM(COALESCE(d.n, 0)) OVER w AS cumulative_completed
+            FROM buckets b
+            LEFT JOIN created c ON c.i = b.i
+            LEFT JOIN done d ON d.i = b.i
+            WINDOW w AS (ORDER BY b.i ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)
+        )
+        SELECT
+            s.bucket_start,
+            s.created::BIGINT AS created,
+            s.completed::BIGINT AS completed,
+            (bl.created + s.cumulative_created - bl.completed - s.cumulative_completed)::BIGINT
+                AS open_at_end,
+            (bl.created + s.cumulative_created)::BIGINT AS total_at_end
+        FROM series s CROSS JOIN baseline bl
+        ORDER BY s.bucket_start
+        "#,
+    )
+    .bind(workspace_id)
+    .bind(&boundaries)
+    .bind(query.user_id)
+    .bind(query.project_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    for point in &mut timeline {
+        point.completion_rate = ratio(point.total_at_end - point.open_at_end, point.total_at_end);
+    }
+
+    // Streaks are runs of consecutive local days with at least one completion.
+    // Subtracting the row number from each day maps every run to one value.
+    let users = sqlx::query_as::<_, UserStats>(
+        r#"
+        WITH scoped AS (
+            SELECT * FROM todos
+            WHERE workspace_id = $1
+              AND ($2::INTEGER IS NULL OR user_id = $2)
+              AND ($3::INTEGER IS NULL OR project_id = $3)
+        ),
+        days AS (
+            SELECT DISTINCT user_id, (completed_at AT TIME ZONE $4)::DATE AS completed_on
+            FROM scoped
+            WHERE completed_at IS NOT NULL
+        ),
+        islands AS (
+            SELECT
+                user_id,
+                completed_on,
+                completed_on - (ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY completed_on))::INTEGER
+                    AS island
+            FROM days
+        ),
+        runs AS (
+            SELECT user_id, COUNT(*) AS length, MAX(completed_on) AS last_day
+            FROM islands
+            GROUP BY user_id, island
+        ),
+        streaks AS (
+            SELECT
+                user_id,
+                MAX(length) AS longest_run,
+                MAX(length) FILTER (
+                    WHERE last_day >= (NOW() AT TIME ZONE $4)::DATE - 1
+                ) AS current_run
+            FROM runs
+            GROUP BY user_id
+        )
+        SELECT
+            t.user_id,
+            COUNT(*) FILTER (WHERE NOT t.completed) AS open,
+            COUNT(*) FILTER (WHERE t.completed) AS completed,
+            COUNT(*) FILTER (WHERE NOT t.completed AND t.due_at < NOW()) AS overdue,
+            (AVG(EXTRACT(EPOCH FROM t.completed_at - t.created_at))
+                FILTER (WHERE t.completed_at IS NOT NULL))::FLOAT8 AS average_seconds_to_complete,
+            COALESCE(MAX(s.current_run), 0)::BIGINT AS current_streak_days,
+            COALESCE(MAX(s.longest_run), 0)::BIGINT AS longest_streak_days
+        FROM scoped t
+        LEFT JOIN streaks s ON s.user_id = t.user_id
+        GROUP BY t.user_id
+        ORDER BY t.user_id
+        "#,
+    )
+    .bind(workspace_id)
+    .bind(query.user_id)
+    .bind(query.project_id)
+    .bind(tz.name())
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(TodoStats {
+        bucket,
+        time_zone: tz.name().to_string(),
+        from,
+        to,
+        completion_rate: ratio(totals.completed, totals.total),
+        totals,
+        average_seconds_to_complete,
+        timeline,
+        users,
+    }))
+}
+
+fn ratio(part: i64, whole: i64) -> Option<f64> {
+    (whole > 0).then(|| part as f64 / whole as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> chrono::DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn boundaries(
+        bucket: Bucket,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+        tz: chrono_tz::Tz,
+    ) -> Vec<chrono::DateTime<Utc>> {
+        bucket_boundaries(bucket, from, to, &tz).unwrap()
+    }
+
+    #[test]
+    fn day_buckets_cover_both_ends() {
+        let b = boundaries(
+            Bucket::Day,
+            utc(2024, 3, 20, 10, 30),
+            utc(2024, 3, 22, 0, 0),
+            chrono_tz::UTC,
+        );
+        assert_eq!(
+            b,
+            [
+                utc(2024, 3, 20, 0, 0),
+                utc(2024, 3, 21, 0, 0),
+                utc(2024, 3, 22, 0, 0),
+                utc(2024, 3, 23, 0, 0),
+            ]
+        );
+
+        // A range inside one day is a single bucket
+        let b = boundaries(
+            Bucket::Day,
+            utc(2024, 3, 20, 1, 0),
+            utc(2024, 3, 20, 2, 0),
+            chrono_tz::UTC,
+        );
+        assert_eq!(b, [utc(2024, 3, 20, 0, 0), utc(2024, 3, 21, 0, 0)]);
+    }
+
+    #[test]
+    fn week_buckets_start_on_monday() {
+        // 2024-03-20 is a Wednesday, 2024-03-31 a Sunday
+        let b = boundaries(
+            Bucket::Week,
+            utc(2024, 3, 20, 12, 0),
+            utc(2024, 3, 31, 23, 59),
+            chrono_tz::UTC,
+        );
+        assert_eq!(
+            b,
+            [
+                utc(2024, 3, 18, 0, 0),
+                utc(2024, 3, 25, 0, 0),
+                utc(2024, 4, 1, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn month_buckets_follow_the_calendar() {
+        let b = boundaries(
+            Bucket::Month,
+            utc(2024, 1, 31, 0, 0),
+            utc(2024, 3, 1, 0, 0),
+            chrono_tz::UTC,
+        );
+        assert_eq!(
+            b,
+            [
+                utc(2024, 1, 1, 0, 0),
+                utc(2024, 2, 1, 0, 0),
+                utc(2024, 3, 1, 0, 0),
+                utc(2024, 4, 1, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn buckets_start_at_local_midnight() {
+        // 23:30 UTC on the 20th is already the 21st in Tokyo
+        let b = boundaries(
+            Bucket::Day,
+            utc(2024, 3, 20, 23, 30),
+            utc(2024, 3, 20, 23, 30),
+            chrono_tz::Asia::Tokyo,
+        );
+        assert_eq!(b, [utc(2024, 3, 20, 15, 0), utc(2024, 3, 21, 15, 0)]);
+    }
+
+    #[test]
+    fn day_buckets_across_dst_change() {
+        // Berlin moves from UTC+1 to UTC+2 on 2024-03-31, which lasts 23 hours
+        let b = boundaries(
+            Bucket::Day,
+            utc(2024, 3, 30, 12, 0),
+            utc(2024, 3, 31, 12, 0),
+            chrono_tz::Europe::Berlin,
+        );
+        assert_eq!(
+            b,
+            [
+                utc(2024, 3, 29, 23, 0),
+                utc(2024, 3, 30, 23, 0),
+                utc(2024, 3, 31, 22, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn skipped_midnight_starts_at_first_local_instant() {
+        // Santiago skips from 00:00 to 01:00 on 2024-09-08
+        let b = boundaries(
+            Bucket::Day,
+            utc(2024, 9, 8, 12, 0),
+            utc(2024, 9, 8, 12, 0),
+            chrono_tz::America::Santiago,
+        );
+        assert_eq!(b, [utc(2024, 9, 8, 4, 0), utc(2024, 9, 9, 3, 0)]);
+    }
+
+    #[test]
+    fn rejects_inverted_ranges() {
+        let err = bucket_boundaries(
+            Bucket::Day,
+            utc(2024, 3, 2, 0, 0),
+            utc(2024, 3, 1, 0, 0),
+            &chrono_tz::UTC,
+        )
+        .unwrap_err();
+        assert_eq!(err, "`from` must not be after `to`");
+    }
+
+    #[test]
+    fn limits_the_number_of_buckets() {
+        let from = utc(2024, 1, 1, 0, 0);
+        let last_allowed = from + chrono::Duration::days(MAX_BUCKETS - 1);
+        let b = boundaries(Bucket::Day, from, last_allowed, chrono_tz::UTC);
+        assert_eq!(b.len() as i64, MAX_BUCKETS + 1);
+
+        let err = bucket_boundaries(
+            Bucket::Day,
+            from,
+            last_allowed + chrono::Duration::days(1),
+            &chrono_tz::UTC,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            format!("At most {} buckets can be requested", MAX_BUCKETS)
+        );
+
+        // Counted in calendar months, not 28-day estimates
+        let b = boundaries(Bucket::Month, from, utc(2054, 6, 30, 0, 0), chrono_tz::UTC);
+        assert_eq!(b.len() as i64, MAX_BUCKETS + 1);
+    }
+
+    #[test]
+    fn default_range_spans_the_default_bucket_count() {
+        let to = utc(2024, 3, 20, 10, 30);
+        for bucket in [Bucket::Day, Bucket::Week] {
+            let from = default_from(bucket, to).unwrap();
+            let b = boundaries(bucket, from, to, chrono_tz::UTC);
+            assert_eq!(b.len() as i32, DEFAULT_BUCKETS + 1);
+        }
+
+        // 28 days is the shortest month, so the estimate never skips a month
+        for month in 1..=12 {
+            let start = NaiveDate::from_ymd_opt(2023, month, 1).unwrap();
+            let length = Bucket::Month.next(start).unwrap() - start;
+            assert!(length >= Bucket::Month.approx_duration());
+        }
+    }
+
+    #[test]
+    fn extreme_dates_are_rejected() {
+        let to = "-262143-01-02T00:00:00Z"
+            .parse::<chrono::DateTime<Utc>>()
+            .unwrap();
+        for bucket in [Bucket::Day, Bucket::Week, Bucket::Month] {
+            assert_eq!(default_from(bucket, to), None);
+        }
+
+        // The week of the earliest date starts before it
+        let from = chrono::DateTime::<Utc>::MIN_UTC;
+        let err = bucket_boundaries(Bucket::Week, from, to, &chrono_tz::UTC).unwrap_err();
+        assert_eq!(err, "`from` is out of range");
+
+        let err = bucket_boundaries(
+            Bucket::Day,
+            chrono::DateTime::<Utc>::MAX_UTC,
+            chrono::DateTime::<Utc>::MAX_UTC,
+            &chrono_tz::UTC,
+        )
+        .unwrap_err();
+        assert_eq!(err, "`to` is out of range");
+
+        // West of UTC the earliest instant has no local date
+        let err = bucket_boundaries(
+            Bucket::Day,
+            chrono::DateTime::<Utc>::MIN_UTC,
+            to,
+            &chrono_tz::America::Los_Angeles,
+        )
+        .unwrap_err();
+        assert_eq!(err, "`from` is out of range");
+    }
+}
@@ -0,0 +1,611 @@
+use auth_api::authz::Authenticated;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::workspaces::{self, Project};
+use crate::{AppState, Todo};
+
+// Template model
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Template {
+    pub id: i32,
+    pub workspace_id: i32,
+    pub name: String,
+    pub body: sqlx::types::Json<TemplateBody>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// What a template creates. With a `project_name` a new project is created to
+// hold the items; without one the items are filed into an existing project.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateBody {
+    #[serde(default)]
+    pub project_name: Option<String>,
+    pub items: Vec<TemplateItem>,
+}
+
+// A todo and its subtasks. Titles may contain `{{variable}}` placeholders and
+// due dates are stored relative to the moment the template is instantiated,
+// in whole days or, for templates saved from existing todos, in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateItem {
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_in_days: Option<i64>,
+    // Wins over `due_in_days` when both are set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_in_seconds: Option<i64>,
+    #[serde(default)]
+    pub subtasks: Vec<TemplateItem>,
+}
+
+impl TemplateItem {
+    // Due date of the todo created from this item, failing on offsets too
+    // large to represent
+    fn due_at(
+        &self,
+        start_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, String> {
+        let offset = match (self.due_in_seconds, self.due_in_days) {
+            (Some(seconds), _) => chrono::Duration::try_seconds(seconds),
+            (None, Some(days)) => chrono::Duration::try_days(days),
+            (None, None) => return Ok(None),
+        };
+
+        offset
+            .and_then(|offset| start_at.checked_add_signed(offset))
+            .map(Some)
+            .ok_or_else(|| format!("Due date offset of \"{}\" is out of range", self.title))
+    }
+
+    // Number of todos the item creates, itself included
+    fn count(&self) -> i64 {
+        1 + self.subtasks.iter().map(TemplateItem::count).sum::<i64>()
+    }
+}
+
+// Request/response types
+#[derive(Debug, Deserialize)]
+pub struct CreateTemplateRequest {
+    name: String,
+    body: TemplateBody,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveAsTemplateRequest {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstantiateTemplateRequest {
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    // Anchor for relative due dates, defaults to now
+    start_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Target project for templates that don't create their own
+    project_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstantiatedTemplate {
+    project: Option<Project>,
+    todos: Vec<Todo>,
+}
+
+// Replace every `{{name}}` placeholder with its value. Unknown variables and
+// unterminated placeholders are reported rather than left in the output.
+pub fn render(text: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| format!("Unterminated placeholder in \"{}\"", text))?;
+        let name = after[..end].trim();
+        let value = variables
+            .get(name)
+            .ok_or_else(|| format!("Missing template variable \"{}\"", name))?;
+        output.push_str(value);
+        rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+// Todos whose parent isn't among `todos` become top-level, so saving a project
+// keeps subtasks of todos filed elsewhere instead of dropping them
+fn reattach_orphans(todos: &mut [Todo]) {
+    let ids: HashSet<i32> = todos.iter().map(|todo| todo.id).collect();
+    for todo in todos.iter_mut() {
+        if todo
+            .parent_id
+            .is_some_and(|parent_id| !ids.contains(&parent_id))
+        {
+            todo.parent_id = None;
+        }
+    }
+}
+
+// Offset of a due date from `anchor`, to the second
+fn due_in_seconds(
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
+    anchor: chrono::DateTime<chrono::Utc>,
+) -> Option<i64> {
+    due_at.map(|due| (due - anchor).num_seconds())
+}
+
+// Turn a flat list of todos into template items, keeping the parent/child
+// structure and expressing due dates relative to `anchor`.
+fn build_items(
+    todos: &[Todo],
+    parent_id: Option<i32>,
+    anchor: chrono::DateTime<chrono::Utc>,
+) -> Vec<TemplateItem> {
+    todos
+        .iter()
+        .filter(|todo| todo.parent_id == parent_id)
+        .map(|todo| TemplateItem {
+            title: todo.title.clone(),
+            due_in_days: None,
+            due_in_seconds: due_in_seconds(todo.due_at, anchor),
+            subtasks: build_items(todos, Some(todo.id), anchor),
+        })
+        .collect()
+}
+
+async fn insert_template(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+    name: &str,
+    body: &TemplateBody,
+) -> Result<Template, (StatusCode, String)> {
+    sqlx::query_as::<_, Template>(
+        "INSERT INTO templates (workspace_id, name, body) VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(workspace_id)
+    .bind(name)
+    .bind(sqlx::types::Json(body))
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// Handler functions
+pub async fn list_templates(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+) -> Result<Json<Vec<Template>>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let templates = sqlx::query_as::<_, Template>(
+        "SELECT * FROM templates WHERE workspace_id = $1 ORDER BY name",
+    )
+    .bind(workspace_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(templates))
+}
+
+pub async fn get_template(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, id)): Path<(i32, i32)>,
+) -> Result<Json<Template>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let template = sqlx::query_as::<_, Template>(
+        "SELECT * FROM templates WHERE id = $1 AND workspace_id = $2",
+    )
+    .bind(id)
+    .bind(workspace_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "Template not found".to_string()))?;
+
+    Ok(Json(template))
+}
+
+pub async fn create_template(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+    Json(payload): Json<CreateTemplateRequest>,
+) -> Result<Json<Template>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let template = insert_template(&mut tx, workspace_id, &payload.name, &payload.body).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(template))
+}
+
+pub async fn delete_template(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, id)): Path<(i32, i32)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let result = sqlx::query("DELETE FROM templates WHERE id = $1 AND workspace_id = $2")
+        .bind(id)
+        .bind(workspace_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Template not found".to_string()));
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn save_project_as_template(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, project_id)): Path<(i32, i32)>,
+    Json(payload): Json<SaveAsTemplateRequest>,
+) -> Result<Json<Template>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let project =
+        sqlx::query_as::<_, Project>("SELECT * FROM projects WHERE id = $1 AND workspace_id = $2")
+            .bind(project_id)
+            .bind(workspace_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .ok_or((StatusCode::NOT_FOUND, "Project not found".to_string()))?;
+
+    let mut todos = sqlx::query_as::<_, Todo>(
+        "SELECT * FROM todos WHERE project_id = $1 AND workspace_id = $2 ORDER BY id",
+    )
+    .bind(project_id)
+    .bind(workspace_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    reattach_orphans(&mut todos);
+
+    let body = TemplateBody {
+        project_name: Some(project.name),
+        items: build_items(&todos, None, project.created_at),
+    };
+    let template = insert_template(&mut tx, workspace_id, &payload.name, &body).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(template))
+}
+
+pub async fn save_todo_as_template(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, todo_id)): Path<(i32, i32)>,
+    Json(payload): Json<SaveAsTemplateRequest>,
+) -> Result<Json<Template>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let todos = sqlx::query_as::<_, Todo>(
+        r#"
+        WITH RECURSIVE tree AS (
+            SELECT * FROM todos WHERE id = $1 AND workspace_id = $2
+            UNION ALL
+            SELECT t.* FROM todos t JOIN tree ON t.parent_id = tree.id
+        )
+        SELECT * FROM tree ORDER BY id
+        "#,
+    )
+    .bind(todo_id)
+    .bind(workspace_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let root = todos
+        .iter()
+        .find(|todo| todo.id == todo_id)
+        .ok_or((StatusCode::NOT_FOUND, "Todo not found".to_string()))?;
+
+    let body = TemplateBody {
+        project_name: None,
+        items: vec![TemplateItem {
+            title: root.title.clone(),
+            due_in_days: None,
+            due_in_seconds: due_in_seconds(root.due_at, root.created_at),
+            subtasks: build_items(&todos, Some(root.id), root.created_at),
+        }],
+    };
+    let template = insert_template(&mut tx, workspace_id, &payload.name, &body).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(template))
+}
+
+pub async fn instantiate_template(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, id)): Path<(i32, i32)>,
+    Json(payload): Json<InstantiateTemplateRequest>,
+) -> Result<Json<InstantiatedTemplate>, (StatusCode, String)> {
+    let mut tx = workspaces::begin_scoped(&state.db, workspace_id, claims.sub).await?;
+
+    let template = sqlx::query_as::<_, Template>(
+        "SELECT * FROM templates WHERE id = $1 AND workspace_id = $2",
+    )
+    .bind(id)
+    .bind(workspace_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "Template not found".to_string()))?;
+    let body = template.body.0;
+    let start_at = payload.start_at.unwrap_or_else(chrono::Utc::now);
+
+    // Every todo the template creates counts against the open todo limit
+    let settings = workspaces::load_settings(&mut tx, workspace_id).await?;
+    let adding = body.items.iter().map(TemplateItem::count).sum();
+    workspaces::ensure_open_todo_capacity(&mut tx, workspace_id, claims.sub, &settings, adding)
+        .await?;
+
+    let project = match &body.project_name {
+        Some(name) => {
+            let name =
+                render(name, &payload.variables).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            let project = sqlx::query_as::<_, Project>(
+                "INSERT INTO projects (workspace_id, name) VALUES ($1, $2) RETURNING *",
+            )
+            .bind(workspace_id)
+            .bind(&name)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            Some(project)
+        }
+        None => None,
+    };
+
+    let project_id = match &project {
+        Some(project) => Some(project.id),
+        None => {
+            let project_id = payload.project_id.or(settings.default_project_id);
+            if let Some(project_id) = project_id {
+                workspaces::ensure_project(&mut tx, workspace_id, project_id).await?;
+            }
+            project_id
+        }
+    };
+
+    // Walk the item tree depth-first, creating parents before their subtasks
+    let mut todos = Vec::new();
+    let mut pending: Vec<(TemplateItem, Option<i32>)> = body
+        .items
+        .into_iter()
+        .rev()
+        .map(|item| (item, None))
+        .collect();
+
+    while let Some((item, parent_id)) = pending.pop() {
+        let title =
+            render(&item.title, &payload.variables).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let due_at = item
+            .due_at(start_at)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+        let todo = sqlx::query_as::<_, Todo>(
+            r#"
+            INSERT INTO todos (title, user_id, workspace_id, project_id, parent_id, due_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(&title)
+        .bind(claims.sub)
+        .bind(workspace_id)
+        .bind(project_id)
+        .bind(parent_id)
+        .bind(due_at)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+        pending.extend(
+            item.subtasks
+                .into_iter()
+                .rev()
+                .map(|subtask| (subtask, Some(todo.id))),
+        );
+        todos.push(todo);
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(InstantiatedTemplate { project, todos }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn at(hour: u32, minute: u32) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc
+            .with_ymd_and_hms(2024, 3, 1, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn todo(
+        id: i32,
+        parent_id: Option<i32>,
+        due_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Todo {
+        Todo {
+            id,
+            title: format!("todo {}", id),
+            completed: false,
+            user_id: 1,
+            workspace_id: 1,
+            project_id: Some(1),
+            parent_id,
+            due_at,
+            tags: Vec::new(),
+            priority: None,
+            recurrence: None,
+            created_at: at(8, 0),
+            completed_at: None,
+        }
+    }
+
+    fn item(due_in_days: Option<i64>, due_in_seconds: Option<i64>) -> TemplateItem {
+        TemplateItem {
+            title: "Ship".to_string(),
+            due_in_days,
+            due_in_seconds,
+            subtasks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let vars = variables(&[("version", "1.4.0"), ("team", "Core")]);
+        assert_eq!(
+            render("Release {{version}} for {{ team }}", &vars).unwrap(),
+            "Release 1.4.0 for Core"
+        );
+        assert_eq!(render("No placeholders", &vars).unwrap(), "No placeholders");
+    }
+
+    #[test]
+    fn reports_missing_variables() {
+        let vars = variables(&[("version", "1.4.0")]);
+        assert_eq!(
+            render("Ship {{version}} to {{target}}", &vars).unwrap_err(),
+            "Missing template variable \"target\""
+        );
+        assert!(render("Ship {{}}", &vars).is_err());
+        assert_eq!(
+            render("Ship {{version", &vars).unwrap_err(),
+            "Unterminated placeholder in \"Ship {{version\""
+        );
+    }
+
+    #[test]
+    fn values_are_inserted_verbatim() {
+        // Placeholders inside values aren't expanded again, and lone braces
+        // are plain text
+        let vars = variables(&[("name", "{{secret}}"), ("secret", "leaked")]);
+        assert_eq!(render("Hi {{name}}", &vars).unwrap(), "Hi {{secret}}");
+        assert_eq!(render("{a} } {", &vars).unwrap(), "{a} } {");
+    }
+
+    #[test]
+    fn builds_nested_subtasks() {
+        let todos = vec![
+            todo(1, None, None),
+            todo(2, Some(1), None),
+            todo(3, Some(2), None),
+            todo(4, None, None),
+            todo(5, Some(1), None),
+        ];
+        let items = build_items(&todos, None, at(8, 0));
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].title, "todo 1");
+        assert_eq!(items[0].subtasks.len(), 2);
+        assert_eq!(items[0].subtasks[0].title, "todo 2");
+        assert_eq!(items[0].subtasks[0].subtasks[0].title, "todo 3");
+        assert_eq!(items[0].subtasks[1].title, "todo 5");
+        assert_eq!(items[1].title, "todo 4");
+        assert!(items[1].subtasks.is_empty());
+        assert_eq!(items[0].count() + items[1].count(), 5);
+    }
+
+    #[test]
+    fn keeps_subtasks_of_parents_outside_the_project() {
+        // Todo 2's parent lives in another project
+        let mut todos = vec![
+            todo(1, None, None),
+            todo(2, Some(99), None),
+            todo(3, Some(2), None),
+        ];
+        reattach_orphans(&mut todos);
+        let items = build_items(&todos, None, at(8, 0));
+
+        let titles: Vec<_> = items.iter().map(|item| item.title.as_str()).collect();
+        assert_eq!(titles, ["todo 1", "todo 2"]);
+        assert_eq!(items[1].subtasks[0].title, "todo 3");
+    }
+
+    #[test]
+    fn due_offsets_keep_the_time_of_day() {
+        // Due the next day at 17:30, saved from a todo created at 08:00
+        let due = at(17, 30) + chrono::Duration::days(1);
+        let items = build_items(&[todo(1, None, Some(due))], None, at(8, 0));
+        assert_eq!(items[0].due_in_seconds, Some(33 * 3600 + 30 * 60));
+
+        let start = at(8, 0) + chrono::Duration::days(10);
+        assert_eq!(
+            items[0].due_at(start).unwrap(),
+            Some(at(17, 30) + chrono::Duration::days(11))
+        );
+    }
+
+    #[test]
+    fn computes_due_dates_from_days_or_seconds() {
+        let start = at(9, 0);
+        assert_eq!(item(None, None).due_at(start).unwrap(), None);
+        assert_eq!(
+            item(Some(2), None).due_at(start).unwrap(),
+            Some(start + chrono::Duration::days(2))
+        );
+        assert_eq!(
+            item(Some(2), Some(90)).due_at(start).unwrap(),
+            Some(start + chrono::Duration::seconds(90))
+        );
+        assert!(item(Some(i64::MAX), None).due_at(start).is_err());
+        assert!(item(None, Some(i64::MAX)).due_at(start).is_err());
+    }
+
+    #[test]
+    fn reads_templates_saved_with_days() {
+        let body: TemplateBody = serde_json::from_str(
+            r#"{"items": [{"title": "Plan", "due_in_days": 1, "subtasks": [{"title": "Draft"}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(body.project_name, None);
+        assert_eq!(body.items[0].due_in_days, Some(1));
+        assert_eq!(body.items[0].due_in_seconds, None);
+        assert_eq!(body.items[0].subtasks[0].due_in_days, None);
+    }
+}
@@ -0,0 +1,665 @@
+use auth_api::authz::Authenticated;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+
+use crate::AppState;
+
+// Tables owned by a workspace. Row-level security policies are attached to
+// each of them when enabled.
+pub const SCOPED_TABLES: &[&str] = &["projects", "todos", "templates"];
+
+// Workspace model
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Workspace {
+    pub id: i32,
+    pub name: String,
+    pub settings: sqlx::types::Json<WorkspaceSettings>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Per-workspace configuration, stored as JSONB on the workspace row
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceSettings {
+    // Project new todos are filed under when the request names none
+    pub default_project_id: Option<i32>,
+    // Upper bound on open todos per user, unlimited when unset
+    pub max_open_todos_per_user: Option<i64>,
+}
+
+// What a member may do in a workspace. Owners and admins manage settings and
+// membership; only the owner can make admins, and the owner can't be removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Admin => "admin",
+            Role::Member => "member",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "owner" => Some(Role::Owner),
+            "admin" => Some(Role::Admin),
+            "member" => Some(Role::Member),
+            _ => None,
+        }
+    }
+
+    pub fn can_manage(&self) -> bool {
+        matches!(self, Role::Owner | Role::Admin)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Member {
+    pub workspace_id: i32,
+    pub user_id: i32,
+    pub role: String,
+    pub joined_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Project {
+    pub id: i32,
+    pub workspace_id: i32,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Request types
+#[derive(Debug, Deserialize)]
+pub struct CreateWorkspaceRequest {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMemberRequest {
+    user_id: i32,
+    #[serde(default)]
+    role: Option<Role>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateProjectRequest {
+    name: String,
+}
+
+// Statements installing the row-level security policy on a scoped table.
+// Outside a scoped transaction the setting reads as `''` on pooled
+// connections, which matches no workspace instead of failing the cast.
+pub fn rls_statements(table: &str) -> Vec<String> {
+    let workspace = "NULLIF(current_setting('app.workspace_id', true), '')::INTEGER";
+    vec![
+        format!("ALTER TABLE {table} ENABLE ROW LEVEL SECURITY"),
+        format!("ALTER TABLE {table} FORCE ROW LEVEL SECURITY"),
+        format!("DROP POLICY IF EXISTS workspace_isolation ON {table}"),
+        format!(
+            "CREATE POLICY workspace_isolation ON {table} \
+             USING (workspace_id = {workspace}) \
+             WITH CHECK (workspace_id = {workspace})"
+        ),
+    ]
+}
+
+// Start a transaction bound to a single workspace, failing unless `user_id`
+// is one of its members. The workspace ID is exposed to the row-level security
+// policies through the `app.workspace_id` setting, which is scoped to the
+// transaction.
+pub async fn begin_scoped(
+    db: &PgPool,
+    workspace_id: i32,
+    user_id: i32,
+) -> Result<Transaction<'static, Postgres>, (StatusCode, String)> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    sqlx::query("SELECT set_config('app.workspace_id', $1, true)")
+        .bind(workspace_id.to_string())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    ensure_member(&mut tx, workspace_id, user_id).await?;
+
+    Ok(tx)
+}
+
+pub async fn load_settings(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+) -> Result<WorkspaceSettings, (StatusCode, String)> {
+    let settings = sqlx::query_scalar::<_, sqlx::types::Json<WorkspaceSettings>>(
+        "SELECT settings FROM workspaces WHERE id = $1",
+    )
+    .bind(workspace_id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "Workspace not found".to_string()))?;
+
+    Ok(settings.0)
+}
+
+pub async fn ensure_member(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    let is_member = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM workspace_members WHERE workspace_id = $1 AND user_id = $2)",
+    )
+    .bind(workspace_id)
+    .bind(user_id)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if !is_member {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "User is not a member of this workspace".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// The member's role, or `None` when the user isn't a member. Locks the
+// member row, so the role can't change before the transaction ends.
+pub async fn member_role(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+    user_id: i32,
+) -> Result<Option<Role>, (StatusCode, String)> {
+    let role = sqlx::query_scalar::<_, String>(
+        "SELECT role FROM workspace_members WHERE workspace_id = $1 AND user_id = $2 FOR UPDATE",
+    )
+    .bind(workspace_id)
+    .bind(user_id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Unknown roles grant nothing beyond membership
+    Ok(role.map(|role| Role::parse(&role).unwrap_or(Role::Member)))
+}
+
+// Fail unless the user is an owner or admin of the workspace, returning the role
+pub async fn ensure_manager(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+    user_id: i32,
+) -> Result<Role, (StatusCode, String)> {
+    match member_role(tx, workspace_id, user_id).await? {
+        Some(role) if role.can_manage() => Ok(role),
+        _ => Err((
+            StatusCode::FORBIDDEN,
+            "Only workspace owners and admins can do this".to_string(),
+        )),
+    }
+}
+
+// Whether a member with the `actor` role may remove one with the `target`
+// role. Members may always leave, except the owner, who can't be removed.
+pub fn can_remove(actor: Role, target: Role, is_self: bool) -> bool {
+    match target {
+        Role::Owner => false,
+        _ if is_self => true,
+        Role::Admin => actor == Role::Owner,
+        Role::Member => actor.can_manage(),
+    }
+}
+
+// Fail when `adding` more open todos would take the user past the workspace
+// limit. The member row is locked first, so concurrent creates for the same
+// user wait for each other instead of all passing the count.
+pub async fn ensure_open_todo_capacity(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+    user_id: i32,
+    settings: &WorkspaceSettings,
+    adding: i64,
+) -> Result<(), (StatusCode, String)> {
+    let Some(limit) = settings.max_open_todos_per_user else {
+        return Ok(());
+    };
+
+    sqlx::query(
+        "SELECT 1 FROM workspace_members WHERE workspace_id = $1 AND user_id = $2 FOR UPDATE",
+    )
+    .bind(workspace_id)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let open = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM todos WHERE workspace_id = $1 AND user_id = $2 AND NOT completed",
+    )
+    .bind(workspace_id)
+    .bind(user_id)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if open + adding > limit {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Workspace allows at most {} open todos per user", limit),
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn ensure_project(
+    tx: &mut Transaction<'static, Postgres>,
+    workspace_id: i32,
+    project_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    sqlx::query("SELECT id FROM projects WHERE id = $1 AND workspace_id = $2")
+        .bind(project_id)
+        .bind(workspace_id)
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Project not found".to_string()))?;
+
+    Ok(())
+}
+
+// Handler functions
+
+// The caller becomes the owner of the workspace they create
+pub async fn create_workspace(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Json(payload): Json<CreateWorkspaceRequest>,
+) -> Result<Json<Workspace>, (StatusCode, String)> {
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let workspace =
+        sqlx::query_as::<_, Workspace>("INSERT INTO workspaces (name) VALUES ($1) RETURNING *")
+            .bind(&payload.name)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    sqlx::query("INSERT INTO workspace_members (workspace_id, user_id, role) VALUES ($1, $2, $3)")
+        .bind(workspace.id)
+        .bind(claims.sub)
+        .bind(Role::Owner.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(workspace))
+}
+
+pub async fn get_workspace(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+) -> Result<Json<Workspace>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let workspace = sqlx::query_as::<_, Workspace>("SELECT * FROM workspaces WHERE id = $1")
+        .bind(workspace_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Workspace not found".to_string()))?;
+
+    Ok(Json(workspace))
+}
+
+pub async fn get_settings(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+) -> Result<Json<WorkspaceSettings>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let settings = load_settings(&mut tx, workspace_id).await?;
+
+    Ok(Json(settings))
+}
+
+pub async fn update_settings(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+    Json(settings): Json<WorkspaceSettings>,
+) -> Result<Json<WorkspaceSettings>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    ensure_manager(&mut tx, workspace_id, claims.sub).await?;
+
+    if let Some(project_id) = settings.default_project_id {
+        ensure_project(&mut tx, workspace_id, project_id).await?;
+    }
+
+    let updated = sqlx::query_scalar::<_, sqlx::types::Json<WorkspaceSettings>>(
+        "UPDATE workspaces SET settings = $1 WHERE id = $2 RETURNING settings",
+    )
+    .bind(sqlx::types::Json(&settings))
+    .bind(workspace_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "Workspace not found".to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(updated.0))
+}
+
+pub async fn list_members(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+) -> Result<Json<Vec<Member>>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let members = sqlx::query_as::<_, Member>(
+        "SELECT * FROM workspace_members WHERE workspace_id = $1 ORDER BY joined_at",
+    )
+    .bind(workspace_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(members))
+}
+
+pub async fn add_member(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+    Json(payload): Json<AddMemberRequest>,
+) -> Result<Json<Member>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let actor = ensure_manager(&mut tx, workspace_id, claims.sub).await?;
+
+    let role = payload.role.unwrap_or(Role::Member);
+    match role {
+        Role::Owner => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "A workspace has a single owner".to_string(),
+            ))
+        }
+        Role::Admin if actor != Role::Owner => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Only the workspace owner can add admins".to_string(),
+            ))
+        }
+        _ => {}
+    }
+
+    // Adding an existing member leaves their role as it is
+    let member = sqlx::query_as::<_, Member>(
+        r#"
+        INSERT INTO workspace_members (workspace_id, user_id, role)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (workspace_id, user_id) DO UPDATE SET user_id = EXCLUDED.user_id
+        RETURNING *
+        "#,
+    )
+    .bind(workspace_id)
+    .bind(payload.user_id)
+    .bind(role.as_str())
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(member))
+}
+
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path((workspace_id, user_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let actor = member_role(&mut tx, workspace_id, claims.sub)
+        .await?
+        .unwrap_or(Role::Member);
+    let target = member_role(&mut tx, workspace_id, user_id)
+        .await?
+        .ok_or((StatusCode::NOT_FOUND, "Member not found".to_string()))?;
+
+    if !can_remove(actor, target, user_id == claims.sub) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Not allowed to remove this member".to_string(),
+        ));
+    }
+
+    let result =
+        sqlx::query("DELETE FROM workspace_members WHERE workspace_id = $1 AND user_id = $2")
+            .bind(workspace_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Member not found".to_string()));
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_projects(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+) -> Result<Json<Vec<Project>>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let projects = sqlx::query_as::<_, Project>(
+        "SELECT * FROM projects WHERE workspace_id = $1 ORDER BY name",
+    )
+    .bind(workspace_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(projects))
+}
+
+pub async fn create_project(
+    State(state): State<Arc<AppState>>,
+    Authenticated(claims): Authenticated,
+    Path(workspace_id): Path<i32>,
+    Json(payload): Json<CreateProjectRequest>,
+) -> Result<Json<Project>, (StatusCode, String)> {
+    let mut tx = begin_scoped(&state.db, workspace_id, claims.sub).await?;
+    let project = sqlx::query_as::<_, Project>(
+        "INSERT INTO projects (workspace_id, name) VALUES ($1, $2) RETURNING *",
+    )
+    .bind(workspace_id)
+    .bind(&payload.name)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(project))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    // Every route taking a workspace ID, with a body where one is expected
+    const ROUTES: &[(&str, &str)] = &[
+        ("POST", "/workspaces"),
+        ("GET", "/workspaces/1"),
+        ("GET", "/workspaces/1/settings"),
+        ("PUT", "/workspaces/1/settings"),
+        ("GET", "/workspaces/1/members"),
+        ("POST", "/workspaces/1/members"),
+        ("DELETE", "/workspaces/1/members/2"),
+        ("GET", "/workspaces/1/projects"),
+        ("POST", "/workspaces/1/projects"),
+        ("POST", "/workspaces/1/projects/1/template"),
+        ("GET", "/workspaces/1/templates"),
+        ("POST", "/workspaces/1/templates"),
+        ("GET", "/workspaces/1/templates/1"),
+        ("DELETE", "/workspaces/1/templates/1"),
+        ("POST", "/workspaces/1/templates/1/instantiate"),
+        ("GET", "/workspaces/1/todos"),
+        ("POST", "/workspaces/1/todos"),
+        ("GET", "/workspaces/1/todos/stats"),
+        ("GET", "/workspaces/1/todos/1"),
+        ("PUT", "/workspaces/1/todos/1"),
+        ("DELETE", "/workspaces/1/todos/1"),
+        ("POST", "/workspaces/1/todos/1/template"),
+    ];
+
+    fn app() -> axum::Router {
+        // Never connected to: requests are turned away before any query
+        let db = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let authenticator: Arc<dyn auth_api::authz::Authenticator> = Arc::new(
+            auth_api::authz::JwtAuthenticator::from_secret(b"test-secret"),
+        );
+        crate::router(Arc::new(AppState { db }), authenticator)
+    }
+
+    async fn status(method: &str, uri: &str, token: Option<&str>) -> StatusCode {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from("{}")).unwrap();
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn every_route_requires_a_token() {
+        for (method, uri) in ROUTES {
+            assert_eq!(
+                status(method, uri, None).await,
+                StatusCode::UNAUTHORIZED,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn every_route_rejects_forged_tokens() {
+        // Signed with another secret
+        let forged = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+            eyJzdWIiOjEsImV4cCI6NDEwMjQ0NDgwMCwiaWF0IjowLCJqdGkiOiJ4In0.\
+            c2lnbmVkLXdpdGgtc29tZXRoaW5nLWVsc2U";
+        for (method, uri) in ROUTES {
+            assert_eq!(
+                status(method, uri, Some(forged)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn rls_policy_tolerates_unset_workspace() {
+        let statements = rls_statements("todos");
+        let policy = statements.last().unwrap();
+        assert!(policy.starts_with("CREATE POLICY workspace_isolation ON todos"));
+        assert!(!policy.contains("current_setting('app.workspace_id', true)::INTEGER"));
+        assert_eq!(
+            policy
+                .matches("NULLIF(current_setting('app.workspace_id', true), '')::INTEGER")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn only_managers_remove_others() {
+        use Role::*;
+
+        // Nobody removes the owner, not even the owner
+        for actor in [Owner, Admin, Member] {
+            assert!(!can_remove(actor, Owner, actor == Owner));
+        }
+        assert!(can_remove(Owner, Admin, false));
+        assert!(!can_remove(Admin, Admin, false));
+        assert!(can_remove(Admin, Member, false));
+        assert!(!can_remove(Member, Member, false));
+        // Everyone else may leave
+        assert!(can_remove(Admin, Admin, true));
+        assert!(can_remove(Member, Member, true));
+    }
+
+    #[test]
+    fn roles_round_trip() {
+        for role in [Role::Owner, Role::Admin, Role::Member] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+            assert_eq!(
+                serde_json::to_string(&role).unwrap(),
+                format!("\"{}\"", role.as_str())
+            );
+        }
+        assert_eq!(Role::parse("superuser"), None);
+        assert!(Role::Owner.can_manage() && Role::Admin.can_manage());
+        assert!(!Role::Member.can_manage());
+    }
+
+    #[test]
+    fn settings_default_to_unlimited() {
+        let settings: WorkspaceSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings.default_project_id, None);
+        assert_eq!(settings.max_open_todos_per_user, None);
+
+        let settings: WorkspaceSettings =
+            serde_json::from_str(r#"{"max_open_todos_per_user": 3}"#).unwrap();
+        assert_eq!(settings.max_open_todos_per_user, Some(3));
+    }
+}